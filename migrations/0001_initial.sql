@@ -0,0 +1,15 @@
+-- The schema as it was before migrations were tracked.
+-- Everything is `IF NOT EXISTS` so this is a no-op against an existing database.
+CREATE EXTENSION IF NOT EXISTS pgcrypto;
+
+CREATE TABLE IF NOT EXISTS notebooks (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    password_hash BYTEA NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS texts (
+    id SERIAL PRIMARY KEY,
+    notebook_id INTEGER NOT NULL REFERENCES notebooks (id) ON DELETE CASCADE,
+    text TEXT NOT NULL
+);
@@ -0,0 +1,13 @@
+CREATE TABLE api_tokens (
+    id SERIAL PRIMARY KEY,
+    notebook_id INTEGER NOT NULL REFERENCES notebooks (id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    -- sha256 of the token; the token itself is only ever shown once, when it's created
+    token_hash BYTEA NOT NULL UNIQUE,
+    read_only BOOLEAN NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_used_at TIMESTAMPTZ,
+    revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX api_tokens_notebook_id ON api_tokens (notebook_id);
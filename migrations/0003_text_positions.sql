@@ -0,0 +1,4 @@
+-- texts used to come back in whatever order postgres felt like
+ALTER TABLE texts ADD COLUMN position INTEGER NOT NULL DEFAULT 0;
+UPDATE texts SET position = id;
+CREATE INDEX texts_notebook_id_position ON texts (notebook_id, position);
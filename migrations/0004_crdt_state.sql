@@ -0,0 +1,3 @@
+-- the yrs document for a text, once it has been edited collaboratively.
+-- `texts.text` is always kept equal to the document's contents.
+ALTER TABLE texts ADD COLUMN crdt_state BYTEA;
@@ -0,0 +1,21 @@
+-- Accounts, so a notebook can be shared without handing out its password.
+CREATE TABLE users (
+    id SERIAL PRIMARY KEY,
+    username TEXT NOT NULL,
+    -- bcrypt, from pgcrypto's crypt()
+    password_hash TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE UNIQUE INDEX users_username ON users (UPPER(username));
+
+-- Whoever knows a notebook's password is its owner; memberships grant everyone else a role on it.
+CREATE TABLE memberships (
+    notebook_id INTEGER NOT NULL REFERENCES notebooks (id) ON DELETE CASCADE,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    role TEXT NOT NULL CHECK (role IN ('viewer', 'commenter', 'editor')),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (notebook_id, user_id)
+);
+
+CREATE INDEX memberships_user_id ON memberships (user_id);
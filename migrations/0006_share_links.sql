@@ -0,0 +1,16 @@
+-- Read-only links to a notebook, or a single text in it, for people without an account.
+CREATE TABLE share_links (
+    id SERIAL PRIMARY KEY,
+    notebook_id INTEGER NOT NULL REFERENCES notebooks (id) ON DELETE CASCADE,
+    -- NULL shares the whole notebook
+    text_id INTEGER REFERENCES texts (id) ON DELETE CASCADE,
+    -- sha256 of the token in the link; the link itself is only shown once, when it's created
+    token_hash BYTEA NOT NULL UNIQUE,
+    -- bcrypt, from pgcrypto's crypt(), if the link needs a password
+    password_hash TEXT,
+    expires_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX share_links_notebook_id ON share_links (notebook_id);
@@ -0,0 +1,8 @@
+-- Failed logins, per client address and per notebook or account name, so password guessing can be slowed down.
+-- Names are recorded whether or not they exist, so a lockout doesn't give away which ones do.
+CREATE TABLE login_throttles (
+    key TEXT PRIMARY KEY,
+    failures INTEGER NOT NULL,
+    last_failure_at TIMESTAMPTZ NOT NULL,
+    locked_until TIMESTAMPTZ
+);
@@ -0,0 +1,24 @@
+-- TOTP secrets for notebooks and accounts which have turned on two-factor authentication.
+CREATE TABLE second_factors (
+    id SERIAL PRIMARY KEY,
+    notebook_id INTEGER UNIQUE REFERENCES notebooks (id) ON DELETE CASCADE,
+    user_id INTEGER UNIQUE REFERENCES users (id) ON DELETE CASCADE,
+    secret BYTEA NOT NULL,
+    -- NULL until a first code from the authenticator app has been checked
+    confirmed_at TIMESTAMPTZ,
+    -- the 30 second step of the last code used, so a code can't be used twice
+    last_used_step BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    CHECK ((notebook_id IS NULL) <> (user_id IS NULL))
+);
+
+-- Single use codes for when the authenticator app is lost.
+CREATE TABLE recovery_codes (
+    id SERIAL PRIMARY KEY,
+    second_factor_id INTEGER NOT NULL REFERENCES second_factors (id) ON DELETE CASCADE,
+    -- sha256 of the code
+    code_hash BYTEA NOT NULL,
+    used_at TIMESTAMPTZ
+);
+
+CREATE INDEX recovery_codes_second_factor_id ON recovery_codes (second_factor_id);
@@ -0,0 +1,19 @@
+-- Browser sessions, which used to live entirely in the cookie and so could never be ended.
+-- Only a digest of the key in the cookie is stored, like API tokens and share links.
+-- The account and notebook are copied out of the state so sessions can be listed and revoked.
+CREATE TABLE sessions (
+    id SERIAL PRIMARY KEY,
+    key_hash BYTEA NOT NULL UNIQUE,
+    -- the session's values, each as json, along with its own id as `session_id`
+    state JSONB NOT NULL,
+    user_id INTEGER REFERENCES users (id) ON DELETE CASCADE,
+    notebook_id INTEGER REFERENCES notebooks (id) ON DELETE CASCADE,
+    device TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_seen_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    -- pushed back on every request, so this is the idle timeout
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX sessions_user_id ON sessions (user_id);
+CREATE INDEX sessions_notebook_id ON sessions (notebook_id);
@@ -0,0 +1,6 @@
+-- Notebooks whose texts are encrypted in the browser. Both columns are NULL for a notebook which isn't.
+-- The salt is for deriving the key from the passphrase; the check is a known text encrypted with the key,
+-- so a browser can tell whether a passphrase is right. Neither is any use without the passphrase.
+ALTER TABLE notebooks
+    ADD COLUMN encryption_salt BYTEA,
+    ADD COLUMN encryption_check TEXT;
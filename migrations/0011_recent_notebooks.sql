@@ -0,0 +1,2 @@
+-- When each account last opened each notebook shared with it, so the home page can list them most recent first.
+ALTER TABLE memberships ADD COLUMN last_opened_at TIMESTAMPTZ;
@@ -0,0 +1,18 @@
+-- Work done outside of requests. A job is deleted once it's done, and kept with `failed_at` set once it's
+-- failed too many times, until an admin retries it. `locked_until` is when a worker which took it is
+-- assumed to have died, so another can take it.
+CREATE TABLE jobs (
+    id BIGSERIAL PRIMARY KEY,
+    job JSONB NOT NULL,
+    -- at most one job which hasn't failed can have each key, so recurring jobs don't pile up
+    unique_key TEXT,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    max_attempts INTEGER NOT NULL,
+    run_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    locked_until TIMESTAMPTZ,
+    last_error TEXT,
+    failed_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX jobs_due ON jobs (run_at) WHERE failed_at IS NULL;
+CREATE UNIQUE INDEX jobs_unique_key ON jobs (unique_key) WHERE failed_at IS NULL;
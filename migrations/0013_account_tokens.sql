@@ -0,0 +1,7 @@
+-- API tokens can belong to an account rather than a notebook, and then work on every notebook shared with it.
+ALTER TABLE api_tokens
+    ALTER COLUMN notebook_id DROP NOT NULL,
+    ADD COLUMN user_id INTEGER REFERENCES users (id) ON DELETE CASCADE,
+    ADD CONSTRAINT api_tokens_one_owner CHECK ((notebook_id IS NULL) <> (user_id IS NULL));
+
+CREATE INDEX api_tokens_user_id ON api_tokens (user_id);
@@ -0,0 +1,12 @@
+-- Notebook passwords were hashed with unsalted sha256. They're hashed with bcrypt now, like account passwords,
+-- and the old hashes are kept, marked `sha256:`, until the notebook is next opened with its password.
+ALTER TABLE notebooks ALTER COLUMN password_hash TYPE TEXT USING 'sha256:' || encode(password_hash, 'hex');
+
+CREATE FUNCTION notebook_password_matches(password_hash TEXT, password TEXT) RETURNS BOOLEAN
+LANGUAGE SQL AS $$
+    SELECT CASE
+        WHEN password_hash LIKE 'sha256:%'
+            THEN password_hash = 'sha256:' || encode(digest(password, 'sha256'), 'hex')
+        ELSE password_hash = crypt(password, password_hash)
+    END
+$$;
@@ -0,0 +1,6 @@
+-- Notebook names were only kept unique by checking before writing, which two renames at once could get past.
+-- Any duplicates that got in are told apart by their id, so the index can be made.
+UPDATE notebooks SET name = name || ' (' || id || ')'
+WHERE id NOT IN (SELECT MIN(id) FROM notebooks GROUP BY UPPER(name));
+
+CREATE UNIQUE INDEX notebooks_name ON notebooks (UPPER(name));
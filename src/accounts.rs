@@ -0,0 +1,366 @@
+use leptos::{
+    either::{Either, EitherOf3},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{AppError, ErrorMessage},
+    sharing::Role,
+    tokens::ApiTokens,
+    two_factor::{LoginStep, SecondFactorForm, TwoFactorSettings, VerifySecondFactor},
+};
+
+// accounts are separate from notebooks: logging in to one doesn't open any notebook by itself,
+// but lets you into every notebook that's been shared with it.
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Account {
+    id: i32,
+    username: String,
+}
+impl Account {
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+}
+
+/// A notebook someone else has shared with an account.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SharedNotebook {
+    id: i32,
+    name: String,
+    role: Role,
+}
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+#[cfg(feature = "ssr")]
+impl Account {
+    /// Returns `None` if the username is already taken.
+    pub async fn create(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        let result: Option<(i32,)> = sqlx::query_as(
+            "INSERT INTO users (username, password_hash) VALUES ($1, crypt($2, gen_salt('bf')))
+            ON CONFLICT ((UPPER(username))) DO NOTHING RETURNING id",
+        )
+        .bind(username)
+        .bind(password)
+        .fetch_optional(pool)
+        .await?;
+        Ok(result.map(|(id,)| Self {
+            id,
+            username: username.to_string(),
+        }))
+    }
+
+    /// Returns `None` if the username or password is wrong, taking as long either way.
+    pub async fn log_in(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        let result: Option<(i32, String, String)> = sqlx::query_as(
+            "SELECT id, username, password_hash FROM users WHERE UPPER(username) = UPPER($1)",
+        )
+        .bind(username)
+        .fetch_optional(pool)
+        .await?;
+        let matches = crate::throttle::password_matches(
+            pool,
+            password,
+            result.as_ref().map(|(_, _, hash)| hash.as_str()),
+        )
+        .await?;
+        Ok(result
+            .filter(|_| matches)
+            .map(|(id, username, _)| Self { id, username }))
+    }
+
+    pub async fn get(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        id: i32,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        let result: Option<(i32, String)> =
+            sqlx::query_as("SELECT id, username FROM users WHERE id = $1")
+                .bind(id)
+                .fetch_optional(pool)
+                .await?;
+        Ok(result.map(|(id, username)| Self { id, username }))
+    }
+
+    pub async fn shared_notebooks(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        id: i32,
+    ) -> Result<Vec<SharedNotebook>, sqlx::Error> {
+        let results: Vec<(i32, String, String)> = sqlx::query_as(
+            "SELECT notebooks.id, notebooks.name, memberships.role FROM memberships
+            JOIN notebooks ON notebooks.id = memberships.notebook_id
+            WHERE memberships.user_id = $1 ORDER BY UPPER(notebooks.name)",
+        )
+        .bind(id)
+        .fetch_all(pool)
+        .await?;
+        Ok(results
+            .into_iter()
+            .filter_map(|(id, name, role)| {
+                Some(SharedNotebook {
+                    id,
+                    name,
+                    role: role.parse().ok()?,
+                })
+            })
+            .collect())
+    }
+}
+
+#[cfg(feature = "ssr")]
+pub(crate) fn log_in_session(
+    session: &actix_session::Session,
+    user_id: i32,
+) -> Result<(), AppError> {
+    // a fresh session id, so one set before logging in can't be used to ride along
+    session.renew();
+    Ok(session.insert("user_id", user_id)?)
+}
+
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all)]
+pub async fn create_account(
+    username: String,
+    password: String,
+) -> Result<(), ServerFnError<AppError>> {
+    if !crate::app::get_config_from_context()?.features.new_accounts {
+        return Err(AppError::Validation(
+            "New accounts are turned off on this server!".to_string(),
+        )
+        .into());
+    }
+    let session: actix_session::Session = leptos_actix::extract().await.map_err(AppError::from)?;
+    let username = username.trim();
+    if username.is_empty() {
+        return Err(AppError::Validation("Choose a username!".to_string()).into());
+    }
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(AppError::Validation(format!(
+            "Passwords need at least {MIN_PASSWORD_LENGTH} characters!"
+        ))
+        .into());
+    }
+    let account = Account::create(
+        &crate::app::get_pool_from_context().await?,
+        username,
+        &password,
+    )
+    .await
+    .map_err(AppError::from)?
+    .ok_or_else(|| AppError::Conflict("That username is taken!".to_string()))?;
+    Ok(log_in_session(&session, account.id())?)
+}
+
+/// Needs a `verify_second_factor` afterwards if the account has two-factor authentication on.
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all)]
+pub async fn log_in(
+    username: String,
+    password: String,
+) -> Result<LoginStep, ServerFnError<AppError>> {
+    let session: actix_session::Session = leptos_actix::extract().await.map_err(AppError::from)?;
+    let req: actix_web::HttpRequest = leptos_actix::extract().await.map_err(AppError::from)?;
+    let pool = crate::app::get_pool_from_context().await?;
+    let throttle = crate::throttle::LoginKeys::new(&req, "account", &username);
+    if let Some(seconds) = throttle.locked_for(&pool).await.map_err(AppError::from)? {
+        return Err(AppError::Validation(crate::throttle::locked_out_message(seconds)).into());
+    }
+    let Some(account) = Account::log_in(&pool, username.trim(), &password)
+        .await
+        .map_err(AppError::from)?
+    else {
+        crate::metrics::record_login("account", false);
+        throttle
+            .record_failure(&pool)
+            .await
+            .map_err(AppError::from)?;
+        return Err(
+            AppError::Validation("The username or password is incorrect!".to_string()).into(),
+        );
+    };
+    crate::metrics::record_login("account", true);
+    throttle
+        .record_success(&pool)
+        .await
+        .map_err(AppError::from)?;
+    Ok(crate::two_factor::password_checked(
+        &pool,
+        &session,
+        crate::two_factor::FactorOwner::Account(account.id()),
+    )
+    .await
+    .map_err(AppError::from)?)
+}
+
+/// Logs out of the account, leaving any notebook opened with its password open.
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all)]
+pub async fn log_out() -> Result<(), ServerFnError<AppError>> {
+    let session: actix_session::Session = leptos_actix::extract().await.map_err(AppError::from)?;
+    let user_id = session.get::<i32>("user_id").ok().flatten();
+    session.remove("user_id");
+    session.remove("pending_login");
+    crate::sync::close_access_from_context(None, user_id)
+        .await
+        .map_err(AppError::from)?;
+    Ok(())
+}
+
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all)]
+pub async fn current_account() -> Result<Option<Account>, ServerFnError<AppError>> {
+    let caller: crate::auth::Caller = leptos_actix::extract().await.map_err(AppError::from)?;
+    let Some(user_id) = caller.user_id() else {
+        return Ok(None);
+    };
+    Ok(
+        Account::get(&crate::app::get_pool_from_context().await?, user_id)
+            .await
+            .map_err(AppError::from)?,
+    )
+}
+
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all)]
+pub async fn shared_notebooks() -> Result<Vec<SharedNotebook>, ServerFnError<AppError>> {
+    let caller: crate::auth::Caller = leptos_actix::extract().await.map_err(AppError::from)?;
+    let Some(user_id) = caller.user_id() else {
+        return Ok(Vec::new());
+    };
+    Ok(
+        Account::shared_notebooks(&crate::app::get_pool_from_context().await?, user_id)
+            .await
+            .map_err(AppError::from)?,
+    )
+}
+
+/// The error from a form's last submission, if it failed.
+fn action_error<T>(result: Option<Result<T, ServerFnError<AppError>>>) -> impl IntoView {
+    match result {
+        Some(Err(error)) => Either::Left(view! { <ErrorMessage error /> }),
+        Some(Ok(_)) | None => Either::Right(()),
+    }
+}
+
+#[component]
+pub fn AccountPage() -> impl IntoView {
+    let create_account = ServerAction::<CreateAccount>::new();
+    let log_in = ServerAction::<LogIn>::new();
+    let log_out = ServerAction::<LogOut>::new();
+    let verify_second_factor = ServerAction::<VerifySecondFactor>::new();
+    let account = Resource::new(
+        move || {
+            (
+                create_account.version().get(),
+                log_in.version().get(),
+                log_out.version().get(),
+                verify_second_factor.version().get(),
+            )
+        },
+        |_| current_account(),
+    );
+    let account_id = move || {
+        account
+            .get()
+            .and_then(Result::ok)
+            .flatten()
+            .map(|account| account.id())
+    };
+    let notebooks = Resource::new(account_id, |_| shared_notebooks());
+    // so logging out doesn't go back to asking for the last login's code
+    Effect::new(move |_| {
+        if log_out.version().get() > 0 {
+            log_in.value().set(None);
+        }
+    });
+
+    let notebook_list = move || {
+        notebooks.get().map(|notebooks| match notebooks {
+            Ok(notebooks) if notebooks.is_empty() => {
+                EitherOf3::A(view! { <p> "Nothing has been shared with you yet." </p> })
+            }
+            Ok(notebooks) => EitherOf3::B(view! {
+                <ul>
+                    {notebooks
+                        .into_iter()
+                        .map(|notebook| view! {
+                            <li>
+                                <a href=format!("/notebook/{}", notebook.id)> {notebook.name} </a>
+                                {format!(" ({})", notebook.role.as_str())}
+                            </li>
+                        })
+                        .collect_view()}
+                </ul>
+            }),
+            Err(error) => EitherOf3::C(view! { <ErrorMessage error /> }),
+        })
+    };
+    let page = move || match account.get() {
+        Some(Ok(Some(account))) => EitherOf3::A(view! {
+            <h1> {format!("Logged in as {}", account.username())} </h1>
+            <h2> "Shared with you" </h2>
+            <Transition>
+                {notebook_list}
+            </Transition>
+            <TwoFactorSettings notebook_id=None />
+            <ApiTokens notebook_id=None />
+            <ActionForm action=log_out>
+                <button type="submit"> "Log out" </button>
+            </ActionForm>
+        }),
+        // the password was right, but there's a code to give as well
+        _ if log_in
+            .value()
+            .get()
+            .is_some_and(|step| matches!(step, Ok(LoginStep::NeedsCode))) =>
+        {
+            EitherOf3::B(view! { <SecondFactorForm action=verify_second_factor /> })
+        }
+        _ => EitherOf3::C(view! {
+            <ActionForm action=log_in>
+                <h1> "Log in" </h1>
+                <input type="text" name="username" placeholder="Username..." required />
+                <br />
+                <input type="password" name="password" placeholder="Password..." required />
+                <br />
+                <button type="submit"> "Log in" </button>
+            </ActionForm>
+            {move || action_error(log_in.value().get())}
+            <ActionForm action=create_account>
+                <h1> "Create an account" </h1>
+                <input type="text" name="username" placeholder="Username..." required />
+                <br />
+                <input type="password" name="password" placeholder="Password..." required />
+                <br />
+                <button type="submit"> "Create account" </button>
+            </ActionForm>
+            {move || action_error(create_account.value().get())}
+        }),
+    };
+
+    view! {
+        <div class="notebook-header">
+            <div class="left">
+                <a href="/"> <h1> "home" </h1> </a>
+            </div>
+        </div>
+        <div id="account-page">
+            <Transition>
+                {page}
+            </Transition>
+        </div>
+    }
+}
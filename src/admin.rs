@@ -0,0 +1,282 @@
+//! Admin commands, run as subcommands of the server binary, e.g. `note-to-self reset-password work`.
+//! They load the same config as the server, so they work on the same database.
+//! Notebooks are picked by name, ignoring case, like logging in to them.
+
+use std::{
+    io::{IsTerminal, Write},
+    path::PathBuf,
+};
+
+use anyhow::{bail, Context};
+use clap::Subcommand;
+
+use crate::{config::Config, notebook::Notebook};
+
+#[derive(Subcommand)]
+pub enum AdminCommand {
+    /// Run any database migrations which haven't been run yet
+    Migrate,
+    /// Create an empty notebook, and print its id
+    CreateNotebook {
+        name: String,
+        /// Read from stdin if it isn't given, so it doesn't end up in your shell history
+        #[arg(long)]
+        password: Option<String>,
+    },
+    /// Set a forgotten notebook password, and log every browser out of the notebook
+    ResetPassword {
+        notebook: String,
+        /// Read from stdin if it isn't given, so it doesn't end up in your shell history
+        #[arg(long)]
+        password: Option<String>,
+    },
+    /// List every notebook, with its id and how many texts it has
+    ListNotebooks,
+    /// Write a notebook, or every notebook, as json which `import` and the import page understand
+    Export {
+        /// Every notebook, if it isn't given
+        notebook: Option<String>,
+        /// Stdout, if it isn't given
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Add the texts from a zip of markdown files, or a json export, to the end of a notebook
+    Import { file: PathBuf, notebook: String },
+    /// Delete expired sessions and share links, and old login failures, once they're past the retention periods
+    PurgeTrash,
+    /// Write every notebook, text and account to one archive, which `restore` can load into another database
+    Backup { output: PathBuf },
+    /// Load a backup into an empty database, running the migrations first if they haven't been
+    Restore { file: PathBuf },
+    /// List the background jobs which failed every time they were tried, with their last error
+    FailedJobs,
+    /// Run a failed background job again, as if it was new
+    RetryJob { id: i64 },
+}
+
+pub async fn run(
+    command: AdminCommand,
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    match command {
+        AdminCommand::Migrate => {
+            sqlx::migrate!()
+                .run(pool)
+                .await
+                .context("couldn't run the migrations")?;
+            println!("the database is up to date");
+        }
+        AdminCommand::CreateNotebook { name, password } => {
+            let name = name.trim();
+            if name.is_empty() {
+                bail!("the notebook needs a name");
+            }
+            let password = password_or_stdin(password)?;
+            let mut transaction = pool.begin().await?;
+            if Notebook::name_taken(&mut *transaction, name).await? {
+                bail!("there's already a notebook called \"{name}\"");
+            }
+            let id = Notebook::create(&mut *transaction, name, &password).await?;
+            transaction.commit().await?;
+            println!("{id}");
+        }
+        AdminCommand::ResetPassword { notebook, password } => {
+            let id = find_notebook(pool, &notebook).await?;
+            let password = password_or_stdin(password)?;
+            let mut transaction = pool.begin().await?;
+            Notebook::reset_password(&mut *transaction, id, &password).await?;
+            crate::sessions::forget_notebook(&mut *transaction, id, None).await?;
+            crate::throttle::forgive(&mut *transaction, "notebook", &notebook).await?;
+            transaction.commit().await?;
+            println!("reset the password of notebook {id}, and logged everyone out of it");
+        }
+        AdminCommand::ListNotebooks => {
+            let notebooks: Vec<(i32, String, i64, bool)> = sqlx::query_as(
+                "SELECT notebooks.id, notebooks.name, COUNT(texts.id), notebooks.encryption_salt IS NOT NULL
+                FROM notebooks LEFT JOIN texts ON texts.notebook_id = notebooks.id
+                GROUP BY notebooks.id ORDER BY notebooks.id",
+            )
+            .fetch_all(pool)
+            .await?;
+            for (id, name, texts, encrypted) in notebooks {
+                println!(
+                    "{id:>6}  {name}  ({texts} text{}{})",
+                    if texts == 1 { "" } else { "s" },
+                    if encrypted { ", encrypted" } else { "" }
+                );
+            }
+        }
+        AdminCommand::Export { notebook, output } => {
+            let ids = match notebook {
+                Some(notebook) => vec![find_notebook(pool, &notebook).await?],
+                None => sqlx::query_as::<_, (i32,)>("SELECT id FROM notebooks ORDER BY id")
+                    .fetch_all(pool)
+                    .await?
+                    .into_iter()
+                    .map(|(id,)| id)
+                    .collect(),
+            };
+            let mut notebooks = Vec::new();
+            for id in ids {
+                // it could have been deleted since the ids were read
+                if let Some(notebook) = Notebook::get_from_id(pool, id).await? {
+                    notebooks.push(notebook);
+                }
+            }
+            // a single notebook is exported on its own, rather than in a list
+            let json = match notebooks.as_slice() {
+                [notebook] => serde_json::to_vec_pretty(notebook)?,
+                notebooks => serde_json::to_vec_pretty(notebooks)?,
+            };
+            match output {
+                Some(path) => std::fs::write(&path, json)
+                    .with_context(|| format!("couldn't write {}", path.display()))?,
+                None => std::io::stdout().write_all(&json)?,
+            }
+        }
+        AdminCommand::Import { file, notebook } => {
+            let id = find_notebook(pool, &notebook).await?;
+            let bytes = std::fs::read(&file)
+                .with_context(|| format!("couldn't read {}", file.display()))?;
+            let file_name = file
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let (texts, skipped) =
+                crate::import::read_upload(&file_name, bytes).map_err(anyhow::Error::msg)?;
+            let mut transaction = pool.begin().await?;
+            // like the import page, since the texts can't be encrypted here, and holding off encryption
+            // being turned on until they're in
+            if crate::encryption::is_encrypted_for_share(&mut *transaction, id).await? {
+                bail!("notes can't be imported into an encrypted notebook");
+            }
+            let (inserted, duplicates) =
+                crate::import::merge_texts(&mut *transaction, id, texts).await?;
+            transaction.commit().await?;
+            for file in skipped {
+                eprintln!("skipped {}: {}", file.path, file.reason);
+            }
+            for source in duplicates {
+                eprintln!("skipped {source}: already in the notebook");
+            }
+            // the server isn't told, so anyone with the notebook open will only see them after reloading
+            println!("imported {} texts into notebook {id}", inserted.len());
+        }
+        AdminCommand::PurgeTrash => {
+            let purged = purge_trash(pool, config).await?;
+            println!(
+                "deleted {} sessions, {} share links and {} login throttles",
+                purged.sessions, purged.share_links, purged.login_throttles
+            );
+        }
+        AdminCommand::Backup { output } => {
+            let manifest = crate::backup::backup(pool, &output).await?;
+            println!(
+                "backed up {} rows to {}",
+                manifest.total_rows(),
+                output.display()
+            );
+        }
+        AdminCommand::FailedJobs => {
+            for job in crate::jobs::failed_jobs(pool).await? {
+                println!(
+                    "{:>6}  {}  failed {} after {} attempts: {}",
+                    job.id,
+                    job.job,
+                    job.failed_at,
+                    job.attempts,
+                    job.last_error.as_deref().unwrap_or("no error recorded")
+                );
+            }
+        }
+        AdminCommand::RetryJob { id } => {
+            if !crate::jobs::retry(pool, id).await? {
+                bail!("there's no failed job {id}, or the same job is already queued");
+            }
+            println!("queued job {id} to run again");
+        }
+        AdminCommand::Restore { file } => {
+            let manifest = crate::backup::restore(pool, &file).await?;
+            println!(
+                "restored {} rows from {}",
+                manifest.total_rows(),
+                file.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// How many of each thing [`purge_trash`] deleted.
+pub struct Purged {
+    pub sessions: u64,
+    pub share_links: u64,
+    pub login_throttles: u64,
+}
+
+/// Deletes what's no use to anyone any more, once it's been that way for longer than `config.retention` says.
+/// Notes aren't touched: deleting them is already permanent.
+pub async fn purge_trash(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    config: &Config,
+) -> Result<Purged, sqlx::Error> {
+    let retention = &config.retention;
+    let sessions = sqlx::query(
+        "DELETE FROM sessions
+        WHERE expires_at < now() - make_interval(days => $1)
+        OR created_at < now() - make_interval(secs => $2) - make_interval(days => $1)",
+    )
+    .bind(retention.expired_sessions_days as i32)
+    .bind(config.sessions.absolute_timeout_secs as f64)
+    .execute(pool)
+    .await?
+    .rows_affected();
+    let share_links = sqlx::query(
+        "DELETE FROM share_links
+        WHERE COALESCE(revoked_at, expires_at) < now() - make_interval(days => $1)",
+    )
+    .bind(retention.expired_share_links_days as i32)
+    .execute(pool)
+    .await?
+    .rows_affected();
+    // failures are forgotten after a day without any, so older rows don't count for anything
+    let login_throttles = sqlx::query(
+        "DELETE FROM login_throttles
+        WHERE last_failure_at < now() - make_interval(days => $1)
+        AND (locked_until IS NULL OR locked_until < now())",
+    )
+    .bind(retention.login_throttles_days as i32)
+    .execute(pool)
+    .await?
+    .rows_affected();
+    Ok(Purged {
+        sessions,
+        share_links,
+        login_throttles,
+    })
+}
+
+async fn find_notebook(pool: &sqlx::Pool<sqlx::Postgres>, name: &str) -> anyhow::Result<i32> {
+    Notebook::id_from_name(pool, name.trim())
+        .await?
+        .with_context(|| format!("there's no notebook called \"{}\"", name.trim()))
+}
+
+fn password_or_stdin(password: Option<String>) -> anyhow::Result<String> {
+    let password = match password {
+        Some(password) => password,
+        None => {
+            if std::io::stdin().is_terminal() {
+                eprint!("password: ");
+            }
+            let mut password = String::new();
+            std::io::stdin().read_line(&mut password)?;
+            password.trim_end_matches(['\r', '\n']).to_string()
+        }
+    };
+    if password.is_empty() {
+        bail!("the password can't be empty");
+    }
+    Ok(password)
+}
@@ -1,16 +1,18 @@
 #![allow(non_snake_case)]
 
-use std::{cell::RefCell, rc::Rc, str::FromStr, time::Duration};
+use std::{cell::RefCell, collections::HashMap, rc::Rc, time::Duration};
 
 use gloo_timers::future::sleep;
+
+use leptos::server_fn::codec::GetUrl;
 use leptos::{
-    either::{Either, EitherOf4},
+    either::{Either, EitherOf3},
     logging::log,
     prelude::*,
     tachys::dom::window,
     task::spawn_local,
 };
-use leptos_meta::{provide_meta_context, Stylesheet, Title};
+use leptos_meta::{provide_meta_context, Title};
 use leptos_router::{
     components::{Route, Router, Routes},
     hooks::{use_navigate, use_params},
@@ -23,7 +25,21 @@ use web_sys::{
     Blob, BlobPropertyBag, HtmlAnchorElement, MediaDevices, MediaStreamConstraints, Navigator,
 };
 
-use crate::notebook::{Notebook, TextFile};
+use crate::{
+    accounts::AccountPage,
+    cells::{self, RemoteCursors},
+    encryption::{notebook_encryption, EncryptionParams, UnlockNotebook},
+    error::{AppError, ErrorMessage},
+    home::{remember_opened, RecentNotebooks},
+    import::ImportPage,
+    notebook::{Notebook, NotebookChange, TextFile},
+    sessions::{LogOutButton, SessionsPage},
+    settings::NotebookSettingsPage,
+    share_links::SharedPage,
+    sharing::{notebook_role, Role},
+    sync::{ClientMessage, SyncConnection, SyncEvent},
+    two_factor::{LoginStep, SecondFactorForm, VerifySecondFactor},
+};
 
 #[component]
 pub fn App() -> impl IntoView {
@@ -31,10 +47,6 @@ pub fn App() -> impl IntoView {
     provide_meta_context();
 
     view! {
-        // injects a stylesheet into the document <head>
-        // id=leptos means cargo-leptos will hot-reload this stylesheet
-        <Stylesheet id="leptos" href="/pkg/note-to-self.css"/>
-
         // sets the document title
         <Title text="Note to self"/>
 
@@ -43,7 +55,13 @@ pub fn App() -> impl IntoView {
             <main>
                 <Routes fallback=move || "Not found.">
                     <Route path=StaticSegment("") view=HomePage/>
+                    <Route path=path!("/account") view=AccountPage />
                     <Route path=path!("/notebook/:id") view=NotebookPage />
+                    <Route path=path!("/notebook/:id/import") view=ImportPage />
+                    <Route path=path!("/notebook/:id/settings") view=NotebookSettingsPage />
+                    <Route path=path!("/import") view=ImportPage />
+                    <Route path=path!("/s/:token") view=SharedPage />
+                    <Route path=path!("/sessions") view=SessionsPage />
                     <Route path=WildcardSegment("any") view=NotFound/>
                 </Routes>
             </main>
@@ -52,100 +70,139 @@ pub fn App() -> impl IntoView {
 }
 
 #[cfg(feature = "ssr")]
-async fn get_pool_from_context_with_custom_error_type<E>(
-) -> Result<sqlx::Pool<sqlx::Postgres>, ServerFnError<E>> {
+pub(crate) async fn get_pool_from_context() -> Result<sqlx::Pool<sqlx::Postgres>, AppError> {
     match use_context::<crate::AppState>() {
-        Some(crate::AppState { pool }) => Ok(pool),
-        None => Err(ServerFnError::ServerError::<E>(String::from(
+        Some(crate::AppState { pool, .. }) => Ok(pool),
+        None => Err(AppError::Storage(String::from(
             "Expected app state context",
         ))),
     }
 }
 
 #[cfg(feature = "ssr")]
-#[inline]
-async fn get_pool_from_context() -> Result<sqlx::Pool<sqlx::Postgres>, ServerFnError> {
-    get_pool_from_context_with_custom_error_type::<server_fn::error::NoCustomError>().await
-}
-
-#[derive(Debug, Clone, Copy)]
-pub struct NoAccessToNotebookError;
-impl std::fmt::Display for NoAccessToNotebookError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Not authorised to access that notebook!")
+pub(crate) fn get_config_from_context() -> Result<std::sync::Arc<crate::config::Config>, AppError> {
+    match use_context::<crate::AppState>() {
+        Some(crate::AppState { config, .. }) => Ok(config),
+        None => Err(AppError::Storage(String::from(
+            "Expected app state context",
+        ))),
     }
 }
-impl FromStr for NoAccessToNotebookError {
-    type Err = ();
 
-    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        if s == Self::to_string(&NoAccessToNotebookError {}).as_str() {
-            Ok(Self)
-        } else {
-            Err(())
+/// A `GET`, so the browser can cache it: it's sent with an ETag, and revalidated every time, so
+/// reloading a notebook which hasn't changed gets a `304` instead of the whole notebook again.
+#[server(prefix = "/api", input = GetUrl)]
+#[tracing::instrument(skip_all, fields(notebook_id = id))]
+pub(crate) async fn get_notebook(id: i32) -> Result<Notebook, ServerFnError<AppError>> {
+    let caller: crate::auth::Caller = leptos_actix::extract().await.map_err(AppError::from)?;
+    if caller.can_read(id) {
+        let pool = get_pool_from_context().await?;
+        let notebook = Notebook::get_from_id(&pool, id)
+            .await
+            .map_err(AppError::from)?
+            .ok_or_else(|| AppError::NotFound(format!("Couldn't find a notebook with id {id}!")))?;
+        if let Ok(bytes) = serde_json::to_vec(&notebook) {
+            use actix_web::http::header::{self, HeaderValue};
+
+            let response = expect_context::<leptos_actix::ResponseOptions>();
+            // private, since it's only for whoever's logged in
+            response.insert_header(
+                header::CACHE_CONTROL,
+                HeaderValue::from_static("private, no-cache"),
+            );
+            response.insert_header(
+                header::ETAG,
+                HeaderValue::from_str(&crate::caching::etag(&bytes))
+                    .expect("an etag should be a valid header value"),
+            );
         }
+        Ok(notebook)
+    } else {
+        Err(AppError::Unauthorized("You don't have access to that notebook!".to_string()).into())
     }
 }
 
+/// Saves what's been changed on one device. An edited text is only saved if nobody else has changed
+/// it since, so their edits aren't undone; that's a conflict, and the device fetches the notebook again.
 #[server(prefix = "/api")]
-async fn get_notebook(id: i32) -> Result<Notebook, ServerFnError<NoAccessToNotebookError>> {
-    let Ok(session): Result<actix_session::Session, _> = leptos_actix::extract().await else {
-        return Err(ServerFnError::ServerError::<NoAccessToNotebookError>(
-            "can't get session from request!".to_string(),
-        ));
-    };
-    if dbg!(session
-        .get("notebook_id")
-        .expect("Should be able to get id from session"))
-    .is_some_and(|notebook_id: i32| notebook_id == id)
-    {
-        Notebook::get_from_id(
-            &get_pool_from_context_with_custom_error_type::<NoAccessToNotebookError>().await?,
-            id,
-        )
-        .await
-        .map_err(|e| ServerFnError::ServerError::<NoAccessToNotebookError>(e.to_string()))?
-        .map(Ok)
-        .unwrap_or_else(|| {
-            Err(ServerFnError::ServerError::<NoAccessToNotebookError>(
-                format!("Couldn't find a notebook with id {id}!"),
-            ))
-        })
-    } else {
-        leptos_actix::redirect("/");
-        Err(ServerFnError::WrappedServerError(NoAccessToNotebookError))
+#[tracing::instrument(skip_all, fields(notebook_id = id))]
+async fn save_notebook(
+    id: i32,
+    changes: Vec<NotebookChange>,
+) -> Result<(), ServerFnError<AppError>> {
+    tracing::debug!(?changes, "saving notebook");
+    let caller: crate::auth::Caller = leptos_actix::extract().await.map_err(AppError::from)?;
+    if !caller.can_write(id) {
+        return Err(
+            AppError::Unauthorized("You don't have access to that notebook!".to_string()).into(),
+        );
     }
+    let pool = get_pool_from_context().await?;
+    crate::metrics::record_save(changes.len());
+    // whatever was saved before a change failed still has to be passed on
+    let mut events = Vec::new();
+    let saved = save_changes(&pool, id, changes, &mut events).await;
+    crate::sync::publish_from_context(id, events);
+    saved.map_err(Into::into)
 }
 
-#[server(prefix = "/api")]
-async fn save_notebook(notebook: Notebook) -> Result<(), ServerFnError> {
-    println!("saving notebook! {:#?}", &notebook);
-    let session: actix_session::Session = leptos_actix::extract().await?;
-    if dbg!(session
-        .get("notebook_id")
-        .expect("Should be able to get id from session"))
-    .is_some_and(|notebook_id: i32| notebook_id == notebook.id())
-    {
-        notebook
-            .save(&get_pool_from_context().await?)
-            .await
-            .map_err(|e| {
-                ServerFnError::ServerError::<server_fn::error::NoCustomError>(e.to_string())
-            })
-    } else {
-        leptos_actix::redirect("/");
-        Err(ServerFnError::ServerError(
-            "You don't have access to that notebook!".to_string(),
-        ))
+/// Edits to a text go through its shared document, see [`cells::save_text`], so they're merged
+/// with everyone else's rather than written over them.
+#[cfg(feature = "ssr")]
+async fn save_changes(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    id: i32,
+    changes: Vec<NotebookChange>,
+    events: &mut Vec<SyncEvent>,
+) -> Result<(), AppError> {
+    let mut stale = None;
+    for change in changes {
+        match change {
+            NotebookChange::TextEdited {
+                id: text_id,
+                base,
+                text,
+            } => match cells::save_text(pool, id, text_id, &base, &text).await {
+                Ok(Some((text, update))) => {
+                    if let Some(update) = update {
+                        events.push(SyncEvent::CellUpdate { text_id, update });
+                    }
+                    events.push(SyncEvent::TextUpdated { text });
+                }
+                // it's been deleted on another device
+                Ok(None) => (),
+                // the rest can still be saved
+                Err(e @ cells::CellError::Stale) => stale = Some(e),
+                Err(e) => return Err(e.into()),
+            },
+            NotebookChange::TextDeleted { id: text_id } => {
+                if TextFile::delete(pool, id, text_id).await? {
+                    events.push(SyncEvent::TextDeleted { id: text_id });
+                }
+            }
+            NotebookChange::TextsReordered { ids } => {
+                Notebook::reorder(pool, id, &ids).await?;
+                events.push(SyncEvent::TextsReordered { ids });
+            }
+        }
     }
+    stale.map_or(Ok(()), |e| Err(e.into()))
 }
 
 /// Renders the home page of your application.
 #[component]
 fn HomePage() -> impl IntoView {
+    // filled in by picking a notebook this device has opened before
+    let notebook_name = RwSignal::new(String::new());
     view! {
+        <div id="home-links">
+            <a href="/account"> "Account" </a>
+            " "
+            <a href="/sessions"> "Sessions" </a>
+        </div>
+        <NotebookSelectionPage notebook_name />
+        <RecentNotebooks notebook_name />
         <AudioRecordingComponent />
-        // <NotebookSelectionPage />
     }
 }
 
@@ -242,75 +299,87 @@ fn AudioRecordingComponent() -> impl IntoView {
 }
 
 #[server(prefix = "/api")]
+#[tracing::instrument(skip_all)]
 async fn select_notebook(
     notebook_name: String,
     notebook_password: String,
-) -> Result<(), ServerFnError> {
+) -> Result<LoginStep, ServerFnError<AppError>> {
     use leptos_actix::extract;
-    let session: actix_session::Session = extract().await?;
-    let notebook_id: Option<i32> = sqlx::query_as("SELECT id FROM notebooks WHERE UPPER(name) = UPPER($1) AND password_hash = digest($2, 'sha256')")
-        .bind(notebook_name)
-        .bind(notebook_password)
-        .fetch_optional(&get_pool_from_context().await?)
+    let session: actix_session::Session = extract().await.map_err(AppError::from)?;
+    let req: actix_web::HttpRequest = extract().await.map_err(AppError::from)?;
+    let pool = get_pool_from_context().await?;
+    let throttle = crate::throttle::LoginKeys::new(&req, "notebook", &notebook_name);
+    if let Some(seconds) = throttle.locked_for(&pool).await.map_err(AppError::from)? {
+        return Err(AppError::Validation(crate::throttle::locked_out_message(seconds)).into());
+    }
+    let notebook_id = Notebook::log_in(&pool, &notebook_name, &notebook_password)
         .await
-        .map_err(|e| e.to_string())
-        .map_err(ServerFnError::<server_fn::error::NoCustomError>::ServerError)?
-        .map(|(x,)| x);
+        .map_err(AppError::from)?;
+    crate::metrics::record_login("notebook", notebook_id.is_some());
     if let Some(notebook_id) = notebook_id {
-        session
-            .insert("notebook_id", notebook_id)
-            .expect("failed to set notebook id");
-        leptos_actix::redirect(&format!("/notebook/{notebook_id}"));
-        Ok(())
+        throttle
+            .record_success(&pool)
+            .await
+            .map_err(AppError::from)?;
+        Ok(crate::two_factor::password_checked(
+            &pool,
+            &session,
+            crate::two_factor::FactorOwner::Notebook(notebook_id),
+        )
+        .await
+        .map_err(AppError::from)?)
     } else {
-        Err(ServerFnError::ServerError(String::from(
-            "The notebook name or password is incorrect!",
-        )))
+        // the same answer whether or not the notebook exists
+        throttle
+            .record_failure(&pool)
+            .await
+            .map_err(AppError::from)?;
+        Err(
+            AppError::Validation(String::from("The notebook name or password is incorrect!"))
+                .into(),
+        )
     }
 }
 
 #[server(prefix = "/api")]
+#[tracing::instrument(skip_all)]
 async fn create_notebook(
     notebook_name: String,
     notebook_password: String,
-) -> Result<(), ServerFnError> {
+) -> Result<(), ServerFnError<AppError>> {
     use leptos_actix::extract;
-    let session: actix_session::Session = extract().await?;
+    let session: actix_session::Session = extract().await.map_err(AppError::from)?;
     let pool = get_pool_from_context().await?;
-    let already_exists = sqlx::query_as("SELECT id FROM notebooks WHERE UPPER(name) = UPPER($1)")
-        .bind(&notebook_name)
-        .fetch_optional(&pool)
+    let already_exists = Notebook::name_taken(&pool, &notebook_name)
         .await
-        .map_err(|e| ServerFnError::ServerError::<server_fn::error::NoCustomError>(e.to_string()))?
-        .map(|_: (i32,)| true)
-        .unwrap_or(false);
+        .map_err(AppError::from)?;
+    let taken = || -> ServerFnError<AppError> {
+        AppError::Conflict("That notebook already exists!".to_string()).into()
+    };
     if already_exists {
-        Err(ServerFnError::ServerError(
-            "That notebook already exists!".to_string(),
-        ))
+        Err(taken())
     } else {
-        let (id,): (i32,) = sqlx::query_as("INSERT INTO notebooks (name, password_hash) VALUES ($1, digest($2, 'sha256')) RETURNING id")
-            .bind(notebook_name)
-            .bind(notebook_password)
-            .fetch_one(&pool)
-            .await
-            .map_err(|e| {
-                ServerFnError::ServerError::<server_fn::error::NoCustomError>(e.to_string())
-            })?;
-        session
-            .insert("notebook_id", id)
-            .expect("failed to set notebook id");
+        let id = match Notebook::create(&pool, &notebook_name, &notebook_password).await {
+            Ok(id) => id,
+            // someone else took the name since it was checked, which `notebooks_name` catches
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => return Err(taken()),
+            Err(e) => return Err(AppError::from(e).into()),
+        };
+        // a fresh session id, so one set before logging in can't be used to ride along
+        session.renew();
+        session.insert("notebook_id", id).map_err(AppError::from)?;
         leptos_actix::redirect(&format!("/notebook/{id}"));
         Ok(())
     }
 }
 
 #[component]
-fn NotebookSelectionPage() -> impl IntoView {
+fn NotebookSelectionPage(notebook_name: RwSignal<String>) -> impl IntoView {
     let select_notebook = ServerAction::<SelectNotebook>::new();
     let select_notebook_result = select_notebook.value();
     let select_notebook_loading = select_notebook.pending();
     let select_notebook_loaded_time = RwSignal::new(None);
+    let verify_second_factor = ServerAction::<VerifySecondFactor>::new();
     Effect::new(move |_| {
         if select_notebook_loading.get() {
             let start = window()
@@ -342,17 +411,12 @@ fn NotebookSelectionPage() -> impl IntoView {
     let select_form_output = move || {
         if select_notebook_loaded_time.get().is_some() {
             match select_notebook_result.get() {
-                None => EitherOf4::A(view! { <p></p> }),
-                Some(Ok(())) => EitherOf4::B(view! { <p> "Redirecting..." </p> }),
-                Some(Err(ServerFnError::ServerError(e))) => {
-                    EitherOf4::C(view! { <p class="error-message"> {e.to_string()} </p> })
-                }
-                Some(Err(e)) => {
-                    EitherOf4::D(view! { <p class="error-message"> {e.to_string()} </p> })
-                }
+                None | Some(Ok(LoginStep::NeedsCode)) => EitherOf3::A(view! { <p></p> }),
+                Some(Ok(LoginStep::Done)) => EitherOf3::B(view! { <p> "Redirecting..." </p> }),
+                Some(Err(error)) => EitherOf3::C(view! { <ErrorMessage error /> }),
             }
         } else {
-            EitherOf4::A(view! { <p></p> })
+            EitherOf3::A(view! { <p></p> })
         }
     };
 
@@ -391,17 +455,12 @@ fn NotebookSelectionPage() -> impl IntoView {
     let create_form_output = move || {
         if create_notebook_loaded_time.get().is_some() {
             match create_notebook_result.get() {
-                None => EitherOf4::A(view! { <p></p> }),
-                Some(Ok(())) => EitherOf4::B(view! { <p> "Redirecting..." </p> }),
-                Some(Err(ServerFnError::ServerError(e))) => {
-                    EitherOf4::C(view! { <p class="error-message"> {e.to_string()} </p> })
-                }
-                Some(Err(e)) => {
-                    EitherOf4::D(view! { <p class="error-message"> {e.to_string()} </p> })
-                }
+                None => EitherOf3::A(view! { <p></p> }),
+                Some(Ok(())) => EitherOf3::B(view! { <p> "Redirecting..." </p> }),
+                Some(Err(error)) => EitherOf3::C(view! { <ErrorMessage error /> }),
             }
         } else {
-            EitherOf4::A(view! { <p></p> })
+            EitherOf3::A(view! { <p></p> })
         }
     };
 
@@ -412,6 +471,11 @@ fn NotebookSelectionPage() -> impl IntoView {
     }
 
     let form_type = RwSignal::new(FormType::Select);
+    Effect::watch(
+        move || notebook_name.get(),
+        move |_, _, _| form_type.set(FormType::Select),
+        false,
+    );
     let choose_select_notebook = move |_| form_type.set(FormType::Select);
     let choose_create_notebook = move |_| form_type.set(FormType::Create);
 
@@ -425,13 +489,25 @@ fn NotebookSelectionPage() -> impl IntoView {
             <Show when={move || form_type.get() == FormType::Select}>
                 <ActionForm action=select_notebook>
                     <h1> "Select a notebook" </h1>
-                    <input type="text" id="notebook_name" name="notebook_name" placeholder="Notebook Name..." required />
+                    <input
+                        type="text"
+                        id="notebook_name"
+                        name="notebook_name"
+                        placeholder="Notebook Name..."
+                        required
+                        prop:value=move || notebook_name.get()
+                        on:input:target=move |ev| notebook_name.set(ev.target().value())
+                    />
                     <br />
                     <input type="password" id="notebook_password" name="notebook_password" placeholder="Notebook Password..." required />
                     <br />
                     <button type="submit"> "Search" </button>
                 </ActionForm>
                 {select_form_output}
+                // the password was right, but the notebook has two-factor authentication on
+                <Show when={move || matches!(select_notebook_result.get(), Some(Ok(LoginStep::NeedsCode)))}>
+                    <SecondFactorForm action=verify_second_factor />
+                </Show>
             </Show>
 
             <Show when={move || form_type.get() == FormType::Create}>
@@ -491,6 +567,13 @@ fn NotebookComponent(id: i32) -> impl IntoView {
             })
             .unwrap_or_default()
     };
+    // keep up with edits made on other devices
+    let sync = SyncConnection::new();
+    provide_context(sync);
+    // an encrypted notebook stays here, still encrypted, until it's unlocked
+    let locked = RwSignal::new(None::<(EncryptionParams, Notebook)>);
+    // shown instead of the notebook if it couldn't be loaded, or above it if it couldn't be saved
+    let error = RwSignal::new(None::<ServerFnError<AppError>>);
     Effect::new(move |_| {
         let navigate = use_navigate();
         log!("Running the get notebook effect");
@@ -498,27 +581,93 @@ fn NotebookComponent(id: i32) -> impl IntoView {
             log!("spawn-local in the get notebook effect");
             match get_notebook(id).await {
                 Ok(received_notebook) => {
-                    log!("Saving some notebook");
-                    notebook.set(Some(received_notebook));
+                    remember_opened(id, received_notebook.name());
+                    match notebook_encryption(id).await {
+                        Ok(Some(params)) => locked.set(Some((params, received_notebook))),
+                        Ok(None) => {
+                            log!("Saving some notebook");
+                            sync.mark_synced(&received_notebook);
+                            notebook.set(Some(received_notebook));
+                        }
+                        Err(e) => {
+                            log!("couldn't check whether the notebook is encrypted: {:#?}", e)
+                        }
+                    }
                 }
-                Err(ServerFnError::WrappedServerError(NoAccessToNotebookError)) => {
+                Err(ServerFnError::WrappedServerError(AppError::Unauthorized(_))) => {
                     (navigate)("/", NavigateOptions::default());
                 }
-                Err(_) => (), // not really sure what to do here?
+                Err(e) => error.set(Some(e)),
             }
         })
     });
+    // orders the account's recent notebooks; separate from get_notebook, which is a GET
+    Effect::new(move |_| {
+        spawn_local(async move {
+            if let Err(e) = crate::home::notebook_opened(id).await {
+                log!("couldn't note that the notebook was opened: {:#?}", e);
+            }
+        })
+    });
+    // viewers get the notebook without any of the controls for changing it
+    let role = RwSignal::new(None::<Role>);
+    Effect::new(move |_| {
+        spawn_local(async move {
+            match notebook_role(id).await {
+                Ok(received_role) => role.set(received_role),
+                Err(e) => log!("couldn't get our role on the notebook: {:#?}", e),
+            }
+        })
+    });
+    let can_edit = Signal::derive(move || role.get().is_some_and(Role::can_edit));
+    let is_owner = move || role.get() == Some(Role::Owner);
+    Effect::new(move |_| sync.connect(id, notebook));
     Effect::new(move |_| {
         log!("Running an effect because of notebook update");
-        notebook.with(|notebook| {
+        let navigate = use_navigate();
+        let unsaved = notebook.with(|current| {
             log!("Notebook updated?");
-            if let Some(notebook) = notebook.as_ref() {
-                let notebook = notebook.clone();
-                spawn_local(async move {
-                    log!("About to save notebook!");
-                    log!("{:#?}", &notebook);
-                    save_notebook(notebook).await.unwrap();
-                })
+            current
+                .as_ref()
+                .filter(|_| can_edit.get_untracked())
+                .and_then(|current| Some((sync.take_unsaved(current)?, current.clone())))
+        });
+        let Some((base, edited)) = unsaved else {
+            return;
+        };
+        spawn_local(async move {
+            log!("About to save notebook!");
+            log!("{:#?}", &edited);
+            // the server only has ciphertext to compare the edits with
+            let (base, edited) = match sync.key() {
+                Some(key) => {
+                    let known = sync.ciphertexts();
+                    match (
+                        key.encrypt_notebook(&base, &known).await,
+                        key.encrypt_notebook(&edited, &known).await,
+                    ) {
+                        (Ok(base), Ok(encrypted)) => {
+                            sync.remember_ciphertexts(&encrypted, &edited);
+                            (base, encrypted)
+                        }
+                        (Err(e), _) | (_, Err(e)) => {
+                            return log!("couldn't encrypt the notebook: {e}")
+                        }
+                    }
+                }
+                None => (base, edited),
+            };
+            match save_notebook(id, edited.edits_since(&base)).await {
+                Ok(()) => error.set(None),
+                Err(ServerFnError::WrappedServerError(AppError::Unauthorized(_))) => {
+                    navigate("/", NavigateOptions::default())
+                }
+                // show what it was changed to instead
+                Err(e @ ServerFnError::WrappedServerError(AppError::Conflict(_))) => {
+                    sync.resync(id, notebook);
+                    error.set(Some(e));
+                }
+                Err(e) => error.set(Some(e)),
             }
         });
     });
@@ -530,43 +679,73 @@ fn NotebookComponent(id: i32) -> impl IntoView {
             <div class="center">
                 <h1> {notebook_name} </h1>
             </div>
+            <div class="right">
+                <Show when=move || can_edit.get()>
+                    <a href=format!("/notebook/{id}/import")> "import" </a>
+                </Show>
+                " "
+                <Show when=is_owner>
+                    <a href=format!("/notebook/{id}/settings")> "settings" </a>
+                </Show>
+                " "
+                <a href="/sessions"> "sessions" </a>
+                " "
+                <LogOutButton />
+            </div>
         </div>
+        {move || error.get().map(|error| view! { <ErrorMessage error /> })}
+        {move || {
+            locked
+                .get()
+                .filter(|_| notebook.with(Option::is_none))
+                .map(|(params, encrypted)| view! { <UnlockNotebook params encrypted notebook /> })
+        }}
         <For
             each={text_ids}
             key={move |id| *id}
-            children={move |id| view! {<TextInputCell id notebook />}}
+            children={move |id| view! {<TextInputCell id notebook can_edit />}}
         />
-        <AddTextButton notebook />
+        <Show when=move || can_edit.get()>
+            <AddTextButton notebook />
+        </Show>
     }
 }
 
 #[server(prefix = "/api")]
-async fn add_new_text_to_notebook(id: i32) -> Result<TextFile, ServerFnError> {
-    let session: actix_session::Session = leptos_actix::extract().await?;
-    if session
-        .get("notebook_id")
-        .expect("should be able to get notebook id from session")
-        .is_some_and(|notebook_id: i32| notebook_id == id)
-    {
-        sqlx::query_as(
-            "INSERT INTO texts (notebook_id, text) VALUES ($1, 'New Text Box...') RETURNING id, text",
-        )
-        .bind(id)
-        .fetch_one(&get_pool_from_context().await?)
-        .await
-        .map_err(|e| ServerFnError::ServerError::<server_fn::error::NoCustomError>(e.to_string()))
-        .map(|(id, text)| TextFile::new(id, text))
+#[tracing::instrument(skip_all, fields(notebook_id = id))]
+async fn add_new_text_to_notebook(id: i32) -> Result<TextFile, ServerFnError<AppError>> {
+    let caller: crate::auth::Caller = leptos_actix::extract().await.map_err(AppError::from)?;
+    if caller.can_write(id) {
+        let pool = get_pool_from_context().await?;
+        // the placeholder would be readable in an encrypted notebook
+        let placeholder = if crate::encryption::is_encrypted(&pool, id)
+            .await
+            .map_err(AppError::from)?
+        {
+            String::new()
+        } else {
+            "New Text Box...".to_string()
+        };
+        let text = Notebook::insert_texts(&pool, id, &[placeholder])
+            .await
+            .map_err(AppError::from)?
+            .pop()
+            .ok_or_else(|| AppError::Storage("Couldn't add a new text!".to_string()))?;
+        crate::sync::publish_from_context(
+            id,
+            [crate::sync::SyncEvent::TextInserted { text: text.clone() }],
+        );
+        Ok(text)
     } else {
-        leptos_actix::redirect("/");
-        Err(ServerFnError::ServerError(
-            "You don't have access to that notebook!".to_string(),
-        ))
+        Err(AppError::Unauthorized("You don't have access to that notebook!".to_string()).into())
     }
 }
 
 #[component]
 fn AddTextButton(notebook: RwSignal<Option<Notebook>>) -> impl IntoView {
+    let navigate = use_navigate();
     let add_text = move || {
+        let navigate = navigate.clone();
         log!("{:#?}", notebook.get());
         if let Some(id) = notebook.with(|notebook| notebook.as_ref().map(|notebook| notebook.id()))
         {
@@ -575,6 +754,9 @@ fn AddTextButton(notebook: RwSignal<Option<Notebook>>) -> impl IntoView {
                     Ok(text) => {
                         notebook.update(|notebook| notebook.as_mut().unwrap().add_new_text(text))
                     }
+                    Err(ServerFnError::WrappedServerError(AppError::Unauthorized(_))) => {
+                        navigate("/", NavigateOptions::default())
+                    }
                     Err(e) => log!("Noooooo there was an error :( {:#?}", e),
                 }
             })
@@ -586,59 +768,226 @@ fn AddTextButton(notebook: RwSignal<Option<Notebook>>) -> impl IntoView {
 }
 
 #[component]
-fn TextInputCell(id: i32, notebook: RwSignal<Option<Notebook>>) -> impl IntoView {
+fn TextInputCell(
+    id: i32,
+    notebook: RwSignal<Option<Notebook>>,
+    can_edit: Signal<bool>,
+) -> impl IntoView {
     let active = RwSignal::new(false);
     let text = RwSignal::new(String::new());
     let size: RwSignal<Option<(i32, i32)>> = RwSignal::new(None);
+    let sync = use_context::<SyncConnection>();
+    // while editing, the cell's shared document, and where everyone else's cursors are in it
+    let doc = StoredValue::new_local(None::<yrs::Doc>);
+    let remote_cursors = RwSignal::new(HashMap::<u64, Vec<u8>>::new());
+    let pending = StoredValue::new(Vec::<Vec<u8>>::new());
+    // whether there are edits which didn't reach the server through the document, so have to be
+    // saved with the notebook once editing stops; the others are there already
+    let unsent = StoredValue::new(false);
 
-    Effect::new(move |updated: Option<bool>| {
-        if updated.is_some_and(|x| x) {
-            true
-        } else {
-            notebook.with(|notebook| {
-                if let Some(notebook) = notebook.as_ref() {
-                    text.set(
-                        notebook
-                            .texts()
-                            .find(|x| x.id() == id)
-                            .unwrap()
-                            .text()
-                            .to_string(),
-                    );
-                    true
-                } else {
-                    false
-                }
-            })
+    // pick up the text once the notebook loads, along with any edits from other devices
+    // which arrive while we aren't editing it ourselves
+    Effect::new(move |_| {
+        let latest = notebook.with(|notebook| {
+            notebook
+                .as_ref()
+                .and_then(|notebook| notebook.texts().find(|x| x.id() == id))
+                .map(|text| text.text().to_string())
+        });
+        if let Some(latest) = latest {
+            if !active.get_untracked() && latest != text.get_untracked() {
+                text.set(latest);
+            }
         }
     });
     let textarea_ref = NodeRef::<leptos::html::Textarea>::new();
+    let notebook_id =
+        move || notebook.with_untracked(|notebook| notebook.as_ref().map(|notebook| notebook.id()));
+
+    let send_cursor = move || {
+        let (Some(sync), Some(textarea)) = (sync, textarea_ref.get_untracked()) else {
+            return;
+        };
+        let Some(offset) = textarea.selection_end().ok().flatten() else {
+            return;
+        };
+        let position =
+            doc.with_value(|doc| doc.as_ref().and_then(|doc| cells::cursor_at(doc, offset)));
+        if position.is_some() {
+            sync.send(&ClientMessage::Cursor {
+                text_id: id,
+                position,
+            });
+        }
+    };
+    // fetches the document, and sends along anything typed before it arrived
+    // or while the connection was down
+    let load_doc = move || {
+        let (Some(sync), Some(notebook_id)) = (sync, notebook_id()) else {
+            return;
+        };
+        spawn_local(async move {
+            let state = match cells::get_cell_state(notebook_id, id).await {
+                Ok(state) => state,
+                Err(e) => return log!("couldn't load the cell's document: {:#?}", e),
+            };
+            let new_doc = match cells::doc_from_state(&state) {
+                Ok(new_doc) => new_doc,
+                Err(e) => return log!("couldn't read the cell's document: {e}"),
+            };
+            if !active.get_untracked() {
+                return;
+            }
+            for update in pending.try_update_value(std::mem::take).unwrap_or_default() {
+                let _ = cells::apply_update(&new_doc, &update);
+            }
+            let local = text.get_untracked();
+            let sent = cells::doc_text(&new_doc) == local
+                || sync.send(&ClientMessage::CellUpdate {
+                    text_id: id,
+                    update: cells::splice_to(&new_doc, &local),
+                });
+            if sent {
+                unsent.set_value(false);
+            }
+            doc.set_value(Some(new_doc));
+            send_cursor();
+        });
+    };
+    let apply_remote_update = move |update: Vec<u8>| {
+        doc.with_value(|doc| {
+            let Some(doc) = doc.as_ref() else {
+                // the document is still loading, and may not have this update in it
+                pending.update_value(|pending| pending.push(update));
+                return;
+            };
+            // anchor the selection to the text around it, so it stays put as the text changes
+            let textarea = textarea_ref.get_untracked();
+            let selection = textarea.as_ref().and_then(|textarea| {
+                let start = textarea.selection_start().ok().flatten()?;
+                let end = textarea.selection_end().ok().flatten()?;
+                Some((cells::cursor_at(doc, start)?, cells::cursor_at(doc, end)?))
+            });
+            if let Err(e) = cells::apply_update(doc, &update) {
+                return log!("couldn't apply a cell update: {e}");
+            }
+            let new_text = cells::doc_text(doc);
+            if let Some(textarea) = textarea {
+                textarea.set_value(&new_text);
+                if let Some((start, end)) = selection {
+                    if let (Some(start), Some(end)) = (
+                        cells::cursor_offset(doc, &start),
+                        cells::cursor_offset(doc, &end),
+                    ) {
+                        let _ = textarea.set_selection_range(start, end);
+                    }
+                }
+            }
+            text.set(new_text);
+        });
+    };
+
     Effect::new(move |_| {
         log!("Activity changed");
         if !active.get() {
             log!("inactive");
-            notebook.update(|notebook| {
-                log!("{:#?}", &notebook);
-                if let Some(notebook) = notebook.as_mut() {
-                    notebook.set_text(id, text.get());
-                }
-            });
+            // edits which went through the document come back from the server by themselves
+            if unsent.try_update_value(std::mem::take).unwrap_or_default() {
+                notebook.update(|notebook| {
+                    log!("{:#?}", &notebook);
+                    if let Some(notebook) = notebook.as_mut() {
+                        notebook.set_text(id, text.get());
+                    }
+                });
+            }
         } else {
             if let Some(textarea_ref) = textarea_ref.get() {
                 let _ = textarea_ref.focus();
             }
         }
     });
+    // edit together with anyone else who has this cell open
+    Effect::new(move |_| {
+        // the server can't merge edits it can't read
+        let Some(sync) = sync.filter(|sync| sync.key().is_none()) else {
+            return;
+        };
+        if active.get() {
+            sync.listen(id, move |event| match event {
+                SyncEvent::CellUpdate { update, .. } => apply_remote_update(update),
+                SyncEvent::Cursor {
+                    client, position, ..
+                } => remote_cursors.update(|cursors| {
+                    match position {
+                        Some(position) => cursors.insert(client, position),
+                        None => cursors.remove(&client),
+                    };
+                }),
+                SyncEvent::Resync => load_doc(),
+                _ => (),
+            });
+            load_doc();
+        } else if doc.with_value(|doc| doc.is_some()) {
+            sync.send(&ClientMessage::Cursor {
+                text_id: id,
+                position: None,
+            });
+            sync.stop_listening(id);
+            doc.set_value(None);
+            pending.set_value(Vec::new());
+            remote_cursors.set(HashMap::new());
+        }
+    });
+    on_cleanup(move || {
+        if let Some(sync) = sync {
+            sync.stop_listening(id);
+        }
+    });
+    let cursor_offsets = Signal::derive(move || {
+        text.track();
+        doc.with_value(|doc| {
+            doc.as_ref()
+                .map(|doc| {
+                    remote_cursors.with(|cursors| {
+                        cursors
+                            .values()
+                            .filter_map(|position| cells::cursor_offset(doc, position))
+                            .collect()
+                    })
+                })
+                .unwrap_or_default()
+        })
+    });
+    let on_input = move |value: String| {
+        let update = doc.with_value(|doc| doc.as_ref().map(|doc| cells::splice_to(doc, &value)));
+        let sent = match (sync, update) {
+            (Some(sync), Some(update)) => sync.send(&ClientMessage::CellUpdate {
+                text_id: id,
+                update,
+            }),
+            _ => false,
+        };
+        if !sent {
+            unsent.set_value(true);
+        }
+        text.set(value);
+        send_cursor();
+    };
     let inner_active = move || {
         view! {
-            <textarea
-                prop:value=move || text.get()
-                on:input:target=move |ev| text.set(ev.target().value())
-                style={move || if let Some(size) = size.get() { format!("width: {}px; height: {}px", size.0, size.1) } else { String::new() } + if active.get() { "" } else { "display: none;" }}
-                node_ref=textarea_ref
-            >
-                {text.get_untracked()}
-            </textarea>
+            <div class="cell-editor">
+                <RemoteCursors text offsets=cursor_offsets />
+                <textarea
+                    prop:value=move || text.get()
+                    on:input:target=move |ev| on_input(ev.target().value())
+                    on:keyup=move |_| send_cursor()
+                    on:click=move |_| send_cursor()
+                    style={move || if let Some(size) = size.get() { format!("width: {}px; height: {}px", size.0, size.1) } else { String::new() } + if active.get() { "" } else { "display: none;" }}
+                    node_ref=textarea_ref
+                >
+                    {text.get_untracked()}
+                </textarea>
+            </div>
         }
     };
     let inner_inactive = move || {
@@ -669,18 +1018,33 @@ fn TextInputCell(id: i32, notebook: RwSignal<Option<Notebook>>) -> impl IntoView
             });
         });
     };
+    let move_by = move |by: isize| {
+        notebook.update(|notebook| {
+            if let Some(notebook) = notebook.as_mut() {
+                notebook.move_text(id, by);
+            }
+        });
+    };
     let footer = move || {
-        if active.get() {
-            Either::Left(view! {
+        if !can_edit.get() {
+            EitherOf3::A(())
+        } else if active.get() {
+            EitherOf3::B(view! {
                 <span on:click=save >
                     "Save"
                 </span>
             })
         } else {
-            Either::Right(view! {
+            EitherOf3::C(view! {
                 <span on:click=move |_| { active.set(true); }>
                     "Edit"
                 </span>
+                <span on:click=move |_| move_by(-1)>
+                    "Up"
+                </span>
+                <span on:click=move |_| move_by(1)>
+                    "Down"
+                </span>
                 <span on:click=delete>
                     "Delete"
                 </span>
@@ -0,0 +1,256 @@
+//! Working out who is making a request, whether it comes from a browser or a script.
+
+use std::{collections::HashMap, future::Future, pin::Pin, sync::Arc};
+
+use actix_session::SessionExt;
+use actix_web::{dev::Payload, http::header, web, FromRequest, HttpRequest};
+
+use crate::{
+    rest::ApiError,
+    sessions::logged_into,
+    sharing::{Member, Role},
+    tokens::{ApiToken, TokenGrant, TokenOwner, TokenScope},
+    AppState,
+};
+
+#[derive(Debug, Clone)]
+pub enum Caller {
+    Anonymous,
+    /// A browser which logged in to a notebook with its password, to an account, or both.
+    Session {
+        /// The session's row in `sessions`, so it can be checked again later.
+        session_id: Option<i32>,
+        notebook_id: Option<i32>,
+        user_id: Option<i32>,
+        /// The notebooks shared with the account, and the role it was given on each.
+        memberships: Arc<HashMap<i32, Role>>,
+    },
+    /// A script presenting a bearer token, for one notebook or for an account.
+    Token {
+        token_id: i32,
+        /// The notebook the token was created for, if it wasn't created for an account.
+        notebook_id: Option<i32>,
+        /// The notebooks shared with the token's account, and the role it was given on each.
+        memberships: Arc<HashMap<i32, Role>>,
+        scope: TokenScope,
+    },
+}
+impl Caller {
+    /// Knowing a notebook's password makes you its owner.
+    pub fn role(&self, id: i32) -> Option<Role> {
+        match self {
+            Self::Anonymous => None,
+            Self::Session {
+                notebook_id,
+                memberships,
+                ..
+            } => {
+                if *notebook_id == Some(id) {
+                    Some(Role::Owner)
+                } else {
+                    memberships.get(&id).copied()
+                }
+            }
+            Self::Token {
+                notebook_id,
+                memberships,
+                scope,
+                ..
+            } => {
+                // an account's token can't do more than the account can, and never manages the notebook
+                let role = if *notebook_id == Some(id) {
+                    Role::Editor
+                } else {
+                    (*memberships.get(&id)?).min(Role::Editor)
+                };
+                Some(match scope {
+                    TokenScope::ReadOnly => Role::Viewer,
+                    TokenScope::ReadWrite => role,
+                })
+            }
+        }
+    }
+
+    pub fn can_read(&self, id: i32) -> bool {
+        self.role(id).is_some()
+    }
+
+    pub fn can_write(&self, id: i32) -> bool {
+        self.role(id).is_some_and(Role::can_edit)
+    }
+
+    pub fn user_id(&self) -> Option<i32> {
+        match self {
+            Self::Session { user_id, .. } => *user_id,
+            Self::Anonymous | Self::Token { .. } => None,
+        }
+    }
+}
+
+/// A bearer token takes priority over the session cookie.
+/// A token that doesn't check out is rejected outright, rather than falling back to the session.
+impl FromRequest for Caller {
+    type Error = ApiError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let bearer = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_string());
+        let session = req.get_session();
+        let state = req.app_data::<web::Data<AppState>>().cloned();
+        Box::pin(async move {
+            if let Some(token) = bearer {
+                let state = state.ok_or(ApiError::Internal)?;
+                let grant = ApiToken::authenticate(&state.pool, &token)
+                    .await?
+                    .ok_or(ApiError::InvalidToken)?;
+                return Ok(Self::for_token(&state.pool, grant).await?);
+            }
+            let session_id = session.get::<i32>("session_id").ok().flatten();
+            let notebook_id = session.get::<i32>("notebook_id").ok().flatten();
+            let user_id = session.get::<i32>("user_id").ok().flatten();
+            if notebook_id.is_none() && user_id.is_none() {
+                return Ok(Self::Anonymous);
+            }
+            let state = state.ok_or(ApiError::Internal)?;
+            Ok(Self::for_session(&state.pool, session_id, notebook_id, user_id).await?)
+        })
+    }
+}
+
+impl Caller {
+    async fn for_token(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        grant: TokenGrant,
+    ) -> Result<Self, sqlx::Error> {
+        let (notebook_id, memberships) = match grant.owner {
+            TokenOwner::Notebook(notebook_id) => (Some(notebook_id), HashMap::new()),
+            TokenOwner::Account(user_id) => (None, Member::roles_for_user(pool, user_id).await?),
+        };
+        Ok(Self::Token {
+            token_id: grant.token_id,
+            notebook_id,
+            memberships: Arc::new(memberships),
+            scope: grant.scope,
+        })
+    }
+
+    async fn for_session(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        session_id: Option<i32>,
+        notebook_id: Option<i32>,
+        user_id: Option<i32>,
+    ) -> Result<Self, sqlx::Error> {
+        let memberships = match user_id {
+            Some(user_id) => Member::roles_for_user(pool, user_id).await?,
+            None => HashMap::new(),
+        };
+        Ok(Self::Session {
+            session_id,
+            notebook_id,
+            user_id,
+            memberships: Arc::new(memberships),
+        })
+    }
+
+    /// Looks the caller's access up again, for websockets, which outlive the request they were
+    /// authorised by. Anonymous once their session has ended or their token has been revoked.
+    pub async fn refresh(&self, state: &AppState) -> Result<Self, sqlx::Error> {
+        match self {
+            Self::Anonymous => Ok(Self::Anonymous),
+            Self::Session {
+                session_id: Some(session_id),
+                ..
+            } => match logged_into(&state.pool, *session_id, &state.config).await? {
+                Some((notebook_id, user_id)) if notebook_id.is_some() || user_id.is_some() => {
+                    Self::for_session(&state.pool, Some(*session_id), notebook_id, user_id).await
+                }
+                _ => Ok(Self::Anonymous),
+            },
+            Self::Session {
+                session_id: None,
+                notebook_id,
+                user_id,
+                ..
+            } => Self::for_session(&state.pool, None, *notebook_id, *user_id).await,
+            Self::Token { token_id, .. } => match ApiToken::grant(&state.pool, *token_id).await? {
+                Some(grant) => Self::for_token(&state.pool, grant).await,
+                None => Ok(Self::Anonymous),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use super::Caller;
+    use crate::{sharing::Role, tokens::TokenScope};
+
+    fn memberships() -> Arc<HashMap<i32, Role>> {
+        Arc::new(HashMap::from([
+            (2, Role::Owner),
+            (3, Role::Editor),
+            (4, Role::Commenter),
+        ]))
+    }
+
+    fn token(notebook_id: Option<i32>, scope: TokenScope) -> Caller {
+        Caller::Token {
+            token_id: 1,
+            notebook_id,
+            memberships: if notebook_id.is_some() {
+                Arc::default()
+            } else {
+                memberships()
+            },
+            scope,
+        }
+    }
+
+    #[test]
+    fn a_notebook_password_makes_you_its_owner() {
+        let caller = Caller::Session {
+            session_id: None,
+            notebook_id: Some(1),
+            user_id: Some(7),
+            memberships: memberships(),
+        };
+        assert_eq!(caller.role(1), Some(Role::Owner));
+        assert_eq!(caller.role(4), Some(Role::Commenter));
+        assert_eq!(caller.role(5), None);
+        assert_eq!(Caller::Anonymous.role(1), None);
+    }
+
+    #[test]
+    fn tokens_are_at_most_editors() {
+        let notebook_token = token(Some(1), TokenScope::ReadWrite);
+        assert_eq!(notebook_token.role(1), Some(Role::Editor));
+        assert_eq!(notebook_token.role(2), None);
+
+        let account_token = token(None, TokenScope::ReadWrite);
+        assert_eq!(account_token.role(2), Some(Role::Editor));
+        assert_eq!(account_token.role(3), Some(Role::Editor));
+        assert_eq!(account_token.role(4), Some(Role::Commenter));
+        assert_eq!(account_token.role(5), None);
+    }
+
+    #[test]
+    fn read_only_tokens_are_viewers() {
+        let notebook_token = token(Some(1), TokenScope::ReadOnly);
+        assert_eq!(notebook_token.role(1), Some(Role::Viewer));
+        assert!(notebook_token.can_read(1));
+        assert!(!notebook_token.can_write(1));
+
+        let account_token = token(None, TokenScope::ReadOnly);
+        assert_eq!(account_token.role(2), Some(Role::Viewer));
+        assert_eq!(account_token.role(4), Some(Role::Viewer));
+        // it still only reaches the notebooks shared with the account
+        assert_eq!(account_token.role(5), None);
+    }
+}
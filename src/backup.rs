@@ -0,0 +1,191 @@
+//! Whole-database backups, for `note-to-self backup` and `restore`.
+//!
+//! A backup is a zip with a `manifest.json` and one json file per table, holding its rows as json
+//! objects, so it doesn't depend on the version of Postgres, or on Postgres at all. Binary columns
+//! are written as `\x`-prefixed hex, and timestamps as ISO 8601 with their offset.
+//! Sessions, login throttles and background jobs aren't backed up: restoring logs everyone out, and
+//! forgets their failures.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    path::Path,
+};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+const FORMAT: &str = "note-to-self-backup";
+/// Bump this when the layout of the archive changes, rather than the tables in it.
+const VERSION: u32 = 1;
+
+/// Every table worth keeping, in an order where rows only refer to rows in tables before them.
+/// The ones with a `SERIAL` id have their sequence moved past the restored ids.
+const TABLES: &[(&str, bool)] = &[
+    ("notebooks", true),
+    ("texts", true),
+    ("users", true),
+    ("memberships", false),
+    ("api_tokens", true),
+    ("share_links", true),
+    ("second_factors", true),
+    ("recovery_codes", true),
+];
+
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    format: String,
+    version: u32,
+    created_at: String,
+    /// The last migration run on the database it came from, which the tables' columns match.
+    schema_version: i64,
+    /// How many rows each table has, to check nothing went missing.
+    rows: Vec<(String, usize)>,
+}
+
+/// Writes every table to `path`, from a single snapshot so texts can't be saved halfway through.
+pub async fn backup(pool: &sqlx::Pool<sqlx::Postgres>, path: &Path) -> anyhow::Result<Manifest> {
+    let mut transaction = pool.begin().await?;
+    sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY")
+        .execute(&mut *transaction)
+        .await?;
+    let (schema_version, created_at): (i64, String) = sqlx::query_as(
+        "SELECT (SELECT MAX(version) FROM _sqlx_migrations WHERE success),
+        to_json(now())#>>'{}'",
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+
+    // it has every notebook and password hash in it, so only its owner gets to read it
+    let mut file_options = OpenOptions::new();
+    file_options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        file_options.mode(0o600);
+    }
+    let file = file_options
+        .open(path)
+        .with_context(|| format!("couldn't create {}", path.display()))?;
+    let mut archive = ZipWriter::new(file);
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    let mut rows = Vec::new();
+    for (table, _) in TABLES {
+        // rows are ordered column by column, starting with their ids, so backups of the same data come out the same
+        let (json, count): (String, i64) = sqlx::query_as(&format!(
+            "SELECT COALESCE(json_agg(t ORDER BY t), '[]')::text, COUNT(*) FROM {table} t"
+        ))
+        .fetch_one(&mut *transaction)
+        .await?;
+        archive.start_file(format!("{table}.json"), options)?;
+        archive.write_all(json.as_bytes())?;
+        rows.push((table.to_string(), count as usize));
+    }
+    transaction.commit().await?;
+
+    let manifest = Manifest {
+        format: FORMAT.to_string(),
+        version: VERSION,
+        created_at,
+        schema_version,
+        rows,
+    };
+    archive.start_file("manifest.json", options)?;
+    archive.write_all(&serde_json::to_vec_pretty(&manifest)?)?;
+    archive.finish()?.sync_all()?;
+    Ok(manifest)
+}
+
+/// Loads a backup into a database without any notebooks or accounts, keeping every id as it was.
+/// It's all one transaction, so a restore which fails leaves the database empty.
+pub async fn restore(pool: &sqlx::Pool<sqlx::Postgres>, path: &Path) -> anyhow::Result<Manifest> {
+    let file = File::open(path).with_context(|| format!("couldn't open {}", path.display()))?;
+    let mut archive = ZipArchive::new(file).context("that doesn't look like a backup")?;
+    let manifest: Manifest = serde_json::from_str(
+        &read_entry(&mut archive, "manifest.json").context("that doesn't look like a backup")?,
+    )
+    .context("the backup's manifest is damaged")?;
+    if manifest.format != FORMAT {
+        bail!("that doesn't look like a backup");
+    }
+    if manifest.version > VERSION {
+        bail!(
+            "the backup is version {}, but this server only understands up to version {VERSION}",
+            manifest.version
+        );
+    }
+
+    sqlx::migrate!()
+        .run(pool)
+        .await
+        .context("couldn't run the migrations")?;
+    let mut transaction = pool.begin().await?;
+    let (schema_version,): (i64,) =
+        sqlx::query_as("SELECT MAX(version) FROM _sqlx_migrations WHERE success")
+            .fetch_one(&mut *transaction)
+            .await?;
+    // the columns have to match, since rows are put back as they came out
+    if manifest.schema_version != schema_version {
+        bail!(
+            "the backup was made with migration {}, but this server is at migration {schema_version}; \
+            restore it with the same version of note-to-self it was made with",
+            manifest.schema_version
+        );
+    }
+    let (empty,): (bool,) = sqlx::query_as(
+        "SELECT NOT EXISTS (SELECT 1 FROM notebooks) AND NOT EXISTS (SELECT 1 FROM users)",
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+    if !empty {
+        bail!("backups can only be restored into an empty database");
+    }
+
+    for (table, serial) in TABLES {
+        let json = read_entry(&mut archive, &format!("{table}.json"))?;
+        let inserted = sqlx::query(&format!(
+            "INSERT INTO {table} SELECT * FROM json_populate_recordset(NULL::{table}, $1::json)"
+        ))
+        .bind(json)
+        .execute(&mut *transaction)
+        .await
+        .with_context(|| format!("couldn't restore {table}"))?
+        .rows_affected();
+        let expected = manifest
+            .rows
+            .iter()
+            .find(|(name, _)| name == table)
+            .map(|(_, rows)| *rows as u64);
+        if expected.is_some_and(|expected| expected != inserted) {
+            bail!("the backup's {table} are incomplete");
+        }
+        if *serial {
+            sqlx::query(&format!(
+                "SELECT setval(pg_get_serial_sequence('{table}', 'id'), COALESCE(MAX(id), 1), MAX(id) IS NOT NULL)
+                FROM {table}"
+            ))
+            .execute(&mut *transaction)
+            .await?;
+        }
+    }
+    transaction.commit().await?;
+    Ok(manifest)
+}
+
+impl Manifest {
+    pub fn total_rows(&self) -> usize {
+        self.rows.iter().map(|(_, rows)| rows).sum()
+    }
+}
+
+fn read_entry(archive: &mut ZipArchive<File>, name: &str) -> anyhow::Result<String> {
+    let mut entry = archive
+        .by_name(name)
+        .with_context(|| format!("the backup has no {name}"))?;
+    let mut contents = String::new();
+    entry
+        .read_to_string(&mut contents)
+        .with_context(|| format!("couldn't read {name} from the backup"))?;
+    Ok(contents)
+}
@@ -0,0 +1,337 @@
+//! `nts`, a command line client for quickly getting notes in and out of a notebook.
+//! It talks to the `/v1` JSON API using a personal API token.
+
+use std::{
+    io::{IsTerminal, Read, Write},
+    path::PathBuf,
+    process::ExitCode,
+};
+
+use clap::{Parser, Subcommand};
+use note_to_self::notebook::{Notebook, TextFile};
+use serde::{Deserialize, Serialize};
+
+#[derive(Parser)]
+#[command(name = "nts", about = "Quick capture for note-to-self", version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Save the server, notebook and API token to use from now on
+    Login {
+        /// e.g. https://note-to-self.fly.dev
+        #[arg(long)]
+        server: String,
+        #[arg(long)]
+        notebook: i32,
+        /// A token created on the notebook's settings page, or on your account page.
+        /// Read from stdin if it isn't given, so it doesn't end up in your shell history
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Add a text, from the arguments or from stdin
+    Add { text: Vec<String> },
+    /// List every text, with its id and first line
+    Ls,
+    /// Print a text
+    Cat { id: i32 },
+    /// Open a text in $EDITOR, and save it when the editor exits
+    Edit { id: i32 },
+}
+
+/// Stored in `$XDG_CONFIG_HOME/nts/config.json`.
+/// Each field can be overridden with `NTS_SERVER`, `NTS_NOTEBOOK` and `NTS_TOKEN`.
+#[derive(Debug, Serialize, Deserialize)]
+struct Config {
+    server: String,
+    notebook: i32,
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct TextPage {
+    items: Vec<TextFile>,
+    total: i64,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: ErrorDetail,
+}
+#[derive(Deserialize)]
+struct ErrorDetail {
+    message: String,
+}
+
+fn config_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join("nts").join("config.json"))
+}
+
+fn load_config() -> Result<Config, String> {
+    let stored: Option<Config> = config_path()
+        .and_then(|path| std::fs::read(path).ok())
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok());
+    let server = std::env::var("NTS_SERVER")
+        .ok()
+        .or_else(|| stored.as_ref().map(|config| config.server.clone()));
+    let notebook = std::env::var("NTS_NOTEBOOK")
+        .ok()
+        .and_then(|id| id.parse().ok())
+        .or_else(|| stored.as_ref().map(|config| config.notebook));
+    let token = std::env::var("NTS_TOKEN")
+        .ok()
+        .or_else(|| stored.as_ref().map(|config| config.token.clone()));
+    match (server, notebook, token) {
+        (Some(server), Some(notebook), Some(token)) => Ok(Config {
+            server,
+            notebook,
+            token,
+        }),
+        _ => Err("not logged in - run `nts login` first".to_string()),
+    }
+}
+
+fn save_config(config: &Config) -> Result<PathBuf, String> {
+    let path = config_path().ok_or("couldn't work out where to save the config")?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    let json = serde_json::to_vec_pretty(config).map_err(|e| e.to_string())?;
+    // the token is a password, so keep it to ourselves from the moment the file exists
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&path).map_err(|e| e.to_string())?;
+    #[cfg(unix)]
+    {
+        // the mode only applies to new files, so tighten one saved by an older version too
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))
+            .map_err(|e| e.to_string())?;
+    }
+    file.write_all(&json).map_err(|e| e.to_string())?;
+    Ok(path)
+}
+
+struct Client {
+    http: reqwest::blocking::Client,
+    config: Config,
+}
+impl Client {
+    fn new(config: Config) -> Self {
+        Self {
+            http: reqwest::blocking::Client::new(),
+            config,
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!(
+            "{}/v1/notebooks/{}{path}",
+            self.config.server.trim_end_matches('/'),
+            self.config.notebook
+        )
+    }
+
+    fn send<T: for<'de> Deserialize<'de>>(
+        &self,
+        request: reqwest::blocking::RequestBuilder,
+    ) -> Result<T, String> {
+        let response = request
+            .bearer_auth(&self.config.token)
+            .send()
+            .map_err(|e| format!("couldn't reach the server: {e}"))?;
+        if response.status().is_success() {
+            response
+                .json()
+                .map_err(|e| format!("unexpected response from the server: {e}"))
+        } else {
+            let status = response.status();
+            Err(response
+                .json::<ErrorBody>()
+                .map(|body| body.error.message)
+                .unwrap_or_else(|_| format!("the server responded with {status}")))
+        }
+    }
+
+    fn notebook(&self) -> Result<Notebook, String> {
+        self.send(self.http.get(self.url("")))
+    }
+
+    fn texts(&self) -> Result<Vec<TextFile>, String> {
+        let mut texts = Vec::new();
+        loop {
+            let page: TextPage = self.send(
+                self.http
+                    .get(self.url("/texts"))
+                    .query(&[("offset", texts.len() as i64), ("limit", 200)]),
+            )?;
+            let done =
+                page.items.is_empty() || texts.len() as i64 + page.items.len() as i64 >= page.total;
+            texts.extend(page.items);
+            if done {
+                return Ok(texts);
+            }
+        }
+    }
+
+    fn text(&self, id: i32) -> Result<TextFile, String> {
+        self.send(self.http.get(self.url(&format!("/texts/{id}"))))
+    }
+
+    fn add(&self, text: String) -> Result<TextFile, String> {
+        self.send(
+            self.http
+                .post(self.url("/texts"))
+                .json(&serde_json::json!({ "text": text })),
+        )
+    }
+
+    fn update(&self, id: i32, text: String) -> Result<TextFile, String> {
+        self.send(
+            self.http
+                .put(self.url(&format!("/texts/{id}")))
+                .json(&serde_json::json!({ "text": text })),
+        )
+    }
+}
+
+fn edit_in_editor(text: &str) -> Result<String, String> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    let path = std::env::temp_dir().join(format!("nts-{}.md", std::process::id()));
+    // the temp dir is shared, so don't follow a file or link someone else put there, and don't let them read it
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(&path)
+        .and_then(|mut file| file.write_all(text.as_bytes()))
+        .map_err(|e| format!("couldn't create {}: {e}", path.display()))?;
+    // $EDITOR is allowed to have arguments in it, like `code --wait`
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{editor} \"$1\""))
+        .arg("sh")
+        .arg(&path)
+        .status()
+        .map_err(|e| format!("couldn't start {editor}: {e}"));
+    let edited = std::fs::read_to_string(&path).map_err(|e| e.to_string());
+    let _ = std::fs::remove_file(&path);
+    if !status?.success() {
+        return Err(format!("{editor} exited with an error, not saving"));
+    }
+    edited
+}
+
+fn run(cli: Cli) -> Result<(), String> {
+    match cli.command {
+        Command::Login {
+            server,
+            notebook,
+            token,
+        } => {
+            let token = match token {
+                Some(token) => token,
+                None => {
+                    if std::io::stdin().is_terminal() {
+                        eprint!("token: ");
+                    }
+                    let mut token = String::new();
+                    std::io::stdin()
+                        .read_line(&mut token)
+                        .map_err(|e| e.to_string())?;
+                    token.trim().to_string()
+                }
+            };
+            if token.is_empty() {
+                return Err("the token can't be empty".to_string());
+            }
+            let client = Client::new(Config {
+                server,
+                notebook,
+                token,
+            });
+            let name = client.notebook()?.name().to_string();
+            let path = save_config(&client.config)?;
+            println!(
+                "logged in to \"{name}\", config saved to {}",
+                path.display()
+            );
+        }
+        Command::Add { text } => {
+            let client = Client::new(load_config()?);
+            let text = if text.is_empty() {
+                if std::io::stdin().is_terminal() {
+                    return Err("nothing to add - pass some text, or pipe it in".to_string());
+                }
+                let mut text = String::new();
+                std::io::stdin()
+                    .read_to_string(&mut text)
+                    .map_err(|e| e.to_string())?;
+                text
+            } else {
+                text.join(" ")
+            };
+            if text.trim().is_empty() {
+                return Err("not adding an empty note".to_string());
+            }
+            println!("{}", client.add(text)?.id());
+        }
+        Command::Ls => {
+            let client = Client::new(load_config()?);
+            for text in client.texts()? {
+                println!(
+                    "{:>6}  {}",
+                    text.id(),
+                    text.text().lines().next().unwrap_or_default()
+                );
+            }
+        }
+        Command::Cat { id } => {
+            let client = Client::new(load_config()?);
+            println!("{}", client.text(id)?.text());
+        }
+        Command::Edit { id } => {
+            let client = Client::new(load_config()?);
+            let original = client.text(id)?;
+            let mut edited = edit_in_editor(original.text())?;
+            // most editors add a newline at the end of the file
+            if !original.text().ends_with('\n') && edited.ends_with('\n') {
+                edited.pop();
+            }
+            if edited == original.text() {
+                println!("no changes");
+            } else {
+                client.update(id, edited)?;
+                println!("saved {id}");
+            }
+        }
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("nts: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
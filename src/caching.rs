@@ -0,0 +1,118 @@
+//! HTTP caching: `Cache-Control` for the static files, and answering requests for a response the
+//! browser already has with a `304 Not Modified`.
+
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::{header, Method, StatusCode},
+    middleware::DefaultHeaders,
+    HttpResponse,
+};
+
+/// For `/pkg`, whose files have their hash in their name when `hash-files` is on, so a changed file
+/// is a new url. Without hashing they're revalidated every time instead, which is cheap with their ETags.
+pub fn pkg_headers(hash_files: bool) -> DefaultHeaders {
+    DefaultHeaders::new().add((
+        header::CACHE_CONTROL,
+        if hash_files {
+            "public, max-age=31536000, immutable"
+        } else {
+            "no-cache"
+        },
+    ))
+}
+
+/// For `/assets`, whose names don't change when they do, so they're only kept for an hour.
+pub fn assets_headers() -> DefaultHeaders {
+    DefaultHeaders::new().add((header::CACHE_CONTROL, "public, max-age=3600"))
+}
+
+/// Weak, because compression changes the bytes but not what they mean.
+pub fn etag(bytes: &[u8]) -> String {
+    use std::hash::{DefaultHasher, Hasher};
+
+    let mut hasher = DefaultHasher::new();
+    hasher.write(bytes);
+    format!("W/\"{:016x}\"", hasher.finish())
+}
+
+/// Swaps a successful `GET` response for an empty `304` when it has the ETag the browser sent in
+/// `If-None-Match`. The response is still made, it just isn't sent again.
+pub fn not_modified<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl std::future::Future<Output = Result<ServiceResponse<BoxBody>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody + 'static,
+{
+    let if_none_match = (req.method() == Method::GET)
+        .then(|| req.headers().get(header::IF_NONE_MATCH).cloned())
+        .flatten();
+    let response = srv.call(req);
+    async move {
+        let response = response.await?;
+        let matches = if_none_match.is_some_and(|if_none_match| {
+            response.status() == StatusCode::OK
+                && response
+                    .headers()
+                    .get(header::ETAG)
+                    .and_then(|etag| etag.to_str().ok())
+                    .zip(if_none_match.to_str().ok())
+                    .is_some_and(|(etag, if_none_match)| etag_matches(etag, if_none_match))
+        });
+        if !matches {
+            return Ok(response.map_into_boxed_body());
+        }
+        let mut not_modified = HttpResponse::NotModified();
+        for name in [header::ETAG, header::CACHE_CONTROL, header::VARY] {
+            if let Some(value) = response.headers().get(&name) {
+                not_modified.insert_header((name, value.clone()));
+            }
+        }
+        let (req, _) = response.into_parts();
+        Ok(ServiceResponse::new(req, not_modified.finish()))
+    }
+}
+
+/// `If-None-Match` compares weakly, and can list several ETags, or be `*`.
+fn etag_matches(etag: &str, if_none_match: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{etag, etag_matches};
+
+    #[test]
+    fn weak_and_strong_etags_match_each_other() {
+        assert!(etag_matches(r#"W/"abc""#, r#"W/"abc""#));
+        assert!(etag_matches(r#"W/"abc""#, r#""abc""#));
+        assert!(etag_matches(r#""abc""#, r#"W/"abc""#));
+        assert!(!etag_matches(r#"W/"abc""#, r#"W/"abd""#));
+    }
+
+    #[test]
+    fn any_etag_in_a_list_matches() {
+        assert!(etag_matches(r#"W/"abc""#, r#""xyz", W/"abc""#));
+        assert!(etag_matches(r#"W/"abc""#, r#""xyz",W/"abc" , "123""#));
+        assert!(!etag_matches(r#"W/"abc""#, r#""xyz", "123""#));
+    }
+
+    #[test]
+    fn a_star_matches_anything() {
+        assert!(etag_matches(r#"W/"abc""#, "*"));
+        assert!(etag_matches(r#""abc""#, " * "));
+    }
+
+    #[test]
+    fn etags_follow_the_bytes() {
+        assert_eq!(etag(b"notes"), etag(b"notes"));
+        assert_ne!(etag(b"notes"), etag(b"other notes"));
+        assert!(etag_matches(&etag(b"notes"), &etag(b"notes")));
+    }
+}
@@ -0,0 +1,400 @@
+use leptos::prelude::*;
+use yrs::{
+    updates::{decoder::Decode, encoder::Encode},
+    Assoc, Doc, GetString, IndexedSequence, OffsetKind, Options, ReadTxn, StateVector, StickyIndex,
+    Text, Transact, Update,
+};
+
+use crate::error::AppError;
+
+// while a cell is being edited, its text is a yrs document rather than a plain string.
+// editors exchange updates to the document over the sync websocket, and the server merges
+// them into `texts.crdt_state`, so concurrent edits to the same cell converge instead of
+// overwriting each other. `texts.text` is kept equal to the document's contents.
+
+const TEXT_NAME: &str = "text";
+
+/// Offsets are counted in UTF-16 code units, to match the browser's textarea selection.
+pub fn new_doc() -> Doc {
+    Doc::with_options(Options {
+        offset_kind: OffsetKind::Utf16,
+        ..Options::default()
+    })
+}
+
+pub fn doc_from_state(state: &[u8]) -> Result<Doc, String> {
+    let doc = new_doc();
+    apply_update(&doc, state)?;
+    Ok(doc)
+}
+
+pub fn doc_text(doc: &Doc) -> String {
+    let text = doc.get_or_insert_text(TEXT_NAME);
+    let txn = doc.transact();
+    text.get_string(&txn)
+}
+
+pub fn doc_state(doc: &Doc) -> Vec<u8> {
+    doc.transact()
+        .encode_state_as_update_v1(&StateVector::default())
+}
+
+pub fn apply_update(doc: &Doc, update: &[u8]) -> Result<(), String> {
+    let update = Update::decode_v1(update).map_err(|e| e.to_string())?;
+    doc.transact_mut()
+        .apply_update(update)
+        .map_err(|e| e.to_string())
+}
+
+/// Edits the document so its text becomes `new_text`, returning the update to send to everyone else.
+/// The edit is a single splice between whatever the old and new text have in common at either end,
+/// so concurrent edits elsewhere in the text are left alone.
+pub fn splice_to(doc: &Doc, new_text: &str) -> Vec<u8> {
+    let text = doc.get_or_insert_text(TEXT_NAME);
+    let mut txn = doc.transact_mut();
+    let old: Vec<u16> = text.get_string(&txn).encode_utf16().collect();
+    let new: Vec<u16> = new_text.encode_utf16().collect();
+
+    let is_high_surrogate = |unit: u16| (0xD800..=0xDBFF).contains(&unit);
+    let is_low_surrogate = |unit: u16| (0xDC00..=0xDFFF).contains(&unit);
+    let mut prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    // never split a surrogate pair
+    if prefix > 0 && is_high_surrogate(old[prefix - 1]) {
+        prefix -= 1;
+    }
+    let mut suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    if suffix > 0 && is_low_surrogate(new[new.len() - suffix]) {
+        suffix -= 1;
+    }
+
+    let removed = old.len() - prefix - suffix;
+    if removed > 0 {
+        text.remove_range(&mut txn, prefix as u32, removed as u32);
+    }
+    let inserted = String::from_utf16_lossy(&new[prefix..new.len() - suffix]);
+    if !inserted.is_empty() {
+        text.insert(&mut txn, prefix as u32, &inserted);
+    }
+    txn.encode_update_v1()
+}
+
+/// A position in the text which stays put as other people edit around it.
+pub fn cursor_at(doc: &Doc, offset: u32) -> Option<Vec<u8>> {
+    let text = doc.get_or_insert_text(TEXT_NAME);
+    let mut txn = doc.transact_mut();
+    text.sticky_index(&mut txn, offset, Assoc::After)
+        .map(|index| index.encode_v1())
+}
+
+pub fn cursor_offset(doc: &Doc, cursor: &[u8]) -> Option<u32> {
+    let index = StickyIndex::decode_v1(cursor).ok()?;
+    let txn = doc.transact();
+    index.get_offset(&txn).map(|offset| offset.index)
+}
+
+#[cfg(feature = "ssr")]
+pub use server::*;
+
+#[cfg(feature = "ssr")]
+mod server {
+    use super::*;
+    use crate::notebook::TextFile;
+
+    #[derive(Debug)]
+    pub enum CellError {
+        Database(sqlx::Error),
+        InvalidUpdate(String),
+        /// The server can't read an encrypted notebook's texts, so can't merge edits to them.
+        Encrypted,
+        /// The text isn't what the edit was made to any more, see [`save_text`].
+        Stale,
+    }
+    impl std::fmt::Display for CellError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Self::Database(e) => write!(f, "{e}"),
+                Self::InvalidUpdate(e) => write!(f, "invalid cell update: {e}"),
+                Self::Encrypted => write!(f, "encrypted cells can't be edited collaboratively"),
+                Self::Stale => write!(f, "the text has been changed by someone else since"),
+            }
+        }
+    }
+    impl From<sqlx::Error> for CellError {
+        fn from(e: sqlx::Error) -> Self {
+            Self::Database(e)
+        }
+    }
+
+    /// Runs `edit` against a text's document with the row locked, then stores the result.
+    /// Texts which have never been edited collaboratively get a document made from their current text.
+    /// Returns `None` if the text doesn't exist in that notebook.
+    async fn with_doc<R>(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        notebook_id: i32,
+        text_id: i32,
+        edit: impl FnOnce(&Doc) -> Result<R, CellError>,
+    ) -> Result<Option<(TextFile, Vec<u8>, R)>, CellError> {
+        let mut transaction = pool.begin().await?;
+        // sharing the notebook's row waits for encryption to be turned on, if it's happening now
+        let row: Option<(String, Option<Vec<u8>>, bool)> = sqlx::query_as(
+            "SELECT texts.text, texts.crdt_state, notebooks.encryption_salt IS NOT NULL
+            FROM texts JOIN notebooks ON notebooks.id = texts.notebook_id
+            WHERE texts.id = $1 AND texts.notebook_id = $2
+            FOR UPDATE OF texts FOR SHARE OF notebooks",
+        )
+        .bind(text_id)
+        .bind(notebook_id)
+        .fetch_optional(&mut *transaction)
+        .await?;
+        let Some((text, state, encrypted)) = row else {
+            return Ok(None);
+        };
+        if encrypted {
+            return Err(CellError::Encrypted);
+        }
+        let (new_text, new_state, result) = {
+            let doc = match state {
+                Some(state) => doc_from_state(&state).map_err(CellError::InvalidUpdate)?,
+                None => {
+                    let doc = new_doc();
+                    splice_to(&doc, &text);
+                    doc
+                }
+            };
+            let result = edit(&doc)?;
+            (doc_text(&doc), doc_state(&doc), result)
+        };
+        sqlx::query("UPDATE texts SET text = $2, crdt_state = $3 WHERE id = $1")
+            .bind(text_id)
+            .bind(&new_text)
+            .bind(&new_state)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(Some((TextFile::new(text_id, new_text), new_state, result)))
+    }
+
+    /// The whole document, for an editor to start from.
+    pub async fn cell_state(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        notebook_id: i32,
+        text_id: i32,
+    ) -> Result<Option<Vec<u8>>, CellError> {
+        Ok(with_doc(pool, notebook_id, text_id, |_| Ok(()))
+            .await?
+            .map(|(_, state, _)| state))
+    }
+
+    /// Merges an editor's update, returning the text as it now stands.
+    pub async fn merge_update(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        notebook_id: i32,
+        text_id: i32,
+        update: &[u8],
+    ) -> Result<Option<TextFile>, CellError> {
+        Ok(with_doc(pool, notebook_id, text_id, |doc| {
+            apply_update(doc, update).map_err(CellError::InvalidUpdate)
+        })
+        .await?
+        .map(|(text, _, ())| text))
+    }
+
+    /// Replaces a text wholesale, for writes which don't come from a collaborative editor,
+    /// returning the update which editors need to apply to keep up, if it changed.
+    /// Encrypted texts have no document, and so no update.
+    pub async fn replace_text(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        notebook_id: i32,
+        text_id: i32,
+        new_text: &str,
+    ) -> Result<Option<(TextFile, Option<Vec<u8>>)>, CellError> {
+        write_text(pool, notebook_id, text_id, None, new_text).await
+    }
+
+    /// Like [`replace_text`], but only if the text is still `base`, which it was when it was edited,
+    /// so a device saving an edit can't undo anything other people have done to the text since.
+    pub async fn save_text(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        notebook_id: i32,
+        text_id: i32,
+        base: &str,
+        new_text: &str,
+    ) -> Result<Option<(TextFile, Option<Vec<u8>>)>, CellError> {
+        write_text(pool, notebook_id, text_id, Some(base), new_text).await
+    }
+
+    async fn write_text(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        notebook_id: i32,
+        text_id: i32,
+        base: Option<&str>,
+        new_text: &str,
+    ) -> Result<Option<(TextFile, Option<Vec<u8>>)>, CellError> {
+        // a text which is already what it's being changed to doesn't conflict with anything
+        let is_stale =
+            |current: &str| base.is_some_and(|base| current != base && current != new_text);
+        match with_doc(pool, notebook_id, text_id, |doc| {
+            let current = doc_text(doc);
+            if is_stale(&current) {
+                Err(CellError::Stale)
+            } else {
+                Ok((current != new_text).then(|| splice_to(doc, new_text)))
+            }
+        })
+        .await
+        {
+            Ok(result) => Ok(result.map(|(text, _, update)| (text, update))),
+            // encryption can't be turned off again, so this can only be let through if it's ciphertext
+            Err(CellError::Encrypted) if !crate::encryption::is_opaque(new_text) => {
+                Err(CellError::Encrypted)
+            }
+            Err(CellError::Encrypted) => {
+                let mut transaction = pool.begin().await?;
+                let current: Option<(String,)> = sqlx::query_as(
+                    "SELECT text FROM texts WHERE id = $1 AND notebook_id = $2 FOR UPDATE",
+                )
+                .bind(text_id)
+                .bind(notebook_id)
+                .fetch_optional(&mut *transaction)
+                .await?;
+                let Some((current,)) = current else {
+                    return Ok(None);
+                };
+                if is_stale(&current) {
+                    return Err(CellError::Stale);
+                }
+                sqlx::query("UPDATE texts SET text = $2, crdt_state = NULL WHERE id = $1")
+                    .bind(text_id)
+                    .bind(new_text)
+                    .execute(&mut *transaction)
+                    .await?;
+                transaction.commit().await?;
+                Ok(Some((TextFile::new(text_id, new_text.to_string()), None)))
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all, fields(notebook_id = notebook_id))]
+pub async fn get_cell_state(
+    notebook_id: i32,
+    text_id: i32,
+) -> Result<Vec<u8>, ServerFnError<AppError>> {
+    let caller: crate::auth::Caller = leptos_actix::extract().await.map_err(AppError::from)?;
+    if !caller.can_write(notebook_id) {
+        return Err(
+            AppError::Unauthorized("You don't have access to that notebook!".to_string()).into(),
+        );
+    }
+    cell_state(
+        &crate::app::get_pool_from_context().await?,
+        notebook_id,
+        text_id,
+    )
+    .await
+    .map_err(AppError::from)?
+    .ok_or_else(|| AppError::NotFound(format!("Couldn't find a text with id {text_id}!")).into())
+}
+
+/// Draws other people's cursors over a cell's textarea, by mirroring its text with markers in it.
+#[component]
+pub fn RemoteCursors(text: RwSignal<String>, offsets: Signal<Vec<u32>>) -> impl IntoView {
+    let segments = move || {
+        let text = text.get();
+        let units: Vec<u16> = text.encode_utf16().collect();
+        let mut offsets = offsets.get();
+        offsets.sort_unstable();
+        let mut segments = Vec::new();
+        let mut last = 0;
+        for offset in offsets {
+            let offset = (offset as usize).min(units.len());
+            segments.push(String::from_utf16_lossy(&units[last..offset]));
+            last = offset;
+        }
+        segments.push(String::from_utf16_lossy(&units[last..]));
+        segments
+    };
+    view! {
+        <div class="remote-cursors" aria-hidden="true">
+            {move || {
+                let segments = segments();
+                let count = segments.len();
+                segments
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, segment)| view! {
+                        <span> {segment} </span>
+                        {(i + 1 < count).then(|| view! { <span class="remote-cursor"></span> })}
+                    })
+                    .collect_view()
+            }}
+        </div>
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_update, doc_from_state, doc_state, doc_text, new_doc, splice_to, Doc};
+
+    fn doc_with(text: &str) -> Doc {
+        let doc = new_doc();
+        splice_to(&doc, text);
+        doc
+    }
+
+    /// Splices `doc` to `new_text`, and checks a copy of it ends up the same from just the update.
+    fn splice_and_check(old_text: &str, new_text: &str) {
+        let doc = doc_with(old_text);
+        let copy = doc_from_state(&doc_state(&doc)).unwrap();
+        let update = splice_to(&doc, new_text);
+        assert_eq!(doc_text(&doc), new_text);
+        apply_update(&copy, &update).unwrap();
+        assert_eq!(doc_text(&copy), new_text);
+    }
+
+    #[test]
+    fn emoji_at_the_start_of_a_splice_stay_whole() {
+        // 😀 and 😁 share their high surrogate, so the common prefix ends inside the pair
+        splice_and_check("a😀b", "a😁b");
+        splice_and_check("😀 tea", "😁 tea");
+        splice_and_check("tea", "😀tea");
+    }
+
+    #[test]
+    fn emoji_at_the_end_of_a_splice_stay_whole() {
+        // 😀 and U+FE600 share their low surrogate, so the common suffix starts inside the pair
+        splice_and_check("tea 😀", "tea \u{FE600}");
+        splice_and_check("tea", "tea😀");
+        splice_and_check("tea😀", "tea");
+    }
+
+    #[test]
+    fn splicing_to_the_same_text_changes_nothing() {
+        let doc = doc_with("milk 🥛");
+        let copy = doc_from_state(&doc_state(&doc)).unwrap();
+        let before = doc_state(&copy);
+        let update = splice_to(&doc, "milk 🥛");
+        assert_eq!(doc_text(&doc), "milk 🥛");
+        apply_update(&copy, &update).unwrap();
+        assert_eq!(doc_state(&copy), before);
+    }
+
+    #[test]
+    fn concurrent_splices_to_the_same_text_both_survive() {
+        let ours = doc_with("buy milk");
+        let theirs = doc_from_state(&doc_state(&ours)).unwrap();
+        let our_update = splice_to(&ours, "buy oat milk");
+        let their_update = splice_to(&theirs, "buy milk and eggs");
+        apply_update(&ours, &their_update).unwrap();
+        apply_update(&theirs, &our_update).unwrap();
+        assert_eq!(doc_text(&ours), "buy oat milk and eggs");
+        assert_eq!(doc_text(&theirs), "buy oat milk and eggs");
+    }
+}
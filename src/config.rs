@@ -0,0 +1,624 @@
+//! The server's settings. Each one has a default, which is overridden by the config file,
+//! then by environment variables, then by command line flags.
+//!
+//! The config file is `note-to-self.toml` in the working directory if there is one,
+//! or wherever `--config` or `NOTE_TO_SELF_CONFIG` points.
+
+use std::{fmt, path::PathBuf, str::FromStr};
+
+use actix_web::cookie::SameSite;
+use serde::Deserialize;
+
+const DEFAULT_CONFIG_FILE: &str = "note-to-self.toml";
+/// actix's cookie signing needs at least this much key.
+const MIN_SECRET_KEY_BYTES: usize = 64;
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub database: DatabaseConfig,
+    pub sessions: SessionConfig,
+    pub cookies: CookieConfig,
+    pub uploads: UploadConfig,
+    pub retention: RetentionConfig,
+    pub features: FeatureConfig,
+    pub logging: LoggingConfig,
+    pub metrics: MetricsConfig,
+    pub shutdown: ShutdownConfig,
+    pub jobs: JobsConfig,
+    pub security: SecurityConfig,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// `DATABASE_URL`. Has to be set one way or another.
+    pub url: Option<String>,
+    /// `DATABASE_MAX_CONNECTIONS`
+    pub max_connections: u32,
+}
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            max_connections: 5,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// `SECRET_KEY_SESSION_MIDDLEWARE`, which signs session cookies. Has to be set one way or another.
+    pub secret_key: Option<String>,
+    /// `SESSION_IDLE_TIMEOUT_SECS`: how long a session lasts without being used.
+    pub idle_timeout_secs: i64,
+    /// `SESSION_ABSOLUTE_TIMEOUT_SECS`: how long a session lasts after logging in, however much it's used.
+    pub absolute_timeout_secs: i64,
+}
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            secret_key: None,
+            idle_timeout_secs: 7 * 24 * 60 * 60,
+            absolute_timeout_secs: 30 * 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CookieConfig {
+    /// `COOKIE_SECURE`: only send the session cookie over https. Browsers make an exception for localhost.
+    pub secure: bool,
+    /// `COOKIE_SAME_SITE`: `strict`, `lax` or `none`.
+    pub same_site: SameSiteConfig,
+}
+impl Default for CookieConfig {
+    fn default() -> Self {
+        Self {
+            secure: true,
+            same_site: SameSiteConfig::Lax,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSiteConfig {
+    Strict,
+    Lax,
+    None,
+}
+impl SameSiteConfig {
+    pub fn same_site(self) -> SameSite {
+        match self {
+            Self::Strict => SameSite::Strict,
+            Self::Lax => SameSite::Lax,
+            Self::None => SameSite::None,
+        }
+    }
+}
+impl FromStr for SameSiteConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "strict" => Ok(Self::Strict),
+            "lax" => Ok(Self::Lax),
+            "none" => Ok(Self::None),
+            _ => Err("expected `strict`, `lax` or `none`".to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadConfig {
+    /// `MAX_IMPORT_BYTES`: the biggest file that can be imported.
+    pub max_import_bytes: usize,
+}
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            max_import_bytes: 20 * 1024 * 1024,
+        }
+    }
+}
+
+/// How long things that are no use any more are kept before they're cleaned up.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    /// `RETENTION_EXPIRED_SESSIONS_DAYS`
+    pub expired_sessions_days: i64,
+    /// `RETENTION_LOGIN_THROTTLES_DAYS`: since the last failed login.
+    pub login_throttles_days: i64,
+    /// `RETENTION_EXPIRED_SHARE_LINKS_DAYS`
+    pub expired_share_links_days: i64,
+}
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            expired_sessions_days: 7,
+            login_throttles_days: 1,
+            expired_share_links_days: 30,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeatureConfig {
+    /// `FEATURE_NEW_ACCOUNTS`: whether anyone can create an account.
+    pub new_accounts: bool,
+    /// `FEATURE_IMPORTS`
+    pub imports: bool,
+    /// `FEATURE_SHARE_LINKS`: turning them off also stops existing links from opening.
+    pub share_links: bool,
+}
+impl Default for FeatureConfig {
+    fn default() -> Self {
+        Self {
+            new_accounts: true,
+            imports: true,
+            share_links: true,
+        }
+    }
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// `LOG_FORMAT`: `pretty` or `json`.
+    pub format: LogFormat,
+    /// `LOG_NOTE_CONTENTS`: log what notes say, which is only meant for debugging locally.
+    pub note_contents: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Pretty,
+    Json,
+}
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "pretty" => Ok(Self::Pretty),
+            "json" => Ok(Self::Json),
+            _ => Err("expected `pretty` or `json`".to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// `METRICS_ENABLED`
+    pub enabled: bool,
+    /// `METRICS_TOKEN`: if it's set, scrapers have to send it as `Authorization: Bearer <token>`.
+    pub token: Option<String>,
+}
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            token: None,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// `SHUTDOWN_TIMEOUT_SECS`: how long requests in progress get to finish when the server is stopped.
+    /// Fly's `kill_timeout` has to be longer, or the machine is killed first.
+    pub timeout_secs: u64,
+}
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self { timeout_secs: 30 }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobsConfig {
+    /// `JOBS_WORKERS`: how many jobs this server runs at once. 0 leaves them to other servers.
+    pub workers: usize,
+    /// `JOBS_VISIBILITY_TIMEOUT_SECS`: how long a job can run before it's assumed its worker died, and
+    /// another one takes it. It has to be longer than any job takes.
+    pub visibility_timeout_secs: u64,
+    /// `JOBS_MAX_ATTEMPTS`: how many times a job is tried before it's left for an admin to look at.
+    pub max_attempts: u32,
+    /// `JOBS_PURGE_TRASH_EVERY_SECS`: how often to run `purge-trash` in the background. 0 turns it off.
+    pub purge_trash_every_secs: u64,
+}
+impl Default for JobsConfig {
+    fn default() -> Self {
+        Self {
+            workers: 2,
+            visibility_timeout_secs: 5 * 60,
+            max_attempts: 5,
+            purge_trash_every_secs: 60 * 60,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityConfig {
+    /// `SECURITY_HSTS_MAX_AGE_SECS`: how long browsers should only use https for the site. 0 leaves the
+    /// header out, for servers which aren't behind https.
+    pub hsts_max_age_secs: u64,
+    /// `SECURITY_ALLOWED_ORIGINS`, comma separated: other sites, like `https://notes.example.com`, which
+    /// can make requests that change things. The server's own site always can.
+    pub allowed_origins: Vec<String>,
+    /// `SECURITY_TRUSTED_PROXIES`: how many proxies in front of the server add to `X-Forwarded-For`, which
+    /// is where client addresses for login lockouts are found. 0 uses the connection's own address.
+    pub trusted_proxies: usize,
+}
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        Self {
+            hsts_max_age_secs: 365 * 24 * 60 * 60,
+            allowed_origins: Vec::new(),
+            trusted_proxies: 0,
+        }
+    }
+}
+
+/// Flags which override the config file and the environment.
+#[derive(clap::Args, Debug, Default)]
+pub struct ConfigArgs {
+    /// The config file to read, instead of `note-to-self.toml`
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    #[arg(long, global = true)]
+    pub database_url: Option<String>,
+    #[arg(long, global = true)]
+    pub max_connections: Option<u32>,
+    /// `pretty` or `json`
+    #[arg(long, global = true)]
+    pub log_format: Option<LogFormat>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    /// An environment variable that's set to something that doesn't make sense.
+    Env(&'static str, String),
+    Invalid(String),
+}
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read(path, e) => write!(f, "couldn't read {}: {e}", path.display()),
+            Self::Parse(path, e) => write!(f, "couldn't understand {}: {e}", path.display()),
+            Self::Env(name, e) => write!(f, "{name} is invalid: {e}"),
+            Self::Invalid(e) => write!(f, "{e}"),
+        }
+    }
+}
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    pub fn load(args: &ConfigArgs) -> Result<Self, ConfigError> {
+        let mut config = Self::from_file(args)?;
+        config.apply_env()?;
+        config.apply_args(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The defaults, if there's no config file.
+    fn from_file(args: &ConfigArgs) -> Result<Self, ConfigError> {
+        let path = match args
+            .config
+            .clone()
+            .or_else(|| std::env::var_os("NOTE_TO_SELF_CONFIG").map(PathBuf::from))
+        {
+            Some(path) => path,
+            None if std::path::Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                PathBuf::from(DEFAULT_CONFIG_FILE)
+            }
+            None => return Ok(Self::default()),
+        };
+        let text =
+            std::fs::read_to_string(&path).map_err(|e| ConfigError::Read(path.clone(), e))?;
+        toml::from_str(&text).map_err(|e| ConfigError::Parse(path, e))
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        fn string(name: &'static str, field: &mut Option<String>) {
+            if let Ok(value) = std::env::var(name) {
+                *field = Some(value);
+            }
+        }
+        fn parsed<T: FromStr>(name: &'static str, field: &mut T) -> Result<(), ConfigError>
+        where
+            T::Err: fmt::Display,
+        {
+            if let Ok(value) = std::env::var(name) {
+                *field = value
+                    .parse()
+                    .map_err(|e: T::Err| ConfigError::Env(name, e.to_string()))?;
+            }
+            Ok(())
+        }
+
+        string("DATABASE_URL", &mut self.database.url);
+        parsed(
+            "DATABASE_MAX_CONNECTIONS",
+            &mut self.database.max_connections,
+        )?;
+        string(
+            "SECRET_KEY_SESSION_MIDDLEWARE",
+            &mut self.sessions.secret_key,
+        );
+        parsed(
+            "SESSION_IDLE_TIMEOUT_SECS",
+            &mut self.sessions.idle_timeout_secs,
+        )?;
+        parsed(
+            "SESSION_ABSOLUTE_TIMEOUT_SECS",
+            &mut self.sessions.absolute_timeout_secs,
+        )?;
+        parsed("COOKIE_SECURE", &mut self.cookies.secure)?;
+        parsed("COOKIE_SAME_SITE", &mut self.cookies.same_site)?;
+        parsed("MAX_IMPORT_BYTES", &mut self.uploads.max_import_bytes)?;
+        parsed(
+            "RETENTION_EXPIRED_SESSIONS_DAYS",
+            &mut self.retention.expired_sessions_days,
+        )?;
+        parsed(
+            "RETENTION_LOGIN_THROTTLES_DAYS",
+            &mut self.retention.login_throttles_days,
+        )?;
+        parsed(
+            "RETENTION_EXPIRED_SHARE_LINKS_DAYS",
+            &mut self.retention.expired_share_links_days,
+        )?;
+        parsed("FEATURE_NEW_ACCOUNTS", &mut self.features.new_accounts)?;
+        parsed("FEATURE_IMPORTS", &mut self.features.imports)?;
+        parsed("FEATURE_SHARE_LINKS", &mut self.features.share_links)?;
+        parsed("LOG_FORMAT", &mut self.logging.format)?;
+        parsed("LOG_NOTE_CONTENTS", &mut self.logging.note_contents)?;
+        parsed("METRICS_ENABLED", &mut self.metrics.enabled)?;
+        string("METRICS_TOKEN", &mut self.metrics.token);
+        parsed("SHUTDOWN_TIMEOUT_SECS", &mut self.shutdown.timeout_secs)?;
+        parsed("JOBS_WORKERS", &mut self.jobs.workers)?;
+        parsed(
+            "JOBS_VISIBILITY_TIMEOUT_SECS",
+            &mut self.jobs.visibility_timeout_secs,
+        )?;
+        parsed("JOBS_MAX_ATTEMPTS", &mut self.jobs.max_attempts)?;
+        parsed(
+            "JOBS_PURGE_TRASH_EVERY_SECS",
+            &mut self.jobs.purge_trash_every_secs,
+        )?;
+        parsed(
+            "SECURITY_HSTS_MAX_AGE_SECS",
+            &mut self.security.hsts_max_age_secs,
+        )?;
+        parsed(
+            "SECURITY_TRUSTED_PROXIES",
+            &mut self.security.trusted_proxies,
+        )?;
+        if let Ok(origins) = std::env::var("SECURITY_ALLOWED_ORIGINS") {
+            self.security.allowed_origins = origins
+                .split(',')
+                .map(|origin| origin.trim().to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect();
+        }
+        Ok(())
+    }
+
+    fn apply_args(&mut self, args: &ConfigArgs) {
+        if let Some(url) = &args.database_url {
+            self.database.url = Some(url.clone());
+        }
+        if let Some(max_connections) = args.max_connections {
+            self.database.max_connections = max_connections;
+        }
+        if let Some(format) = args.log_format {
+            self.logging.format = format;
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |e: &str| Err(ConfigError::Invalid(e.to_string()));
+        if self.database.url.as_deref().unwrap_or_default().is_empty() {
+            return invalid(
+                "there's no database url: set DATABASE_URL, `database.url` in the config file, or --database-url",
+            );
+        }
+        if self.database.max_connections == 0 {
+            return invalid("the database pool needs at least one connection");
+        }
+        match &self.sessions.secret_key {
+            None => {
+                return invalid(
+                    "there's no session key: set SECRET_KEY_SESSION_MIDDLEWARE or `sessions.secret_key` in the config file",
+                )
+            }
+            Some(key) if key.len() < MIN_SECRET_KEY_BYTES => {
+                return Err(ConfigError::Invalid(format!(
+                    "the session key has to be at least {MIN_SECRET_KEY_BYTES} bytes long"
+                )))
+            }
+            Some(_) => (),
+        }
+        if self.sessions.idle_timeout_secs <= 0
+            || self.sessions.idle_timeout_secs > self.sessions.absolute_timeout_secs
+        {
+            return invalid(
+                "the session idle timeout has to be positive, and no longer than the absolute timeout",
+            );
+        }
+        if matches!(self.cookies.same_site, SameSiteConfig::None) && !self.cookies.secure {
+            return invalid("browsers only accept `same_site = \"none\"` cookies which are secure");
+        }
+        if self.uploads.max_import_bytes == 0 {
+            return invalid("`max_import_bytes` has to be more than 0");
+        }
+        if self.retention.expired_sessions_days < 0
+            || self.retention.login_throttles_days < 0
+            || self.retention.expired_share_links_days < 0
+        {
+            return invalid("retention periods can't be negative");
+        }
+        if let Some(origin) = self
+            .security
+            .allowed_origins
+            .iter()
+            .find(|origin| !origin.contains("://") || origin.ends_with('/'))
+        {
+            return Err(ConfigError::Invalid(format!(
+                "allowed origins are a scheme and a host, like `https://notes.example.com`, not `{origin}`"
+            )));
+        }
+        if self.jobs.visibility_timeout_secs == 0 || self.jobs.max_attempts == 0 {
+            return invalid("jobs need a visibility timeout, and at least one attempt");
+        }
+        Ok(())
+    }
+
+    pub fn database_url(&self) -> &str {
+        self.database
+            .url
+            .as_deref()
+            .expect("the config should have been validated")
+    }
+
+    pub fn session_key(&self) -> actix_web::cookie::Key {
+        actix_web::cookie::Key::from(
+            self.sessions
+                .secret_key
+                .as_deref()
+                .expect("the config should have been validated")
+                .as_bytes(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn valid() -> Config {
+        let mut config = Config::default();
+        config.database.url = Some("postgres://localhost/note_to_self".to_string());
+        config.sessions.secret_key = Some("k".repeat(MIN_SECRET_KEY_BYTES));
+        config
+    }
+
+    fn assert_rejected(config: Config, reason: &str) {
+        match config.validate() {
+            Err(ConfigError::Invalid(e)) => assert!(e.contains(reason), "unexpected error: {e}"),
+            Err(e) => panic!("unexpected error: {e}"),
+            Ok(()) => panic!("should have been rejected for {reason:?}"),
+        }
+    }
+
+    #[test]
+    fn the_defaults_are_valid_with_a_database_and_a_key() {
+        assert!(valid().validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_a_missing_database_url() {
+        let mut config = valid();
+        config.database.url = None;
+        assert_rejected(config, "no database url");
+        let mut config = valid();
+        config.database.url = Some(String::new());
+        assert_rejected(config, "no database url");
+    }
+
+    #[test]
+    fn rejects_a_pool_without_connections() {
+        let mut config = valid();
+        config.database.max_connections = 0;
+        assert_rejected(config, "at least one connection");
+    }
+
+    #[test]
+    fn rejects_a_missing_or_short_session_key() {
+        let mut config = valid();
+        config.sessions.secret_key = None;
+        assert_rejected(config, "no session key");
+        let mut config = valid();
+        config.sessions.secret_key = Some("k".repeat(MIN_SECRET_KEY_BYTES - 1));
+        assert_rejected(config, "at least 64 bytes");
+    }
+
+    #[test]
+    fn rejects_session_timeouts_that_dont_make_sense() {
+        let mut config = valid();
+        config.sessions.idle_timeout_secs = 0;
+        assert_rejected(config, "idle timeout");
+        let mut config = valid();
+        config.sessions.idle_timeout_secs = config.sessions.absolute_timeout_secs + 1;
+        assert_rejected(config, "idle timeout");
+    }
+
+    #[test]
+    fn rejects_same_site_none_cookies_which_arent_secure() {
+        let mut config = valid();
+        config.cookies.same_site = SameSiteConfig::None;
+        config.cookies.secure = false;
+        assert_rejected(config, "same_site");
+        let mut config = valid();
+        config.cookies.same_site = SameSiteConfig::None;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_an_empty_import_limit() {
+        let mut config = valid();
+        config.uploads.max_import_bytes = 0;
+        assert_rejected(config, "max_import_bytes");
+    }
+
+    #[test]
+    fn rejects_negative_retention_periods() {
+        for set in [
+            |retention: &mut RetentionConfig| retention.expired_sessions_days = -1,
+            |retention: &mut RetentionConfig| retention.login_throttles_days = -1,
+            |retention: &mut RetentionConfig| retention.expired_share_links_days = -1,
+        ] {
+            let mut config = valid();
+            set(&mut config.retention);
+            assert_rejected(config, "can't be negative");
+        }
+    }
+
+    #[test]
+    fn rejects_allowed_origins_which_arent_origins() {
+        for origin in ["notes.example.com", "https://notes.example.com/"] {
+            let mut config = valid();
+            config.security.allowed_origins = vec![origin.to_string()];
+            assert_rejected(config, "allowed origins");
+        }
+        let mut config = valid();
+        config.security.allowed_origins = vec!["https://notes.example.com".to_string()];
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_jobs_which_could_never_run() {
+        let mut config = valid();
+        config.jobs.visibility_timeout_secs = 0;
+        assert_rejected(config, "visibility timeout");
+        let mut config = valid();
+        config.jobs.max_attempts = 0;
+        assert_rejected(config, "at least one attempt");
+    }
+}
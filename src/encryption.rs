@@ -0,0 +1,538 @@
+use std::collections::HashMap;
+
+use js_sys::{Array, Uint8Array};
+use leptos::{either::Either, logging::log, prelude::*, tachys::dom::window, task::spawn_local};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{AesGcmParams, AesKeyGenParams, CryptoKey, Pbkdf2Params, SubtleCrypto};
+
+use crate::{
+    error::{error_message, AppError},
+    notebook::{Notebook, TextFile},
+    sync::{SyncConnection, SyncEvent},
+};
+
+// opt-in end-to-end encryption. an encrypted notebook's texts are encrypted in the browser with AES-GCM,
+// using a key derived from a passphrase with PBKDF2, so the server only ever stores ciphertext.
+// the passphrase and the key never leave the browser, and there's no way to get them back if they're lost.
+// the notebook's name, and the ids and order of its texts, stay readable so logging in and syncing still work.
+// merging concurrent edits needs the server to read the text, so encrypted cells are saved whole instead.
+
+const PREFIX: &str = "enc:v1:";
+const PBKDF2_ITERATIONS: u32 = 600_000;
+const SALT_LEN: usize = 16;
+const IV_LEN: usize = 12;
+/// Encrypted when encryption is turned on, so a wrong passphrase can be told apart from a right one.
+const CHECK_TEXT: &str = "note-to-self";
+const MIN_PASSPHRASE_LENGTH: usize = 12;
+
+/// What a browser needs, along with the passphrase, to get an encrypted notebook's key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncryptionParams {
+    salt: Vec<u8>,
+    /// `CHECK_TEXT`, encrypted with the key.
+    check: String,
+}
+
+/// The ciphertext the server has for each text, along with what it decrypts to, so a text which hasn't
+/// changed is saved as it was, rather than encrypted again with a fresh IV every time anything is saved.
+#[derive(Debug, Clone, Default)]
+pub struct Ciphertexts(HashMap<i32, (String, String)>);
+impl Ciphertexts {
+    /// Remembers every text in a notebook, encrypted and decrypted.
+    pub fn remember(&mut self, encrypted: &Notebook, decrypted: &Notebook) {
+        for (encrypted, decrypted) in encrypted.texts().zip(decrypted.texts()) {
+            self.remember_text(encrypted, decrypted);
+        }
+    }
+
+    pub fn remember_text(&mut self, encrypted: &TextFile, decrypted: &TextFile) {
+        if encrypted.id() == decrypted.id() && is_ciphertext(encrypted.text()) {
+            self.0.insert(
+                encrypted.id(),
+                (decrypted.text().to_string(), encrypted.text().to_string()),
+            );
+        }
+    }
+
+    fn get(&self, text: &TextFile) -> Option<&str> {
+        self.0
+            .get(&text.id())
+            .filter(|(plaintext, _)| plaintext == text.text())
+            .map(|(_, ciphertext)| ciphertext.as_str())
+    }
+}
+
+pub fn is_ciphertext(text: &str) -> bool {
+    text.starts_with(PREFIX)
+}
+
+/// Whether a text gives nothing away: it's been encrypted, or it's empty.
+pub fn is_opaque(text: &str) -> bool {
+    text.is_empty() || is_ciphertext(text)
+}
+
+#[cfg(feature = "ssr")]
+impl EncryptionParams {
+    /// Returns `None` if the notebook isn't encrypted.
+    pub async fn for_notebook(
+        executor: impl sqlx::PgExecutor<'_>,
+        notebook_id: i32,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        let result: Option<(Option<Vec<u8>>, Option<String>)> =
+            sqlx::query_as("SELECT encryption_salt, encryption_check FROM notebooks WHERE id = $1")
+                .bind(notebook_id)
+                .fetch_optional(executor)
+                .await?;
+        Ok(result.and_then(|(salt, check)| {
+            Some(Self {
+                salt: salt?,
+                check: check?,
+            })
+        }))
+    }
+}
+
+#[cfg(feature = "ssr")]
+pub async fn is_encrypted(
+    executor: impl sqlx::PgExecutor<'_>,
+    notebook_id: i32,
+) -> Result<bool, sqlx::Error> {
+    Ok(EncryptionParams::for_notebook(executor, notebook_id)
+        .await?
+        .is_some())
+}
+
+/// Like [`is_encrypted`], but stops encryption being turned on until the transaction ends, so what
+/// the transaction writes can't be left readable in a notebook which has just been encrypted.
+#[cfg(feature = "ssr")]
+pub async fn is_encrypted_for_share(
+    executor: impl sqlx::PgExecutor<'_>,
+    notebook_id: i32,
+) -> Result<bool, sqlx::Error> {
+    let encrypted: Option<(bool,)> =
+        sqlx::query_as("SELECT encryption_salt IS NOT NULL FROM notebooks WHERE id = $1 FOR SHARE")
+            .bind(notebook_id)
+            .fetch_optional(executor)
+            .await?;
+    Ok(encrypted.is_some_and(|(encrypted,)| encrypted))
+}
+
+/// Returns `None` if the notebook isn't encrypted.
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all, fields(notebook_id = notebook_id))]
+pub async fn notebook_encryption(
+    notebook_id: i32,
+) -> Result<Option<EncryptionParams>, ServerFnError<AppError>> {
+    let caller: crate::auth::Caller = leptos_actix::extract().await.map_err(AppError::from)?;
+    if !caller.can_read(notebook_id) {
+        return Err(
+            AppError::Unauthorized("You don't have access to that notebook!".to_string()).into(),
+        );
+    }
+    Ok(
+        EncryptionParams::for_notebook(&crate::app::get_pool_from_context().await?, notebook_id)
+            .await
+            .map_err(AppError::from)?,
+    )
+}
+
+/// Turns encryption on, replacing every text with the copy the browser encrypted.
+/// Share links to the notebook are revoked, since they could only show ciphertext from now on.
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all, fields(notebook_id = notebook_id))]
+pub async fn enable_encryption(
+    notebook_id: i32,
+    params: EncryptionParams,
+    texts: Vec<TextFile>,
+) -> Result<(), ServerFnError<AppError>> {
+    crate::sharing::owned_notebook(notebook_id).await?;
+    if params.salt.len() < SALT_LEN
+        || !is_ciphertext(&params.check)
+        || !texts.iter().all(|text| is_opaque(text.text()))
+    {
+        return Err(
+            AppError::Validation("The notebook wasn't encrypted properly!".to_string()).into(),
+        );
+    }
+    let pool = crate::app::get_pool_from_context().await?;
+    let mut transaction = pool.begin().await.map_err(AppError::from)?;
+    // locking the notebook and its texts stops anything being saved unencrypted halfway through
+    let (already_encrypted,): (bool,) = sqlx::query_as(
+        "SELECT encryption_salt IS NOT NULL FROM notebooks WHERE id = $1 FOR UPDATE",
+    )
+    .bind(notebook_id)
+    .fetch_one(&mut *transaction)
+    .await
+    .map_err(AppError::from)?;
+    if already_encrypted {
+        return Err(AppError::Conflict("This notebook is already encrypted!".to_string()).into());
+    }
+    let existing: Vec<(i32,)> =
+        sqlx::query_as("SELECT id FROM texts WHERE notebook_id = $1 ORDER BY id FOR UPDATE")
+            .bind(notebook_id)
+            .fetch_all(&mut *transaction)
+            .await
+            .map_err(AppError::from)?;
+    let mut ids: Vec<i32> = texts.iter().map(TextFile::id).collect();
+    ids.sort_unstable();
+    // any text left out would stay readable
+    if existing.into_iter().map(|(id,)| id).collect::<Vec<_>>() != ids {
+        return Err(AppError::Conflict(
+            "The notebook changed while it was being encrypted, try again!".to_string(),
+        )
+        .into());
+    }
+    sqlx::query(
+        "UPDATE texts SET text = new_texts.text, crdt_state = NULL
+        FROM UNNEST($2::INTEGER[], $3::TEXT[]) AS new_texts (id, text)
+        WHERE texts.id = new_texts.id AND texts.notebook_id = $1",
+    )
+    .bind(notebook_id)
+    .bind(texts.iter().map(TextFile::id).collect::<Vec<_>>())
+    .bind(texts.iter().map(|text| text.text()).collect::<Vec<_>>())
+    .execute(&mut *transaction)
+    .await
+    .map_err(AppError::from)?;
+    sqlx::query("UPDATE notebooks SET encryption_salt = $2, encryption_check = $3 WHERE id = $1")
+        .bind(notebook_id)
+        .bind(&params.salt)
+        .bind(&params.check)
+        .execute(&mut *transaction)
+        .await
+        .map_err(AppError::from)?;
+    sqlx::query(
+        "UPDATE share_links SET revoked_at = now() WHERE notebook_id = $1 AND revoked_at IS NULL",
+    )
+    .bind(notebook_id)
+    .execute(&mut *transaction)
+    .await
+    .map_err(AppError::from)?;
+    transaction.commit().await.map_err(AppError::from)?;
+    crate::sync::publish_from_context(notebook_id, [SyncEvent::Resync]);
+    Ok(())
+}
+
+fn js_error(e: JsValue) -> String {
+    format!("{e:?}")
+}
+
+fn subtle() -> Result<SubtleCrypto, String> {
+    Ok(window().crypto().map_err(js_error)?.subtle())
+}
+
+fn random_bytes(len: usize) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![0; len];
+    window()
+        .crypto()
+        .map_err(js_error)?
+        .get_random_values_with_u8_array(&mut bytes)
+        .map_err(js_error)?;
+    Ok(bytes)
+}
+
+// `btoa` and `atob` work on strings with one character per byte
+fn to_base64(bytes: &[u8]) -> Result<String, String> {
+    window()
+        .btoa(&bytes.iter().map(|byte| *byte as char).collect::<String>())
+        .map_err(js_error)
+}
+
+fn from_base64(text: &str) -> Result<Vec<u8>, String> {
+    Ok(window()
+        .atob(text)
+        .map_err(js_error)?
+        .chars()
+        .map(|c| c as u8)
+        .collect())
+}
+
+async fn resolve(promise: Result<js_sys::Promise, JsValue>) -> Result<JsValue, String> {
+    JsFuture::from(promise.map_err(js_error)?)
+        .await
+        .map_err(js_error)
+}
+
+/// The key to an encrypted notebook, once it's been unlocked. It can't be exported from the browser.
+#[derive(Debug, Clone)]
+pub struct NotebookKey(CryptoKey);
+
+impl NotebookKey {
+    async fn derive(passphrase: &str, salt: &[u8]) -> Result<Self, String> {
+        let subtle = subtle()?;
+        let material = resolve(subtle.import_key_with_str(
+            "raw",
+            &Uint8Array::from(passphrase.as_bytes()),
+            "PBKDF2",
+            false,
+            &Array::of1(&JsValue::from_str("deriveKey")),
+        ))
+        .await?;
+        let key = resolve(subtle.derive_key_with_object_and_object(
+            &Pbkdf2Params::new(
+                "PBKDF2",
+                &JsValue::from_str("SHA-256"),
+                PBKDF2_ITERATIONS,
+                &Uint8Array::from(salt),
+            ),
+            material.unchecked_ref(),
+            &AesKeyGenParams::new("AES-GCM", 256),
+            false,
+            &Array::of2(&JsValue::from_str("encrypt"), &JsValue::from_str("decrypt")),
+        ))
+        .await?;
+        Ok(Self(key.unchecked_into()))
+    }
+
+    /// Makes a key for a notebook which isn't encrypted yet, along with what the server should keep.
+    pub async fn generate(passphrase: &str) -> Result<(Self, EncryptionParams), String> {
+        let salt = random_bytes(SALT_LEN)?;
+        let key = Self::derive(passphrase, &salt).await?;
+        let check = key.encrypt(CHECK_TEXT).await?;
+        Ok((key, EncryptionParams { salt, check }))
+    }
+
+    /// Returns `None` if the passphrase is wrong.
+    pub async fn unlock(
+        passphrase: &str,
+        params: &EncryptionParams,
+    ) -> Result<Option<Self>, String> {
+        let key = Self::derive(passphrase, &params.salt).await?;
+        let check = key.decrypt(&params.check).await.ok();
+        Ok((check.as_deref() == Some(CHECK_TEXT)).then_some(key))
+    }
+
+    /// A fresh IV every time, so the same text never encrypts the same way twice.
+    pub async fn encrypt(&self, text: &str) -> Result<String, String> {
+        if text.is_empty() {
+            return Ok(String::new());
+        }
+        let iv = random_bytes(IV_LEN)?;
+        let ciphertext = resolve(subtle()?.encrypt_with_object_and_buffer_source(
+            &AesGcmParams::new("AES-GCM", &Uint8Array::from(iv.as_slice())),
+            &self.0,
+            &Uint8Array::from(text.as_bytes()),
+        ))
+        .await?;
+        let mut bytes = iv;
+        bytes.extend(Uint8Array::new(&ciphertext).to_vec());
+        Ok(format!("{PREFIX}{}", to_base64(&bytes)?))
+    }
+
+    /// Texts which aren't encrypted, like new empty ones, are passed through as they are.
+    pub async fn decrypt(&self, text: &str) -> Result<String, String> {
+        let Some(encoded) = text.strip_prefix(PREFIX) else {
+            return Ok(text.to_string());
+        };
+        let bytes = from_base64(encoded)?;
+        if bytes.len() < IV_LEN {
+            return Err("the ciphertext is too short".to_string());
+        }
+        let (iv, ciphertext) = bytes.split_at(IV_LEN);
+        let plaintext = resolve(subtle()?.decrypt_with_object_and_buffer_source(
+            &AesGcmParams::new("AES-GCM", &Uint8Array::from(iv)),
+            &self.0,
+            &Uint8Array::from(ciphertext),
+        ))
+        .await
+        .map_err(|_| "the text couldn't be decrypted".to_string())?;
+        String::from_utf8(Uint8Array::new(&plaintext).to_vec()).map_err(|e| e.to_string())
+    }
+
+    /// Texts which are the same as in `known` keep the ciphertext they already have.
+    pub async fn encrypt_notebook(
+        &self,
+        notebook: &Notebook,
+        known: &Ciphertexts,
+    ) -> Result<Notebook, String> {
+        let mut encrypted = notebook.clone();
+        for text in notebook.texts() {
+            let ciphertext = match known.get(text) {
+                Some(ciphertext) => ciphertext.to_string(),
+                None => self.encrypt(text.text()).await?,
+            };
+            encrypted.set_text(text.id(), ciphertext);
+        }
+        Ok(encrypted)
+    }
+
+    pub async fn decrypt_notebook(&self, notebook: &Notebook) -> Result<Notebook, String> {
+        let mut decrypted = notebook.clone();
+        for text in notebook.texts() {
+            decrypted.set_text(text.id(), self.decrypt(text.text()).await?);
+        }
+        Ok(decrypted)
+    }
+
+    /// Decrypts the texts in a change from another device.
+    pub async fn decrypt_event(&self, event: SyncEvent) -> Result<SyncEvent, String> {
+        Ok(match event {
+            SyncEvent::TextInserted { text } => SyncEvent::TextInserted {
+                text: TextFile::new(text.id(), self.decrypt(text.text()).await?),
+            },
+            SyncEvent::TextUpdated { text } => SyncEvent::TextUpdated {
+                text: TextFile::new(text.id(), self.decrypt(text.text()).await?),
+            },
+            event => event,
+        })
+    }
+}
+
+/// Asks for the passphrase to an encrypted notebook, and decrypts it into `notebook` once it's right.
+#[component]
+pub fn UnlockNotebook(
+    params: EncryptionParams,
+    encrypted: Notebook,
+    notebook: RwSignal<Option<Notebook>>,
+) -> impl IntoView {
+    let sync = expect_context::<SyncConnection>();
+    let params = StoredValue::new(params);
+    let encrypted = StoredValue::new(encrypted);
+    let passphrase = RwSignal::new(String::new());
+    let error = RwSignal::new(None::<String>);
+    let unlocking = RwSignal::new(false);
+
+    let unlock = move |ev: leptos::ev::SubmitEvent| {
+        ev.prevent_default();
+        let entered = passphrase.get_untracked();
+        unlocking.set(true);
+        spawn_local(async move {
+            let result = match NotebookKey::unlock(&entered, &params.get_value()).await {
+                Ok(Some(key)) => key
+                    .decrypt_notebook(&encrypted.get_value())
+                    .await
+                    .map(|decrypted| (key, decrypted)),
+                Ok(None) => Err("That passphrase is incorrect!".to_string()),
+                Err(e) => Err(e),
+            };
+            unlocking.set(false);
+            match result {
+                Ok((key, decrypted)) => {
+                    sync.unlock(key);
+                    sync.mark_synced(&decrypted);
+                    sync.remember_ciphertexts(&encrypted.get_value(), &decrypted);
+                    notebook.set(Some(decrypted));
+                }
+                Err(e) => {
+                    log!("couldn't unlock the notebook: {e}");
+                    error.set(Some(e));
+                }
+            }
+        })
+    };
+
+    view! {
+        <form class="unlock-notebook" on:submit=unlock>
+            <h1> "This notebook is encrypted" </h1>
+            <input
+                type="password"
+                placeholder="Passphrase..."
+                autocomplete="current-password"
+                prop:value=move || passphrase.get()
+                on:input:target=move |ev| passphrase.set(ev.target().value())
+            />
+            <br />
+            <button type="submit" disabled=move || unlocking.get()>
+                {move || if unlocking.get() { "Unlocking..." } else { "Unlock" }}
+            </button>
+            {move || error.get().map(|e| view! { <p class="error-message"> {e} </p> })}
+        </form>
+    }
+}
+
+#[component]
+pub fn EncryptionSettings(notebook_id: i32) -> impl IntoView {
+    let encrypted = RwSignal::new(None::<bool>);
+    let passphrase = RwSignal::new(String::new());
+    let confirmation = RwSignal::new(String::new());
+    let error = RwSignal::new(None::<String>);
+    let working = RwSignal::new(false);
+
+    let refresh = move || {
+        spawn_local(async move {
+            match notebook_encryption(notebook_id).await {
+                Ok(params) => encrypted.set(Some(params.is_some())),
+                Err(e) => log!("couldn't check the notebook's encryption: {:#?}", e),
+            }
+        })
+    };
+    Effect::new(move |_| refresh());
+
+    let encrypt = move |_| {
+        let (entered, confirmed) = (passphrase.get_untracked(), confirmation.get_untracked());
+        if entered.chars().count() < MIN_PASSPHRASE_LENGTH {
+            return error.set(Some(format!(
+                "Passphrases need at least {MIN_PASSPHRASE_LENGTH} characters!"
+            )));
+        }
+        if entered != confirmed {
+            return error.set(Some("The passphrases don't match!".to_string()));
+        }
+        working.set(true);
+        spawn_local(async move {
+            let result: Result<(), String> = async {
+                let (key, params) = NotebookKey::generate(&entered).await?;
+                let notebook = crate::app::get_notebook(notebook_id)
+                    .await
+                    .map_err(|e| error_message(&e))?;
+                let texts = key
+                    .encrypt_notebook(&notebook, &Ciphertexts::default())
+                    .await?
+                    .texts()
+                    .cloned()
+                    .collect();
+                enable_encryption(notebook_id, params, texts)
+                    .await
+                    .map_err(|e| error_message(&e))
+            }
+            .await;
+            working.set(false);
+            match result {
+                Ok(()) => {
+                    error.set(None);
+                    passphrase.set(String::new());
+                    confirmation.set(String::new());
+                }
+                Err(e) => error.set(Some(e)),
+            }
+            refresh();
+        })
+    };
+
+    let body = move || match encrypted.get() {
+        Some(true) => Either::Left(view! {
+            <p> "This notebook is encrypted. Its texts can only be read with the passphrase." </p>
+        }),
+        _ => Either::Right(view! {
+            <p>
+                "Encrypt this notebook's texts in the browser, so the server only stores ciphertext. "
+                "Nobody can recover the notes if the passphrase is lost, share links stop working, "
+                "and cells can no longer be edited by several people at once."
+            </p>
+            <input
+                type="password"
+                placeholder="Passphrase..."
+                autocomplete="new-password"
+                prop:value=move || passphrase.get()
+                on:input:target=move |ev| passphrase.set(ev.target().value())
+            />
+            <input
+                type="password"
+                placeholder="Passphrase again..."
+                autocomplete="new-password"
+                prop:value=move || confirmation.get()
+                on:input:target=move |ev| confirmation.set(ev.target().value())
+            />
+            <button on:click=encrypt disabled=move || working.get()> "Encrypt" </button>
+        }),
+    };
+
+    view! {
+        <div class="settings-section">
+            <h2> "Encryption" </h2>
+            {body}
+            {move || error.get().map(|e| view! { <p class="error-message"> {e} </p> })}
+        </div>
+    }
+}
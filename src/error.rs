@@ -0,0 +1,148 @@
+use std::{fmt, str::FromStr};
+
+use leptos::{either::EitherOf6, prelude::*};
+use serde::{Deserialize, Serialize};
+
+// every server function fails with an `AppError`, so the client can tell what went wrong
+// without having to recognise the message.
+
+/// Why a server function failed. The message is meant for whoever is using the app.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "message")]
+pub enum AppError {
+    /// It doesn't exist, or the caller isn't allowed to know whether it does.
+    NotFound(String),
+    /// The caller isn't logged in, or isn't allowed to do that.
+    Unauthorized(String),
+    /// It clashes with something already there, like a name that's taken.
+    Conflict(String),
+    /// What was sent doesn't make sense, or the password or code was wrong.
+    Validation(String),
+    /// The database or the session failed. The details are only printed on the server.
+    Storage(String),
+}
+impl AppError {
+    pub fn message(&self) -> &str {
+        match self {
+            Self::NotFound(message)
+            | Self::Unauthorized(message)
+            | Self::Conflict(message)
+            | Self::Validation(message)
+            | Self::Storage(message) => message,
+        }
+    }
+}
+
+/// Server functions send custom errors as text, so this is the JSON the client parses back.
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            serde_json::to_string(self).map_err(|_| fmt::Error)?
+        )
+    }
+}
+impl FromStr for AppError {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s)
+    }
+}
+
+const STORAGE_MESSAGE: &str = "Something went wrong on our end, please try again!";
+
+/// Only from extracting something from the request, which fails if the session can't be read.
+impl From<ServerFnError> for AppError {
+    fn from(e: ServerFnError) -> Self {
+        tracing::error!("couldn't read a server function's request: {e}");
+        Self::Storage(STORAGE_MESSAGE.to_string())
+    }
+}
+
+#[cfg(feature = "ssr")]
+impl From<sqlx::Error> for AppError {
+    fn from(e: sqlx::Error) -> Self {
+        tracing::error!("database error in a server function: {e}");
+        Self::Storage(STORAGE_MESSAGE.to_string())
+    }
+}
+
+#[cfg(feature = "ssr")]
+impl From<actix_session::SessionInsertError> for AppError {
+    fn from(e: actix_session::SessionInsertError) -> Self {
+        tracing::error!("couldn't update a session: {e}");
+        Self::Storage(STORAGE_MESSAGE.to_string())
+    }
+}
+
+#[cfg(feature = "ssr")]
+impl From<crate::cells::CellError> for AppError {
+    fn from(e: crate::cells::CellError) -> Self {
+        match e {
+            crate::cells::CellError::Database(e) => e.into(),
+            crate::cells::CellError::InvalidUpdate(e) => Self::Validation(e),
+            crate::cells::CellError::Encrypted => {
+                Self::Validation(crate::cells::CellError::Encrypted.to_string())
+            }
+            crate::cells::CellError::Stale => Self::Conflict(
+                "Someone else changed that text at the same time, so your change to it wasn't saved!"
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "ssr")]
+impl From<crate::two_factor::TwoFactorError> for AppError {
+    fn from(e: crate::two_factor::TwoFactorError) -> Self {
+        match e {
+            crate::two_factor::TwoFactorError::Database(e) => e.into(),
+            e => {
+                tracing::error!("two-factor authentication failed: {e}");
+                Self::Storage(STORAGE_MESSAGE.to_string())
+            }
+        }
+    }
+}
+
+/// What to tell the user about a failed server function.
+pub fn error_message(e: &ServerFnError<AppError>) -> String {
+    match e {
+        ServerFnError::WrappedServerError(e) => e.message().to_string(),
+        ServerFnError::Request(_) => {
+            "Couldn't reach the server, check your connection!".to_string()
+        }
+        e => e.to_string(),
+    }
+}
+
+/// Shows a failed server function: a way to log in if that's the problem, a page of its own if
+/// what was asked for isn't there, a plain apology if it was the server's fault, and what needs
+/// fixing, next to the form that was sent, if it was the input's.
+#[component]
+pub fn ErrorMessage(error: ServerFnError<AppError>) -> impl IntoView {
+    match error {
+        ServerFnError::WrappedServerError(AppError::Unauthorized(message)) => EitherOf6::A(view! {
+            <p class="error-message"> {message} " " <a href="/"> "Log in" </a> </p>
+        }),
+        ServerFnError::WrappedServerError(AppError::NotFound(message)) => EitherOf6::B(view! {
+            <div class="not-found">
+                <h1> "Not found" </h1>
+                <p> {message} </p>
+                <a href="/"> "Back to the home page" </a>
+            </div>
+        }),
+        ServerFnError::WrappedServerError(AppError::Validation(message)) => EitherOf6::C(view! {
+            <p class="error-message field-error" role="alert"> {message} </p>
+        }),
+        ServerFnError::WrappedServerError(AppError::Conflict(message)) => EitherOf6::D(view! {
+            <p class="error-message field-error conflict" role="alert"> {message} </p>
+        }),
+        ServerFnError::WrappedServerError(AppError::Storage(message)) => {
+            EitherOf6::E(view! { <p class="error-message server-error"> {message} </p> })
+        }
+        e => EitherOf6::F(view! { <p class="error-message"> {error_message(&e)} </p> }),
+    }
+}
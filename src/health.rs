@@ -0,0 +1,86 @@
+//! Health checks for the platform: `/healthz` says the process is up, and `/readyz` says it can
+//! serve requests, which needs the database to answer and every migration to have been run.
+
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+use sqlx::migrate::Migrator;
+
+use crate::AppState;
+
+/// The same migrations `main` runs at startup.
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+#[derive(Serialize)]
+struct Status {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    checks: Option<Checks>,
+}
+
+#[derive(Serialize)]
+struct Checks {
+    database: String,
+    migrations: String,
+}
+
+#[get("/healthz")]
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(Status {
+        status: "ok",
+        checks: None,
+    })
+}
+
+#[get("/readyz")]
+pub async fn readyz(state: web::Data<AppState>) -> HttpResponse {
+    let database = sqlx::query("SELECT 1").execute(&state.pool).await;
+    // there's no telling without the database
+    let migrations = match &database {
+        Ok(_) => Some(pending_migrations(&state.pool).await),
+        Err(_) => None,
+    };
+    let ready = database.is_ok() && matches!(migrations, Some(Ok(0)));
+    let checks = Checks {
+        database: match database {
+            Ok(_) => "ok".to_string(),
+            Err(e) => {
+                tracing::warn!("readiness check couldn't reach the database: {e}");
+                "unreachable".to_string()
+            }
+        },
+        migrations: match migrations {
+            Some(Ok(0)) => "ok".to_string(),
+            Some(Ok(pending)) => format!("{pending} pending"),
+            None => "unknown".to_string(),
+            Some(Err(e)) => {
+                tracing::warn!("readiness check couldn't read the migrations: {e}");
+                "unknown".to_string()
+            }
+        },
+    };
+    let status = Status {
+        status: if ready { "ok" } else { "unavailable" },
+        checks: Some(checks),
+    };
+    if ready {
+        HttpResponse::Ok().json(status)
+    } else {
+        HttpResponse::ServiceUnavailable().json(status)
+    }
+}
+
+/// How many of the migrations built into the server haven't been run successfully.
+async fn pending_migrations(pool: &sqlx::Pool<sqlx::Postgres>) -> Result<usize, sqlx::Error> {
+    let applied: Vec<(i64,)> = sqlx::query_as("SELECT version FROM _sqlx_migrations WHERE success")
+        .fetch_all(pool)
+        .await?;
+    Ok(MIGRATOR
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration())
+        .filter(|migration| {
+            !applied
+                .iter()
+                .any(|(version,)| *version == migration.version)
+        })
+        .count())
+}
@@ -0,0 +1,468 @@
+use js_sys::{Array, Function, Reflect};
+use leptos::{either::Either, logging::log, prelude::*, tachys::dom::window, task::spawn_local};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::{prelude::Closure, JsCast, JsValue};
+
+use crate::{
+    error::{error_message, AppError},
+    sharing::Role,
+};
+
+// the home page's notebooks: the ones this device has opened, the ones the caller can open right now,
+// and a quick way to jot something down in one of them without opening it.
+
+/// A notebook the caller can open without a password, most recently opened first.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecentNotebook {
+    id: i32,
+    name: String,
+    role: Role,
+    /// Encrypted notebooks can only be added to once they're open and unlocked.
+    encrypted: bool,
+}
+
+/// A notebook this browser has opened, kept in local storage.
+/// It may need its password again, or may not even exist any more.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct DeviceNotebook {
+    id: i32,
+    name: String,
+}
+
+const DEVICE_NOTEBOOKS_KEY: &str = "recent-notebooks";
+const DEVICE_NOTEBOOKS: usize = 10;
+const ACCOUNT_NOTEBOOKS: i64 = 20;
+/// Set once dictation has been turned on in this browser, which it isn't until someone chooses to.
+const DICTATION_KEY: &str = "dictation";
+
+fn local_storage() -> Option<web_sys::Storage> {
+    window().local_storage().ok().flatten()
+}
+
+fn opened_on_device() -> Vec<DeviceNotebook> {
+    local_storage()
+        .and_then(|storage| storage.get_item(DEVICE_NOTEBOOKS_KEY).ok().flatten())
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+/// Puts a notebook at the top of this device's recent notebooks.
+pub fn remember_opened(id: i32, name: &str) {
+    let mut recent = opened_on_device();
+    recent.retain(|notebook| notebook.id != id);
+    recent.insert(
+        0,
+        DeviceNotebook {
+            id,
+            name: name.to_string(),
+        },
+    );
+    recent.truncate(DEVICE_NOTEBOOKS);
+    if let (Some(storage), Ok(json)) = (local_storage(), serde_json::to_string(&recent)) {
+        let _ = storage.set_item(DEVICE_NOTEBOOKS_KEY, &json);
+    }
+}
+
+fn dictation_allowed() -> bool {
+    local_storage()
+        .and_then(|storage| storage.get_item(DICTATION_KEY).ok().flatten())
+        .is_some()
+}
+
+fn allow_dictation(allowed: bool) {
+    if let Some(storage) = local_storage() {
+        let _ = if allowed {
+            storage.set_item(DICTATION_KEY, "on")
+        } else {
+            storage.remove_item(DICTATION_KEY)
+        };
+    }
+}
+
+fn forget_device_notebooks() {
+    if let Some(storage) = local_storage() {
+        let _ = storage.remove_item(DEVICE_NOTEBOOKS_KEY);
+    }
+}
+
+/// Notes when an account last opened a notebook shared with it, to order its recent notebooks.
+#[cfg(feature = "ssr")]
+pub async fn touch_membership(
+    executor: impl sqlx::PgExecutor<'_>,
+    notebook_id: i32,
+    user_id: i32,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE memberships SET last_opened_at = now() WHERE notebook_id = $1 AND user_id = $2",
+    )
+    .bind(notebook_id)
+    .bind(user_id)
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Called once by the notebook page, rather than from `get_notebook`, so that `GET` stays free of side effects.
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all, fields(notebook_id = id))]
+pub async fn notebook_opened(id: i32) -> Result<(), ServerFnError<AppError>> {
+    let caller: crate::auth::Caller = leptos_actix::extract().await.map_err(AppError::from)?;
+    if !caller.can_read(id) {
+        return Err(
+            AppError::Unauthorized("You don't have access to that notebook!".to_string()).into(),
+        );
+    }
+    if let Some(user_id) = caller.user_id() {
+        let pool = crate::app::get_pool_from_context().await?;
+        touch_membership(&pool, id, user_id)
+            .await
+            .map_err(AppError::from)?;
+    }
+    Ok(())
+}
+
+/// The notebook opened with its password in this session, then the account's notebooks.
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all)]
+pub async fn recent_notebooks() -> Result<Vec<RecentNotebook>, ServerFnError<AppError>> {
+    let caller: crate::auth::Caller = leptos_actix::extract().await.map_err(AppError::from)?;
+    let crate::auth::Caller::Session {
+        notebook_id,
+        user_id,
+        ..
+    } = caller
+    else {
+        return Ok(Vec::new());
+    };
+    let pool = crate::app::get_pool_from_context().await?;
+    let mut notebooks = Vec::new();
+    if let Some(notebook_id) = notebook_id {
+        let owned: Option<(String, bool)> =
+            sqlx::query_as("SELECT name, encryption_salt IS NOT NULL FROM notebooks WHERE id = $1")
+                .bind(notebook_id)
+                .fetch_optional(&pool)
+                .await
+                .map_err(AppError::from)?;
+        if let Some((name, encrypted)) = owned {
+            notebooks.push(RecentNotebook {
+                id: notebook_id,
+                name,
+                role: Role::Owner,
+                encrypted,
+            });
+        }
+    }
+    if let Some(user_id) = user_id {
+        let shared: Vec<(i32, String, String, bool)> = sqlx::query_as(
+            "SELECT notebooks.id, notebooks.name, memberships.role, notebooks.encryption_salt IS NOT NULL
+            FROM memberships JOIN notebooks ON notebooks.id = memberships.notebook_id
+            WHERE memberships.user_id = $1 AND notebooks.id IS DISTINCT FROM $2
+            ORDER BY memberships.last_opened_at DESC NULLS LAST, UPPER(notebooks.name)
+            LIMIT $3",
+        )
+        .bind(user_id)
+        .bind(notebook_id)
+        .bind(ACCOUNT_NOTEBOOKS)
+        .fetch_all(&pool)
+        .await
+        .map_err(AppError::from)?;
+        notebooks.extend(
+            shared
+                .into_iter()
+                .filter_map(|(id, name, role, encrypted)| {
+                    Some(RecentNotebook {
+                        id,
+                        name,
+                        role: role.parse().ok()?,
+                        encrypted,
+                    })
+                }),
+        );
+    }
+    Ok(notebooks)
+}
+
+/// Adds a text to the end of a notebook, from the home page.
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all, fields(notebook_id = notebook_id))]
+pub async fn capture_text(notebook_id: i32, text: String) -> Result<(), ServerFnError<AppError>> {
+    let caller: crate::auth::Caller = leptos_actix::extract().await.map_err(AppError::from)?;
+    if !caller.can_write(notebook_id) {
+        return Err(AppError::Unauthorized("You can't add to that notebook!".to_string()).into());
+    }
+    if text.trim().is_empty() {
+        return Err(AppError::Validation("There's nothing to save!".to_string()).into());
+    }
+    let pool = crate::app::get_pool_from_context().await?;
+    let mut transaction = pool.begin().await.map_err(AppError::from)?;
+    // the text would be stored where the server can read it
+    if crate::encryption::is_encrypted_for_share(&mut *transaction, notebook_id)
+        .await
+        .map_err(AppError::from)?
+    {
+        return Err(
+            AppError::Validation("Open an encrypted notebook to add to it!".to_string()).into(),
+        );
+    }
+    let texts = crate::notebook::Notebook::insert_texts(&mut *transaction, notebook_id, &[text])
+        .await
+        .map_err(AppError::from)?;
+    transaction.commit().await.map_err(AppError::from)?;
+    crate::sync::publish_from_context(
+        notebook_id,
+        texts
+            .into_iter()
+            .map(|text| crate::sync::SyncEvent::TextInserted { text }),
+    );
+    Ok(())
+}
+
+/// Notebooks the caller can open straight away, then the ones this device remembers.
+/// Picking one of the latter fills in `notebook_name` on the open form.
+#[component]
+pub fn RecentNotebooks(notebook_name: RwSignal<String>) -> impl IntoView {
+    let notebooks = RwSignal::new(Vec::<RecentNotebook>::new());
+    let on_device = RwSignal::new(Vec::<DeviceNotebook>::new());
+    Effect::new(move |_| {
+        on_device.set(opened_on_device());
+        spawn_local(async move {
+            match recent_notebooks().await {
+                Ok(received) => notebooks.set(received),
+                Err(e) => log!("couldn't list recent notebooks: {:#?}", e),
+            }
+        })
+    });
+    // the ones that can be opened straight away are already listed
+    let needs_password = move || {
+        notebooks.with(|notebooks| {
+            on_device
+                .get()
+                .into_iter()
+                .filter(|device| !notebooks.iter().any(|notebook| notebook.id == device.id))
+                .collect::<Vec<_>>()
+        })
+    };
+    let forget = move |_| {
+        forget_device_notebooks();
+        on_device.set(Vec::new());
+    };
+
+    view! {
+        <div id="recent-notebooks">
+            <Show when=move || !notebooks.with(Vec::is_empty)>
+                <h2> "Your notebooks" </h2>
+                <ul>
+                    <For
+                        each=move || notebooks.get()
+                        key=|notebook| notebook.id
+                        children=move |notebook| view! {
+                            <li>
+                                <a href=format!("/notebook/{}", notebook.id)> {notebook.name.clone()} </a>
+                                {format!(" ({})", notebook.role.as_str())}
+                            </li>
+                        }
+                    />
+                </ul>
+            </Show>
+            <Show when=move || !needs_password().is_empty()>
+                <h2> "Opened on this device" </h2>
+                <ul>
+                    <For
+                        each=needs_password
+                        key=|notebook| notebook.id
+                        children=move |notebook| {
+                            let name = notebook.name.clone();
+                            view! {
+                                <li>
+                                    {notebook.name}
+                                    <button on:click=move |_| notebook_name.set(name.clone())> "Open..." </button>
+                                </li>
+                            }
+                        }
+                    />
+                </ul>
+                <button on:click=forget> "Forget these" </button>
+            </Show>
+            <QuickCapture notebooks />
+        </div>
+    }
+}
+
+/// The browser's speech recognition, if it has any. Chrome and Safari still only have it prefixed.
+fn speech_recognition() -> Option<JsValue> {
+    let window: JsValue = window().into();
+    ["SpeechRecognition", "webkitSpeechRecognition"]
+        .into_iter()
+        .find_map(|name| {
+            let constructor = Reflect::get(&window, &JsValue::from_str(name)).ok()?;
+            let constructor = constructor.dyn_into::<Function>().ok()?;
+            Reflect::construct(&constructor, &Array::new()).ok()
+        })
+}
+
+fn call_method(object: &JsValue, name: &str) {
+    if let Ok(method) = Reflect::get(object, &JsValue::from_str(name))
+        .and_then(|method| method.dyn_into::<Function>())
+    {
+        let _ = method.call0(object);
+    }
+}
+
+/// The finished phrases in a speech recognition `result` event.
+fn final_transcript(event: &JsValue) -> Option<String> {
+    let get = |object: &JsValue, key: &str| Reflect::get(object, &JsValue::from_str(key)).ok();
+    let results = get(event, "results")?;
+    let first = get(event, "resultIndex")?.as_f64()? as u32;
+    let length = get(&results, "length")?.as_f64()? as u32;
+    let mut transcript = String::new();
+    for i in first..length {
+        let result = Reflect::get_u32(&results, i).ok()?;
+        if get(&result, "isFinal")?.as_bool() == Some(true) {
+            let best = Reflect::get_u32(&result, 0).ok()?;
+            transcript.push_str(get(&best, "transcript")?.as_string()?.trim());
+            transcript.push(' ');
+        }
+    }
+    Some(transcript)
+}
+
+/// Jots a text, typed or dictated, into the end of a notebook without opening it.
+#[component]
+fn QuickCapture(notebooks: RwSignal<Vec<RecentNotebook>>) -> impl IntoView {
+    let writable = move || {
+        notebooks
+            .get()
+            .into_iter()
+            .filter(|notebook| notebook.role.can_edit() && !notebook.encrypted)
+            .collect::<Vec<_>>()
+    };
+    let chosen = RwSignal::new(None::<i32>);
+    // the first one, until something else is picked
+    let notebook_id = move || {
+        chosen
+            .get()
+            .or_else(|| writable().first().map(|notebook| notebook.id))
+    };
+    let text = RwSignal::new(String::new());
+    let message = RwSignal::new(None::<Result<String, String>>);
+
+    let save = move |_| {
+        let Some(id) = notebook_id() else {
+            return;
+        };
+        let captured = text.get_untracked();
+        spawn_local(async move {
+            message.set(Some(match capture_text(id, captured).await {
+                Ok(()) => {
+                    text.set(String::new());
+                    Ok("Saved.".to_string())
+                }
+                Err(e) => Err(error_message(&e)),
+            }));
+        })
+    };
+
+    let recognition = StoredValue::new_local(None::<JsValue>);
+    let listening = RwSignal::new(false);
+    let can_dictate = RwSignal::new(false);
+    // off until it's turned on, since the browser may send the audio away to be transcribed
+    let dictation_on = RwSignal::new(false);
+    Effect::new(move |_| {
+        can_dictate.set(speech_recognition().is_some());
+        dictation_on.set(dictation_allowed());
+    });
+    let toggle_dictation = move |on: bool| {
+        allow_dictation(on);
+        dictation_on.set(on);
+        if !on {
+            if let Some(recognition) = recognition.get_value() {
+                call_method(&recognition, "stop");
+            }
+        }
+    };
+    let dictate = move |_| {
+        if listening.get_untracked() {
+            if let Some(recognition) = recognition.get_value() {
+                call_method(&recognition, "stop");
+            }
+            return;
+        }
+        let Some(new_recognition) = speech_recognition() else {
+            return;
+        };
+        let _ = Reflect::set(&new_recognition, &"continuous".into(), &JsValue::TRUE);
+        let on_result = Closure::<dyn FnMut(JsValue)>::new(move |event: JsValue| {
+            if let Some(transcript) = final_transcript(&event) {
+                text.update(|text| {
+                    if !text.is_empty() && !text.ends_with(char::is_whitespace) {
+                        text.push(' ');
+                    }
+                    text.push_str(transcript.trim_end());
+                });
+            }
+        });
+        let on_end = Closure::<dyn FnMut()>::new(move || listening.set(false));
+        let _ = Reflect::set(&new_recognition, &"onresult".into(), on_result.as_ref());
+        let _ = Reflect::set(&new_recognition, &"onend".into(), on_end.as_ref());
+        on_result.forget();
+        on_end.forget();
+        call_method(&new_recognition, "start");
+        recognition.set_value(Some(new_recognition));
+        listening.set(true);
+    };
+
+    view! {
+        <div id="quick-capture">
+            <h2> "Quick capture" </h2>
+            {move || if writable().is_empty() {
+                Either::Left(view! { <p> "Open or create a notebook to capture into it." </p> })
+            } else {
+                Either::Right(view! {
+                    <select on:change:target=move |ev| chosen.set(ev.target().value().parse().ok())>
+                        {writable()
+                            .into_iter()
+                            .map(|notebook| {
+                                let id = notebook.id;
+                                view! {
+                                    <option value=id selected=move || notebook_id() == Some(id)>
+                                        {notebook.name}
+                                    </option>
+                                }
+                            })
+                            .collect_view()}
+                    </select>
+                    <textarea
+                        placeholder="Write something down..."
+                        prop:value=move || text.get()
+                        on:input:target=move |ev| text.set(ev.target().value())
+                    ></textarea>
+                    <button on:click=save> "Save" </button>
+                    <Show when=move || can_dictate.get() && dictation_on.get()>
+                        <button on:click=dictate>
+                            {move || if listening.get() { "Stop dictating" } else { "Dictate" }}
+                        </button>
+                    </Show>
+                    <Show when=move || can_dictate.get()>
+                        <label class="dictation">
+                            <input
+                                type="checkbox"
+                                prop:checked=move || dictation_on.get()
+                                on:change:target=move |ev| toggle_dictation(ev.target().checked())
+                            />
+                            " Dictate with this browser's speech recognition"
+                        </label>
+                        <p class="dictation-note">
+                            "Your browser transcribes what you say, not note-to-self, and most send the audio to "
+                            "their maker to do it: Chrome sends it to Google, and Safari to Apple."
+                        </p>
+                    </Show>
+                })
+            }}
+            {move || match message.get() {
+                Some(Ok(done)) => Either::Left(view! { <p> {done} </p> }),
+                Some(Err(e)) => Either::Right(Either::Left(view! { <p class="error-message"> {e} </p> })),
+                None => Either::Right(Either::Right(())),
+            }}
+        </div>
+    }
+}
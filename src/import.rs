@@ -0,0 +1,518 @@
+use leptos::server_fn::codec::{MultipartData, MultipartFormData};
+use leptos::{either::Either, logging::log, prelude::*, task::spawn_local};
+use leptos_router::{hooks::use_params, params::Params};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::JsCast;
+use web_sys::{FormData, HtmlFormElement, SubmitEvent};
+
+use crate::error::{error_message, AppError};
+
+// imports accept either:
+// - a zip of markdown files (a plain folder, or an obsidian vault), one text per file
+// - a json dump of a `Notebook`, or a list of them, as produced by serde
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ImportReport {
+    pub notebook_id: i32,
+    pub imported: usize,
+    pub skipped: Vec<SkippedFile>,
+    pub duplicates: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SkippedFile {
+    pub path: String,
+    pub reason: String,
+}
+
+/// A text pulled out of an upload, along with where it came from.
+#[cfg(feature = "ssr")]
+pub(crate) struct ImportedText {
+    source: String,
+    text: String,
+}
+
+#[cfg(feature = "ssr")]
+fn is_markdown(path: &str) -> bool {
+    let path = path.to_lowercase();
+    path.ends_with(".md") || path.ends_with(".markdown") || path.ends_with(".txt")
+}
+
+/// Vault metadata (`.obsidian/`, `.trash/`) and macOS resource forks aren't notes.
+#[cfg(feature = "ssr")]
+fn is_hidden(path: &str) -> bool {
+    path.split('/')
+        .any(|component| component.starts_with('.') || component == "__MACOSX")
+}
+
+/// The biggest file in a zip that's imported as a note. Bigger ones are skipped.
+#[cfg(feature = "ssr")]
+const MAX_UNZIPPED_FILE_BYTES: u64 = 1024 * 1024;
+/// How much a zip can unpack to altogether, so a small zip that unpacks to gigabytes is refused.
+#[cfg(feature = "ssr")]
+const MAX_UNZIPPED_BYTES: u64 = 100 * 1024 * 1024;
+
+#[cfg(feature = "ssr")]
+fn read_zip(bytes: Vec<u8>) -> Result<(Vec<ImportedText>, Vec<SkippedFile>), String> {
+    use std::io::Read;
+
+    let too_big = || {
+        format!(
+            "That zip file unpacks to more than {} MB!",
+            MAX_UNZIPPED_BYTES / (1024 * 1024)
+        )
+    };
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(bytes))
+        .map_err(|e| format!("That doesn't look like a zip file: {e}"))?;
+    let mut texts = Vec::new();
+    let mut skipped = Vec::new();
+    let mut remaining = MAX_UNZIPPED_BYTES;
+    for i in 0..archive.len() {
+        let mut file = archive
+            .by_index(i)
+            .map_err(|e| format!("Couldn't read the zip file: {e}"))?;
+        if file.is_dir() {
+            continue;
+        }
+        let path = file.name().to_string();
+        let mut skip = |reason: &str| {
+            skipped.push(SkippedFile {
+                path: path.clone(),
+                reason: reason.to_string(),
+            })
+        };
+        if file.enclosed_name().is_none() {
+            skip("unsafe path");
+        } else if is_hidden(&path) {
+            continue;
+        } else if !is_markdown(&path) {
+            skip("not a markdown file");
+        } else if file.size() > MAX_UNZIPPED_FILE_BYTES {
+            skip("too big");
+        } else {
+            // the sizes in a zip can lie, so what's actually read is limited too
+            let limit = MAX_UNZIPPED_FILE_BYTES.min(remaining);
+            let mut bytes = Vec::new();
+            (&mut file)
+                .take(limit + 1)
+                .read_to_end(&mut bytes)
+                .map_err(|e| format!("Couldn't read the zip file: {e}"))?;
+            if bytes.len() as u64 > limit {
+                if limit == remaining {
+                    return Err(too_big());
+                }
+                skip("too big");
+                continue;
+            }
+            remaining -= bytes.len() as u64;
+            match String::from_utf8(bytes) {
+                Err(_) => skip("not valid UTF-8"),
+                Ok(text) if text.trim().is_empty() => skip("empty"),
+                Ok(text) => texts.push(ImportedText { source: path, text }),
+            }
+        }
+    }
+    texts.sort_by(|a, b| a.source.cmp(&b.source));
+    Ok((texts, skipped))
+}
+
+#[cfg(feature = "ssr")]
+fn read_json(bytes: Vec<u8>) -> Result<(Vec<ImportedText>, Vec<SkippedFile>), String> {
+    use crate::notebook::Notebook;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Dump {
+        One(Notebook),
+        Many(Vec<Notebook>),
+    }
+
+    let notebooks = match serde_json::from_slice(&bytes)
+        .map_err(|e| format!("That doesn't look like a notebook dump: {e}"))?
+    {
+        Dump::One(notebook) => vec![notebook],
+        Dump::Many(notebooks) => notebooks,
+    };
+    let mut texts = Vec::new();
+    let mut skipped = Vec::new();
+    for notebook in notebooks.iter() {
+        for text in notebook.texts() {
+            let source = format!("{}#{}", notebook.name(), text.id());
+            if text.text().trim().is_empty() {
+                skipped.push(SkippedFile {
+                    path: source,
+                    reason: "empty".to_string(),
+                });
+            } else {
+                texts.push(ImportedText {
+                    source,
+                    text: text.text().to_string(),
+                });
+            }
+        }
+    }
+    Ok((texts, skipped))
+}
+
+/// Json dumps are told apart from zips by the file name.
+#[cfg(feature = "ssr")]
+pub(crate) fn read_upload(
+    file_name: &str,
+    bytes: Vec<u8>,
+) -> Result<(Vec<ImportedText>, Vec<SkippedFile>), String> {
+    if file_name.to_lowercase().ends_with(".json") {
+        read_json(bytes)
+    } else {
+        read_zip(bytes)
+    }
+}
+
+/// Adds the texts to the end of a notebook, leaving out any it already has.
+/// Returns the texts which were added, and where the duplicates came from.
+#[cfg(feature = "ssr")]
+pub(crate) async fn merge_texts(
+    connection: &mut sqlx::PgConnection,
+    notebook_id: i32,
+    texts: Vec<ImportedText>,
+) -> Result<(Vec<crate::notebook::TextFile>, Vec<String>), sqlx::Error> {
+    use std::collections::HashSet;
+
+    let existing: Vec<(String,)> = sqlx::query_as("SELECT text FROM texts WHERE notebook_id = $1")
+        .bind(notebook_id)
+        .fetch_all(&mut *connection)
+        .await?;
+    let mut seen: HashSet<String> = existing
+        .iter()
+        .map(|(text,)| text.trim().to_string())
+        .collect();
+    let mut duplicates = Vec::new();
+    let mut to_insert = Vec::new();
+    for ImportedText { source, text } in texts {
+        if seen.insert(text.trim().to_string()) {
+            to_insert.push(text);
+        } else {
+            duplicates.push(source);
+        }
+    }
+    let inserted =
+        crate::notebook::Notebook::insert_texts(&mut *connection, notebook_id, &to_insert).await?;
+    Ok((inserted, duplicates))
+}
+
+#[server(prefix = "/api", input = MultipartFormData)]
+#[tracing::instrument(skip_all)]
+pub async fn import_notes(data: MultipartData) -> Result<ImportReport, ServerFnError<AppError>> {
+    use crate::{
+        app::{get_config_from_context, get_pool_from_context},
+        notebook::Notebook,
+    };
+
+    let config = get_config_from_context()?;
+    if !config.features.imports {
+        return Err(
+            AppError::Validation("Importing is turned off on this server!".to_string()).into(),
+        );
+    }
+    // the largest upload we're willing to read into memory
+    let max_bytes = config.uploads.max_import_bytes;
+    let session: actix_session::Session = leptos_actix::extract().await.map_err(AppError::from)?;
+    let mut data = data
+        .into_inner()
+        .ok_or_else(|| AppError::Validation("Expected multipart data!".to_string()))?;
+
+    let mut notebook_id: Option<i32> = None;
+    let mut notebook_name = None;
+    let mut notebook_password = None;
+    let mut file: Option<(String, Vec<u8>)> = None;
+    while let Some(mut field) = data
+        .next_field()
+        .await
+        .map_err(|e| AppError::Validation(format!("Couldn't read the upload: {e}")))?
+    {
+        let name = field.name().unwrap_or_default().to_string();
+        match name.as_str() {
+            "notebook_id" => {
+                notebook_id = field.text().await.ok().and_then(|id| id.parse().ok());
+            }
+            "notebook_name" => notebook_name = field.text().await.ok(),
+            "notebook_password" => notebook_password = field.text().await.ok(),
+            "file" => {
+                let file_name = field.file_name().unwrap_or_default().to_string();
+                let mut bytes = Vec::new();
+                while let Some(chunk) = field
+                    .chunk()
+                    .await
+                    .map_err(|e| AppError::Validation(format!("Couldn't read the upload: {e}")))?
+                {
+                    if bytes.len() + chunk.len() > max_bytes {
+                        return Err(AppError::Validation(format!(
+                            "Imports are limited to {} MB!",
+                            max_bytes.div_ceil(1024 * 1024)
+                        ))
+                        .into());
+                    }
+                    bytes.extend_from_slice(&chunk);
+                }
+                crate::metrics::record_import(bytes.len());
+                file = Some((file_name, bytes));
+            }
+            _ => (),
+        }
+    }
+
+    let Some((file_name, bytes)) = file else {
+        return Err(AppError::Validation("Choose a file to import!".to_string()).into());
+    };
+    // unzipping can take a while, so it's kept off the thread that's serving requests
+    let (texts, skipped) = actix_web::web::block(move || read_upload(&file_name, bytes))
+        .await
+        .map_err(|e| AppError::Storage(format!("Couldn't read the upload: {e}")))?
+        .map_err(AppError::Validation)?;
+
+    let pool = get_pool_from_context().await?;
+    let mut transaction = pool.begin().await.map_err(AppError::from)?;
+
+    // either merge into the notebook we're logged in to, or make a new one
+    let (notebook_id, created) = if let Some(notebook_id) = notebook_id {
+        let caller: crate::auth::Caller = leptos_actix::extract().await.map_err(AppError::from)?;
+        if !caller.can_write(notebook_id) {
+            return Err(AppError::Unauthorized(
+                "You don't have access to that notebook!".to_string(),
+            )
+            .into());
+        }
+        // the notes would have to be encrypted in the browser, and importing happens on the server
+        if crate::encryption::is_encrypted_for_share(&mut *transaction, notebook_id)
+            .await
+            .map_err(AppError::from)?
+        {
+            return Err(AppError::Validation(
+                "Notes can't be imported into an encrypted notebook!".to_string(),
+            )
+            .into());
+        }
+        (notebook_id, false)
+    } else {
+        let (Some(name), Some(password)) = (notebook_name, notebook_password) else {
+            return Err(AppError::Validation(
+                "A new notebook needs a name and a password!".to_string(),
+            )
+            .into());
+        };
+        if Notebook::name_taken(&mut *transaction, &name)
+            .await
+            .map_err(AppError::from)?
+        {
+            return Err(AppError::Conflict("That notebook already exists!".to_string()).into());
+        }
+        let id = Notebook::create(&mut *transaction, &name, &password)
+            .await
+            .map_err(AppError::from)?;
+        (id, true)
+    };
+
+    let (inserted, duplicates) = merge_texts(&mut *transaction, notebook_id, texts)
+        .await
+        .map_err(AppError::from)?;
+    transaction.commit().await.map_err(AppError::from)?;
+
+    if created {
+        // open the new notebook straight away, like `create_notebook` does, with a fresh session id
+        // so one set before logging in can't be used to ride along
+        session.renew();
+        session
+            .insert("notebook_id", notebook_id)
+            .map_err(AppError::from)?;
+    }
+    let imported = inserted.len();
+    crate::sync::publish_from_context(
+        notebook_id,
+        inserted
+            .into_iter()
+            .map(|text| crate::sync::SyncEvent::TextInserted { text }),
+    );
+    Ok(ImportReport {
+        notebook_id,
+        imported,
+        skipped,
+        duplicates,
+    })
+}
+
+#[derive(Params, PartialEq, Eq)]
+struct ImportParams {
+    id: Option<i32>,
+}
+
+/// Imports into the notebook in the url if there is one, or into a new notebook otherwise.
+#[component]
+pub fn ImportPage() -> impl IntoView {
+    let params = use_params::<ImportParams>();
+    let notebook_id = move || params.read().as_ref().ok().and_then(|params| params.id);
+    let report = RwSignal::new(None::<Result<ImportReport, String>>);
+    let importing = RwSignal::new(false);
+
+    let submit = move |ev: SubmitEvent| {
+        ev.prevent_default();
+        let form = ev
+            .target()
+            .expect("submit events should have a target")
+            .unchecked_into::<HtmlFormElement>();
+        let form_data = FormData::new_with_form(&form).expect("should be able to read form data");
+        importing.set(true);
+        spawn_local(async move {
+            let result = import_notes(form_data.into()).await;
+            log!("import finished: {:#?}", &result);
+            report.set(Some(result.map_err(|e| error_message(&e))));
+            importing.set(false);
+        });
+    };
+
+    let target_fields = move || match notebook_id() {
+        Some(id) => Either::Left(view! {
+            <input type="hidden" name="notebook_id" value=id.to_string() />
+        }),
+        None => Either::Right(view! {
+            <input type="text" name="notebook_name" placeholder="Notebook Name..." required />
+            <br />
+            <input type="password" name="notebook_password" placeholder="Notebook Password..." required />
+            <br />
+        }),
+    };
+
+    let report_view = move || {
+        report.get().map(|report| match report {
+            Ok(report) => Either::Left(view! {
+                <div class="import-report">
+                    <p> {format!("Imported {} texts.", report.imported)} </p>
+                    <Show when={let empty = report.duplicates.is_empty(); move || !empty}>
+                        <h3> "Skipped as duplicates" </h3>
+                        <ul>
+                            {report.duplicates.iter().map(|path| view! { <li> {path.clone()} </li> }).collect_view()}
+                        </ul>
+                    </Show>
+                    <Show when={let empty = report.skipped.is_empty(); move || !empty}>
+                        <h3> "Skipped" </h3>
+                        <ul>
+                            {report.skipped.iter().map(|skipped| view! {
+                                <li> {format!("{} ({})", skipped.path, skipped.reason)} </li>
+                            }).collect_view()}
+                        </ul>
+                    </Show>
+                    <a href=format!("/notebook/{}", report.notebook_id)> "Open notebook" </a>
+                </div>
+            }),
+            Err(e) => Either::Right(view! { <p class="error-message"> {e} </p> }),
+        })
+    };
+
+    view! {
+        <div id="notebook-page">
+            <form on:submit=submit>
+                <h1> {move || if notebook_id().is_some() { "Import into this notebook" } else { "Import into a new notebook" }} </h1>
+                <p> "Upload a zip of markdown files (an Obsidian vault works too), or a JSON notebook dump." </p>
+                {target_fields}
+                <input type="file" name="file" accept=".zip,.json" required />
+                <br />
+                <button type="submit" disabled=move || importing.get()>
+                    {move || if importing.get() { "Importing..." } else { "Import" }}
+                </button>
+            </form>
+            {report_view}
+        </div>
+    }
+}
+
+#[cfg(all(test, feature = "ssr"))]
+mod tests {
+    use std::io::Write;
+
+    use super::{read_upload, ImportedText, SkippedFile};
+
+    fn zip_of(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut archive = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for (path, contents) in files {
+            archive
+                .start_file(*path, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            archive.write_all(contents).unwrap();
+        }
+        archive.finish().unwrap().into_inner()
+    }
+
+    fn sources(texts: &[ImportedText]) -> Vec<&str> {
+        texts.iter().map(|text| text.source.as_str()).collect()
+    }
+
+    fn reasons(skipped: &[SkippedFile]) -> Vec<(&str, &str)> {
+        skipped
+            .iter()
+            .map(|file| (file.path.as_str(), file.reason.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn reads_a_json_dump_of_one_notebook_or_several() {
+        let one = br#"{"id": 1, "name": "ideas", "texts": [
+            {"id": 1, "text": "buy milk"},
+            {"id": 2, "text": "  "}
+        ]}"#;
+        let (texts, skipped) = read_upload("ideas.JSON", one.to_vec()).unwrap();
+        assert_eq!(sources(&texts), ["ideas#1"]);
+        assert_eq!(texts[0].text, "buy milk");
+        assert_eq!(reasons(&skipped), [("ideas#2", "empty")]);
+
+        let many = br#"[
+            {"id": 1, "name": "ideas", "texts": [{"id": 1, "text": "buy milk"}]},
+            {"id": 2, "name": "todo"}
+        ]"#;
+        let (texts, skipped) = read_upload("dump.json", many.to_vec()).unwrap();
+        assert_eq!(sources(&texts), ["ideas#1"]);
+        assert!(skipped.is_empty());
+
+        assert!(read_upload("dump.json", b"not json".to_vec()).is_err());
+    }
+
+    #[test]
+    fn reads_markdown_from_nested_folders_in_a_zip() {
+        let upload = zip_of(&[
+            ("vault/z.md", &b"last"[..]),
+            ("vault/daily/2024-01-01.md", &b"first"[..]),
+            ("vault/.obsidian/workspace.md", &b"settings"[..]),
+            ("__MACOSX/vault/._z.md", &b"resource fork"[..]),
+            ("vault/picture.png", &b"\x89PNG"[..]),
+            ("vault/blank.txt", &b"\n"[..]),
+        ]);
+        let (texts, skipped) = read_upload("vault.zip", upload).unwrap();
+        assert_eq!(sources(&texts), ["vault/daily/2024-01-01.md", "vault/z.md"]);
+        assert_eq!(texts[0].text, "first");
+        assert_eq!(
+            reasons(&skipped),
+            [
+                ("vault/picture.png", "not a markdown file"),
+                ("vault/blank.txt", "empty")
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_files_which_would_escape_the_zip() {
+        let upload = zip_of(&[("../escaped.md", &b"sneaky"[..]), ("note.md", &b"fine"[..])]);
+        let (texts, skipped) = read_upload("notes.zip", upload).unwrap();
+        assert_eq!(sources(&texts), ["note.md"]);
+        assert_eq!(reasons(&skipped), [("../escaped.md", "unsafe path")]);
+    }
+
+    #[test]
+    fn skips_files_which_arent_utf8() {
+        let upload = zip_of(&[("latin1.md", &b"caf\xe9"[..]), ("note.md", &b"fine"[..])]);
+        let (texts, skipped) = read_upload("notes.zip", upload).unwrap();
+        assert_eq!(sources(&texts), ["note.md"]);
+        assert_eq!(reasons(&skipped), [("latin1.md", "not valid UTF-8")]);
+    }
+
+    #[test]
+    fn refuses_something_which_isnt_a_zip() {
+        assert!(read_upload("notes.zip", b"not a zip".to_vec()).is_err());
+    }
+}
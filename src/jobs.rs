@@ -0,0 +1,297 @@
+//! Background jobs, queued in the `jobs` table and run by a pool of workers which `main` spawns.
+//!
+//! A worker takes a job by locking it for `jobs.visibility_timeout_secs`, so if the server dies while
+//! running it, another worker runs it again once the lock runs out. A job which fails is retried with
+//! exponential backoff, up to `jobs.max_attempts` times, then kept with its error until an admin retries it
+//! with `note-to-self retry-job`. Every server polls the same table, so each job runs on just one of them.
+
+use std::{sync::Arc, time::Duration};
+
+use actix_web::rt::task::JoinHandle;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tracing::Instrument;
+
+use crate::config::Config;
+
+/// How often an idle worker looks for new jobs.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// The wait before the first retry, which doubles each time after that.
+const BACKOFF_BASE_SECS: f64 = 30.0;
+const MAX_BACKOFF_SECS: f64 = 6.0 * 60.0 * 60.0;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum Job {
+    /// What `note-to-self purge-trash` does, queued every `jobs.purge_trash_every_secs`.
+    PurgeTrash,
+}
+impl Job {
+    async fn run(&self, pool: &sqlx::Pool<sqlx::Postgres>, config: &Config) -> anyhow::Result<()> {
+        match self {
+            Self::PurgeTrash => {
+                let purged = crate::admin::purge_trash(pool, config).await?;
+                tracing::info!(
+                    sessions = purged.sessions,
+                    share_links = purged.share_links,
+                    login_throttles = purged.login_throttles,
+                    "purged trash"
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Queues a job to run as soon as a worker is free. With a `unique_key`, it isn't queued if there's
+/// already a job with the same key waiting, running or being retried.
+pub async fn enqueue<'c, E: sqlx::PgExecutor<'c>>(
+    executor: E,
+    job: &Job,
+    unique_key: Option<&str>,
+    config: &Config,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO jobs (job, unique_key, max_attempts) VALUES ($1, $2, $3)
+        ON CONFLICT (unique_key) WHERE failed_at IS NULL DO NOTHING",
+    )
+    .bind(sqlx::types::Json(job))
+    .bind(unique_key)
+    .bind(config.jobs.max_attempts as i32)
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// The running workers, and the scheduler which queues recurring jobs.
+pub struct Workers {
+    stop: watch::Sender<bool>,
+    tasks: Vec<JoinHandle<()>>,
+}
+impl Workers {
+    pub fn spawn(pool: sqlx::Pool<sqlx::Postgres>, config: Arc<Config>) -> Self {
+        let (stop, stopped) = watch::channel(false);
+        let mut tasks = Vec::new();
+        for worker in 0..config.jobs.workers {
+            tasks.push(actix_web::rt::spawn(
+                work(pool.clone(), config.clone(), stopped.clone())
+                    .instrument(tracing::info_span!("job_worker", worker)),
+            ));
+        }
+        if config.jobs.purge_trash_every_secs > 0 {
+            tasks.push(actix_web::rt::spawn(schedule(pool, config, stopped)));
+        }
+        Self { stop, tasks }
+    }
+
+    /// Lets the jobs that are running finish, for up to `deadline`, and stops the workers taking any more.
+    /// A job that doesn't finish in time is aborted, and run again later by this server or another one.
+    pub async fn stop(mut self, deadline: Duration) {
+        let _ = self.stop.send(true);
+        if tokio::time::timeout(deadline, join_all(&mut self.tasks))
+            .await
+            .is_err()
+        {
+            tracing::warn!(
+                "jobs were still running after the shutdown deadline, so they've been aborted and will be retried"
+            );
+            // otherwise they'd carry on against a pool that's about to be closed
+            for task in &self.tasks {
+                task.abort();
+            }
+        }
+    }
+}
+
+async fn join_all(tasks: &mut [JoinHandle<()>]) {
+    for task in tasks {
+        let _ = task.await;
+    }
+}
+
+async fn work(
+    pool: sqlx::Pool<sqlx::Postgres>,
+    config: Arc<Config>,
+    mut stopped: watch::Receiver<bool>,
+) {
+    while !*stopped.borrow() {
+        match take_job(&pool, &config).await {
+            Ok(Some(taken)) => {
+                let span = tracing::info_span!("job", id = taken.id, attempt = taken.attempts);
+                run(&pool, &config, taken).instrument(span).await
+            }
+            Ok(None) => {
+                tokio::select! {
+                    _ = tokio::time::sleep(POLL_INTERVAL) => (),
+                    _ = stopped.changed() => (),
+                }
+            }
+            Err(e) => {
+                tracing::error!("couldn't take a job: {e}");
+                tokio::select! {
+                    _ = tokio::time::sleep(POLL_INTERVAL) => (),
+                    _ = stopped.changed() => (),
+                }
+            }
+        }
+    }
+}
+
+/// Queues the recurring jobs. Every server does it, but the unique key means there's only ever one queued.
+async fn schedule(
+    pool: sqlx::Pool<sqlx::Postgres>,
+    config: Arc<Config>,
+    mut stopped: watch::Receiver<bool>,
+) {
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(config.jobs.purge_trash_every_secs));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => (),
+            _ = stopped.changed() => return,
+        }
+        if let Err(e) = enqueue(&pool, &Job::PurgeTrash, Some("purge-trash"), &config).await {
+            tracing::error!("couldn't queue purging the trash: {e}");
+        }
+    }
+}
+
+struct TakenJob {
+    id: i64,
+    job: serde_json::Value,
+    attempts: i32,
+    max_attempts: i32,
+}
+
+/// Locks the job that's been due the longest, skipping any another worker is taking at the same time.
+async fn take_job(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    config: &Config,
+) -> Result<Option<TakenJob>, sqlx::Error> {
+    let taken: Option<(i64, serde_json::Value, i32, i32)> = sqlx::query_as(
+        "UPDATE jobs
+        SET attempts = attempts + 1, locked_until = now() + make_interval(secs => $1)
+        WHERE id = (
+            SELECT id FROM jobs
+            WHERE failed_at IS NULL AND run_at <= now() AND (locked_until IS NULL OR locked_until < now())
+            ORDER BY run_at, id
+            LIMIT 1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING id, job, attempts, max_attempts",
+    )
+    .bind(config.jobs.visibility_timeout_secs as f64)
+    .fetch_optional(pool)
+    .await?;
+    Ok(taken.map(|(id, job, attempts, max_attempts)| TakenJob {
+        id,
+        job,
+        attempts,
+        max_attempts,
+    }))
+}
+
+/// How it went is only recorded if no other worker has taken the job since, which they can once its
+/// lock runs out, so a slow worker can't delete or reschedule a job another one is running.
+async fn run(pool: &sqlx::Pool<sqlx::Postgres>, config: &Config, taken: TakenJob) {
+    let finished = match perform(pool, config, &taken.job).await {
+        Ok(()) => {
+            sqlx::query("DELETE FROM jobs WHERE id = $1 AND attempts = $2")
+                .bind(taken.id)
+                .bind(taken.attempts)
+                .execute(pool)
+                .await
+        }
+        Err(e) if taken.attempts >= taken.max_attempts => {
+            tracing::error!("job failed for the last time: {e:#}");
+            sqlx::query(
+                "UPDATE jobs SET failed_at = now(), locked_until = NULL, last_error = $3
+                WHERE id = $1 AND attempts = $2",
+            )
+            .bind(taken.id)
+            .bind(taken.attempts)
+            .bind(format!("{e:#}"))
+            .execute(pool)
+            .await
+        }
+        Err(e) => {
+            let backoff = (BACKOFF_BASE_SECS * 2f64.powi(taken.attempts - 1)).min(MAX_BACKOFF_SECS);
+            tracing::warn!("job failed, retrying in {backoff}s: {e:#}");
+            sqlx::query(
+                "UPDATE jobs
+                SET run_at = now() + make_interval(secs => $3), locked_until = NULL, last_error = $4
+                WHERE id = $1 AND attempts = $2",
+            )
+            .bind(taken.id)
+            .bind(taken.attempts)
+            .bind(backoff)
+            .bind(format!("{e:#}"))
+            .execute(pool)
+            .await
+        }
+    };
+    match finished {
+        Ok(finished) if finished.rows_affected() == 0 => tracing::warn!(
+            "the job's lock ran out while it was running, so another worker has taken it"
+        ),
+        Ok(_) => (),
+        // it'll be run again once its lock runs out
+        Err(e) => tracing::error!("couldn't record how the job went: {e}"),
+    }
+}
+
+async fn perform(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    config: &Config,
+    job: &serde_json::Value,
+) -> anyhow::Result<()> {
+    // a job this version of the server doesn't know about fails like any other, so it can be retried by one that does
+    let job: Job = serde_json::from_value(job.clone())?;
+    tracing::info!(?job, "running job");
+    job.run(pool, config).await
+}
+
+/// A job which has failed too many times, for `note-to-self failed-jobs`.
+pub struct FailedJob {
+    pub id: i64,
+    pub job: serde_json::Value,
+    pub attempts: i32,
+    pub failed_at: String,
+    pub last_error: Option<String>,
+}
+
+pub async fn failed_jobs(pool: &sqlx::Pool<sqlx::Postgres>) -> Result<Vec<FailedJob>, sqlx::Error> {
+    let jobs: Vec<(i64, serde_json::Value, i32, String, Option<String>)> = sqlx::query_as(
+        "SELECT id, job, attempts, to_char(failed_at, 'YYYY-MM-DD HH24:MI:SS TZ'), last_error
+        FROM jobs WHERE failed_at IS NOT NULL ORDER BY failed_at",
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(jobs
+        .into_iter()
+        .map(|(id, job, attempts, failed_at, last_error)| FailedJob {
+            id,
+            job,
+            attempts,
+            failed_at,
+            last_error,
+        })
+        .collect())
+}
+
+/// Queues a failed job to run again, with its attempts starting over. False if there's no failed job with that id.
+/// A failed recurring job can't be retried while another with its key is queued, which would do the same thing.
+pub async fn retry(pool: &sqlx::Pool<sqlx::Postgres>, id: i64) -> Result<bool, sqlx::Error> {
+    let retried = sqlx::query(
+        "UPDATE jobs SET failed_at = NULL, attempts = 0, run_at = now(), last_error = NULL
+        WHERE id = $1 AND failed_at IS NOT NULL
+        AND (unique_key IS NULL OR NOT EXISTS (
+            SELECT 1 FROM jobs queued WHERE queued.unique_key = jobs.unique_key AND queued.failed_at IS NULL
+        ))",
+    )
+    .bind(id)
+    .execute(pool)
+    .await?
+    .rows_affected();
+    Ok(retried > 0)
+}
@@ -1,10 +1,50 @@
+mod accounts;
+#[cfg(feature = "ssr")]
+pub mod admin;
 pub mod app;
-mod notebook;
+#[cfg(feature = "ssr")]
+pub mod auth;
+#[cfg(feature = "ssr")]
+mod backup;
+#[cfg(feature = "ssr")]
+pub mod caching;
+mod cells;
+#[cfg(feature = "ssr")]
+pub mod config;
+mod encryption;
+pub mod error;
+#[cfg(feature = "ssr")]
+pub mod health;
+mod home;
+mod import;
+#[cfg(feature = "ssr")]
+pub mod jobs;
+#[cfg(feature = "ssr")]
+pub mod metrics;
+pub mod notebook;
+#[cfg(feature = "ssr")]
+pub mod rest;
+#[cfg(feature = "ssr")]
+pub mod security;
+pub mod sessions;
+mod settings;
+mod share_links;
+pub mod sharing;
+#[cfg(feature = "ssr")]
+pub mod shutdown;
+pub mod sync;
+pub mod telemetry;
+#[cfg(feature = "ssr")]
+mod throttle;
+mod tokens;
+mod two_factor;
 
 #[cfg(feature = "ssr")]
 #[derive(Clone)]
 pub struct AppState {
     pub pool: sqlx::Pool<sqlx::Postgres>,
+    pub sync: sync::SyncHub,
+    pub config: std::sync::Arc<config::Config>,
 }
 
 #[cfg(feature = "hydrate")]
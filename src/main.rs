@@ -1,44 +1,89 @@
+/// Serves the app, or runs an admin command. See `note_to_self::config` for what can be configured.
+#[cfg(feature = "ssr")]
+#[derive(clap::Parser)]
+#[command(name = "note-to-self", version)]
+struct Cli {
+    #[command(flatten)]
+    config: note_to_self::config::ConfigArgs,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[cfg(feature = "ssr")]
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Run the web server, which is what happens without a subcommand
+    Serve,
+    #[command(flatten)]
+    Admin(note_to_self::admin::AdminCommand),
+}
+
 #[cfg(feature = "ssr")]
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     use actix_files::Files;
-    use actix_session::{storage::CookieSessionStore, SessionMiddleware};
-    use actix_web::*;
+    use actix_web::{dev::Service as _, *};
+    use clap::Parser as _;
     use leptos::config::get_configuration;
     use leptos::prelude::*;
     use leptos_actix::{generate_route_list, LeptosRoutes};
-    use leptos_meta::MetaTags;
-    use note_to_self::app::*;
+    use leptos_meta::{HashedStylesheet, MetaTags};
+    use note_to_self::{app::*, config::Config};
 
-    println!("Getting dotenv");
     dotenv::dotenv().ok();
+    let cli = Cli::parse();
+    let config = match Config::load(&cli.config) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("note-to-self: {e}");
+            std::process::exit(2);
+        }
+    };
+    note_to_self::telemetry::init(&config.logging);
 
     let conf = get_configuration(None).unwrap();
     let addr = conf.leptos_options.site_addr;
-    let database_url = std::env::var("DATABASE_URL").expect("couldn't find database url");
+    let pool = match sqlx::postgres::PgPoolOptions::new()
+        .max_connections(config.database.max_connections)
+        .connect(config.database_url())
+        .await
+    {
+        Ok(pool) => pool,
+        Err(e) => {
+            tracing::error!("couldn't connect to the database: {e}");
+            std::process::exit(1);
+        }
+    };
+    if let Some(Command::Admin(command)) = cli.command {
+        if let Err(e) = note_to_self::admin::run(command, &pool, &config).await {
+            eprintln!("note-to-self: {e:#}");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Err(e) = sqlx::migrate!().run(&pool).await {
+        tracing::error!("couldn't run the database migrations: {e}");
+        std::process::exit(1);
+    }
     let app_state = note_to_self::AppState {
-        pool: sqlx::postgres::PgPoolOptions::new()
-            .max_connections(5)
-            .connect(database_url.as_str())
-            .await
-            .expect("failed to connect to database"),
+        pool,
+        sync: Default::default(),
+        config: std::sync::Arc::new(config),
     };
 
-    let secret_key = actix_web::cookie::Key::from(
-        std::env::var("SECRET_KEY_SESSION_MIDDLEWARE")
-            .expect("should be able to load session secret key")
-            .bytes()
-            .collect::<Vec<_>>()
-            .as_slice(),
-    );
+    let pool = app_state.pool.clone();
+    let sync = app_state.sync.clone();
+    let shutdown_timeout = app_state.config.shutdown.timeout_secs;
+    let workers = note_to_self::jobs::Workers::spawn(pool.clone(), app_state.config.clone());
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         // Generate the list of routes in your Leptos App
         let routes = generate_route_list(App);
         let leptos_options = &conf.leptos_options;
         let site_root = leptos_options.site_root.clone().to_string();
 
-        println!("listening on http://{}", &addr);
+        tracing::info!("listening on http://{}", &addr);
 
         use leptos::prelude::provide_context;
         use leptos_actix::handle_server_fns_with_context;
@@ -48,15 +93,39 @@ async fn main() -> std::io::Result<()> {
         let app_state_clone_2 = app_state.clone();
         App::new()
             // serve JS/WASM/CSS from `pkg`
-            .service(Files::new("/pkg", format!("{site_root}/pkg")))
+            .service(
+                web::scope("/pkg")
+                    .wrap(note_to_self::caching::pkg_headers(leptos_options.hash_files))
+                    .service(Files::new("", format!("{site_root}/pkg"))),
+            )
             // serve other assets from the `assets` directory
-            .service(Files::new("/assets", &site_root))
+            .service(
+                web::scope("/assets")
+                    .wrap(note_to_self::caching::assets_headers())
+                    .service(Files::new("", &site_root)),
+            )
             // serve the favicon from /favicon.ico
             .service(favicon)
+            .service(note_to_self::metrics::metrics)
+            .service(note_to_self::health::healthz)
+            .service(note_to_self::health::readyz)
+            .configure(note_to_self::rest::configure)
+            .service(note_to_self::sync::notebook_socket)
             .service(web::resource("/api").route(handle_server_fns_with_context(move || provide_context(app_state_clone.clone()))))
-            .leptos_routes_with_context(routes, move || provide_context(app_state_clone_2.clone()), {
+            .leptos_routes_with_context(routes, move || {
+                provide_context(app_state_clone_2.clone());
+                leptos::nonce::provide_nonce();
+            }, {
                 let leptos_options = leptos_options.clone();
+                let developing = matches!(leptos_options.env, leptos::config::Env::DEV);
                 move || {
+                    // the hydration scripts are inline, so the policy has this page's nonce in it
+                    let nonce = leptos::nonce::use_nonce().map(|nonce| nonce.to_string()).unwrap_or_default();
+                    expect_context::<leptos_actix::ResponseOptions>().insert_header(
+                        actix_web::http::header::CONTENT_SECURITY_POLICY,
+                        actix_web::http::header::HeaderValue::from_str(&note_to_self::security::content_security_policy(&nonce, developing))
+                            .expect("the content security policy should be a valid header value"),
+                    );
                     view! {
                         <!DOCTYPE html>
                         <html lang="en">
@@ -65,6 +134,8 @@ async fn main() -> std::io::Result<()> {
                                 <meta name="viewport" content="width=device-width, initial-scale=1"/>
                                 <AutoReload options=leptos_options.clone() />
                                 <HydrationScripts options=leptos_options.clone()/>
+                                // id=leptos means cargo-leptos will hot-reload this stylesheet
+                                <HashedStylesheet options=leptos_options.clone() id="leptos"/>
                                 <MetaTags/>
                             </head>
                             <body>
@@ -76,12 +147,38 @@ async fn main() -> std::io::Result<()> {
             })
             .app_data(web::Data::new(leptos_options.to_owned()))
             .app_data(web::Data::new(app_state.clone()))
-            .wrap(SessionMiddleware::new(CookieSessionStore::default(), secret_key.clone()))
-        //.wrap(middleware::Compress::default())
+            // inside the session middleware, so it can see the session
+            .wrap_fn(|req, srv| {
+                note_to_self::sessions::remember_device(&req);
+                srv.call(req)
+            })
+            .wrap_fn(note_to_self::caching::not_modified)
+            .wrap(note_to_self::sessions::middleware(app_state.pool.clone(), &app_state.config))
+            // outside the session middleware, so a refused request doesn't touch its session
+            .wrap_fn(note_to_self::security::check_origin)
+            .wrap_fn(note_to_self::metrics::time_server_fns)
+            .wrap(note_to_self::security::headers(&app_state.config))
+            .wrap(middleware::Compress::default())
+            // outermost, so everything else happens inside the request's span
+            .wrap(tracing_actix_web::TracingLogger::<note_to_self::telemetry::RequestSpan>::new())
     })
+    .shutdown_timeout(shutdown_timeout)
+    .disable_signals()
     .bind(&addr)?
-    .run()
-    .await
+    .run();
+    let shutdown = actix_web::rt::spawn(note_to_self::shutdown::on_signal(
+        server.handle(),
+        sync,
+        workers,
+        std::time::Duration::from_secs(shutdown_timeout),
+    ));
+    server.await?;
+    // the jobs could still be finishing
+    let _ = shutdown.await;
+
+    pool.close().await;
+    tracing::info!("stopped");
+    Ok(())
 }
 
 #[cfg(feature = "ssr")]
@@ -0,0 +1,198 @@
+//! Prometheus metrics, served at `/metrics`.
+//! Set `metrics.token` to require `Authorization: Bearer <token>` for them, otherwise anyone can read them.
+
+use std::{collections::HashSet, sync::LazyLock, time::Instant};
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse},
+    get,
+    http::header,
+    web, HttpRequest, HttpResponse,
+};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+
+use crate::AppState;
+
+struct Metrics {
+    registry: Registry,
+    server_fn_duration: HistogramVec,
+    pool_connections: IntGauge,
+    pool_idle_connections: IntGauge,
+    pool_max_connections: IntGauge,
+    save_changes: Histogram,
+    logins: IntCounterVec,
+    import_bytes: IntCounter,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
+    let registry = Registry::new_custom(Some("note_to_self".to_string()), None)
+        .expect("the metrics prefix should be valid");
+    let server_fn_duration = HistogramVec::new(
+        HistogramOpts::new(
+            "server_fn_duration_seconds",
+            "How long server functions take to respond.",
+        ),
+        &["function", "status"],
+    )
+    .expect("metric options should be valid");
+    let pool_connections = IntGauge::new(
+        "db_pool_connections",
+        "Connections open in the database pool, busy or idle.",
+    )
+    .expect("metric options should be valid");
+    let pool_idle_connections = IntGauge::new(
+        "db_pool_idle_connections",
+        "Connections in the database pool which aren't being used.",
+    )
+    .expect("metric options should be valid");
+    let pool_max_connections = IntGauge::new(
+        "db_pool_max_connections",
+        "The most connections the database pool will open.",
+    )
+    .expect("metric options should be valid");
+    let save_changes = Histogram::with_opts(
+        HistogramOpts::new(
+            "notebook_save_changes",
+            "How many changes each notebook save has.",
+        )
+        .buckets(vec![
+            1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0,
+        ]),
+    )
+    .expect("metric options should be valid");
+    let logins = IntCounterVec::new(
+        Opts::new(
+            "logins_total",
+            "Login attempts, by what was logged into and whether it worked.",
+        ),
+        &["kind", "outcome"],
+    )
+    .expect("metric options should be valid");
+    let import_bytes = IntCounter::new(
+        "import_upload_bytes_total",
+        "Bytes of files uploaded to be imported.",
+    )
+    .expect("metric options should be valid");
+
+    for collector in [
+        Box::new(server_fn_duration.clone()) as Box<dyn prometheus::core::Collector>,
+        Box::new(pool_connections.clone()),
+        Box::new(pool_idle_connections.clone()),
+        Box::new(pool_max_connections.clone()),
+        Box::new(save_changes.clone()),
+        Box::new(logins.clone()),
+        Box::new(import_bytes.clone()),
+    ] {
+        registry
+            .register(collector)
+            .expect("metrics should only be registered once");
+    }
+    Metrics {
+        registry,
+        server_fn_duration,
+        pool_connections,
+        pool_idle_connections,
+        pool_max_connections,
+        save_changes,
+        logins,
+        import_bytes,
+    }
+});
+
+/// `kind` is what was logged into, e.g. `notebook`, `account` or `second-factor`.
+pub fn record_login(kind: &str, succeeded: bool) {
+    METRICS
+        .logins
+        .with_label_values(&[kind, if succeeded { "success" } else { "failure" }])
+        .inc();
+}
+
+pub fn record_save(changes: usize) {
+    METRICS.save_changes.observe(changes as f64);
+}
+
+pub fn record_import(bytes: usize) {
+    METRICS.import_bytes.inc_by(bytes as u64);
+}
+
+/// The paths server functions are registered at, so only they get a label of their own.
+static SERVER_FN_PATHS: LazyLock<HashSet<&'static str>> = LazyLock::new(|| {
+    leptos::server_fn::actix::server_fn_paths()
+        .map(|(path, _)| path)
+        .collect()
+});
+
+/// Times server functions, which are all posted to `/api/<name>`. Anything else under `/api/` is
+/// timed as `other`, so made up paths can't add labels without end.
+pub fn time_server_fns<S>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl std::future::Future<Output = Result<ServiceResponse, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    let function = req.path().strip_prefix("/api/").map(|function| {
+        if SERVER_FN_PATHS.contains(req.path()) {
+            function.to_string()
+        } else {
+            "other".to_string()
+        }
+    });
+    let started = Instant::now();
+    let response = srv.call(req);
+    async move {
+        let response = response.await;
+        if let Some(function) = function {
+            let status = match &response {
+                Ok(response) => response.status().as_u16().to_string(),
+                Err(e) => e.as_response_error().status_code().as_u16().to_string(),
+            };
+            METRICS
+                .server_fn_duration
+                .with_label_values(&[&function, &status])
+                .observe(started.elapsed().as_secs_f64());
+        }
+        response
+    }
+}
+
+#[get("/metrics")]
+pub async fn metrics(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    if !state.config.metrics.enabled {
+        return HttpResponse::NotFound().finish();
+    }
+    if let Some(token) = &state.config.metrics.token {
+        let authorised = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|given| given == token.as_str());
+        if !authorised {
+            return HttpResponse::Unauthorized().finish();
+        }
+    }
+
+    // the pool is read when it's asked about, rather than kept up to date
+    METRICS.pool_connections.set(state.pool.size().into());
+    METRICS
+        .pool_idle_connections
+        .set(state.pool.num_idle() as i64);
+    METRICS
+        .pool_max_connections
+        .set(state.pool.options().get_max_connections().into());
+
+    let mut body = Vec::new();
+    match TextEncoder::new().encode(&METRICS.registry.gather(), &mut body) {
+        Ok(()) => HttpResponse::Ok()
+            .content_type(TextEncoder::new().format_type())
+            .body(body),
+        Err(e) => {
+            tracing::error!("couldn't encode metrics: {e}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
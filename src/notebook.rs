@@ -3,17 +3,20 @@ use sqlx::Error;
 
 use leptos::server_fn::serde::{Deserialize, Serialize};
 
+use crate::{sync::SyncEvent, telemetry::Redacted};
+
 // database:
 // table notebooks
-// id | name | password_hash
+// id | name | password_hash (bcrypt, or unsalted sha256 from before - see `notebook_password_matches`)
 
 // table texts
-// id | notebook_id | text
+// id | notebook_id | text | position
 
 // this file models and abstracts the database.
 /// This struct seems to have different meanings on the server side
 /// and on the client side. Maybe this should be two different structs?
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "ssr", derive(utoipa::ToSchema))]
 pub struct Notebook {
     id: i32,
     name: String,
@@ -22,6 +25,7 @@ pub struct Notebook {
 }
 #[cfg(feature = "ssr")]
 impl Notebook {
+    #[tracing::instrument(skip_all, fields(notebook_id = id))]
     pub async fn get_from_id(
         pool: &sqlx::Pool<sqlx::Postgres>,
         id: i32,
@@ -31,7 +35,7 @@ impl Notebook {
                 .bind(id)
                 .fetch_optional(pool)
                 .await?;
-        let results: Vec<(i32, String)> = sqlx::query_as("SELECT texts.id, texts.text FROM notebooks JOIN texts ON notebooks.id = texts.notebook_id WHERE notebooks.id=$1").bind(id)
+        let results: Vec<(i32, String)> = sqlx::query_as("SELECT texts.id, texts.text FROM notebooks JOIN texts ON notebooks.id = texts.notebook_id WHERE notebooks.id=$1 ORDER BY texts.position, texts.id").bind(id)
             .fetch_all(pool).await?;
         Ok(if let Some((notebook_name,)) = notebook_name {
             Some(Self {
@@ -47,53 +51,214 @@ impl Notebook {
         })
     }
 
-    pub async fn save(&self, pool: &sqlx::Pool<sqlx::Postgres>) -> Result<(), Error> {
-        println!("Hi there!");
-        let _: Option<()> = sqlx::query_as("INSERT INTO notebooks (id, name) VALUES ($1, $2) ON CONFLICT (id) DO UPDATE SET name = EXCLUDED.name")
-            .bind(self.id).bind(&self.name)
-            .fetch_optional(pool).await?;
+    /// Puts a notebook's texts in the order of `ids`. Texts which aren't in it, like ones added on
+    /// another device at the same time, go after them.
+    #[tracing::instrument(skip_all, fields(notebook_id = id))]
+    pub async fn reorder(
+        executor: impl sqlx::PgExecutor<'_>,
+        id: i32,
+        ids: &[i32],
+    ) -> Result<(), Error> {
+        sqlx::query(
+            "UPDATE texts SET position = COALESCE(
+                (SELECT ordinality::int FROM UNNEST($2::int[]) WITH ORDINALITY AS new_order (id, ordinality)
+                WHERE new_order.id = texts.id),
+                cardinality($2::int[]) + position
+            )
+            WHERE notebook_id = $1",
+        )
+        .bind(id)
+        .bind(ids)
+        .execute(executor)
+        .await?;
+        Ok(())
+    }
 
-        let values = self
-            .texts()
-            .enumerate()
-            .map(|(i, _)| {
-                format!(
-                    "(${left}, $1, ${right})",
-                    left = i * 2 + 1 + 1,
-                    right = i * 2 + 2 + 1
-                )
-            })
-            .reduce(|acc, val| acc + ", " + val.as_str());
-        if let Some(values) = values {
-            let query_text = format!("INSERT INTO texts (id, notebook_id, text) VALUES {values} ON CONFLICT (id) DO UPDATE SET text = EXCLUDED.text");
-            dbg!(&query_text);
-            let mut query = sqlx::query_as(&query_text).bind(self.id);
-            for text in self.texts() {
-                query = query.bind(text.id).bind(text.text.as_str());
-            }
-            let _: Option<()> = query.fetch_optional(pool).await?;
+    /// Checks whether a notebook with this name exists, ignoring case.
+    #[tracing::instrument(skip_all)]
+    pub async fn name_taken(
+        executor: impl sqlx::PgExecutor<'_>,
+        name: &str,
+    ) -> Result<bool, Error> {
+        let existing: Option<(i32,)> =
+            sqlx::query_as("SELECT id FROM notebooks WHERE UPPER(name) = UPPER($1)")
+                .bind(name)
+                .fetch_optional(executor)
+                .await?;
+        Ok(existing.is_some())
+    }
+
+    /// Renames a notebook, unless another notebook already has the name, ignoring case.
+    /// Returns whether it was renamed.
+    #[tracing::instrument(skip_all, fields(notebook_id = id))]
+    pub async fn rename(
+        executor: impl sqlx::PgExecutor<'_>,
+        id: i32,
+        name: &str,
+    ) -> Result<bool, Error> {
+        let result = sqlx::query("UPDATE notebooks SET name = $2 WHERE id = $1")
+            .bind(id)
+            .bind(name)
+            .execute(executor)
+            .await;
+        match result {
+            Ok(result) => Ok(result.rows_affected() > 0),
+            // `notebooks_name` keeps names unique
+            Err(Error::Database(e)) if e.is_unique_violation() => Ok(false),
+            Err(e) => Err(e),
         }
+    }
 
-        let ids_to_keep = self
-            .texts()
-            .map(|t| t.id.to_string())
-            .reduce(|acc, val| acc + ", " + val.as_str());
-        let _: Option<()> = if let Some(ids_to_keep) = ids_to_keep {
-            let query_text =
-                format!("DELETE FROM texts WHERE id NOT IN ({ids_to_keep}) AND notebook_id = $1");
-            sqlx::query_as(&query_text)
-                .bind(self.id)
-                .fetch_optional(pool)
-                .await?
-        } else {
-            let query_text = "DELETE FROM texts WHERE notebook_id = $1".to_string();
-            sqlx::query_as(&query_text)
-                .bind(self.id)
+    /// Changes a notebook's password, as long as `old_password` is its current one.
+    /// Returns whether it was changed.
+    #[tracing::instrument(skip_all, fields(notebook_id = id))]
+    pub async fn change_password(
+        executor: impl sqlx::PgExecutor<'_>,
+        id: i32,
+        old_password: &str,
+        new_password: &str,
+    ) -> Result<bool, Error> {
+        let result = sqlx::query(
+            "UPDATE notebooks SET password_hash = crypt($3, gen_salt('bf'))
+            WHERE id = $1 AND notebook_password_matches(password_hash, $2)",
+        )
+        .bind(id)
+        .bind(old_password)
+        .bind(new_password)
+        .execute(executor)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Sets a notebook's password without knowing the old one, for when it's been forgotten.
+    #[tracing::instrument(skip_all, fields(notebook_id = id))]
+    pub async fn reset_password(
+        executor: impl sqlx::PgExecutor<'_>,
+        id: i32,
+        password: &str,
+    ) -> Result<(), Error> {
+        sqlx::query("UPDATE notebooks SET password_hash = crypt($2, gen_salt('bf')) WHERE id = $1")
+            .bind(id)
+            .bind(password)
+            .execute(executor)
+            .await?;
+        Ok(())
+    }
+
+    /// Finds the notebook with this name, ignoring case, if `password` is its password.
+    /// It takes as long whether or not there's a notebook by that name, so it doesn't give that away.
+    /// A password still hashed the old way is hashed again with bcrypt, now it's known.
+    #[tracing::instrument(skip_all)]
+    pub async fn log_in(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        name: &str,
+        password: &str,
+    ) -> Result<Option<i32>, Error> {
+        let notebook: Option<(i32, String)> =
+            sqlx::query_as("SELECT id, password_hash FROM notebooks WHERE UPPER(name) = UPPER($1)")
+                .bind(name)
                 .fetch_optional(pool)
+                .await?;
+        let matches = match &notebook {
+            Some((_, hash)) if hash.starts_with("sha256:") => {
+                // bcrypt all the same, so these take as long to check as any other
+                crate::throttle::password_matches(pool, password, None).await?;
+                let (matches,): (bool,) =
+                    sqlx::query_as("SELECT notebook_password_matches($1, $2)")
+                        .bind(hash)
+                        .bind(password)
+                        .fetch_one(pool)
+                        .await?;
+                matches
+            }
+            _ => {
+                crate::throttle::password_matches(
+                    pool,
+                    password,
+                    notebook.as_ref().map(|(_, hash)| hash.as_str()),
+                )
                 .await?
+            }
         };
+        let Some((id, _)) = notebook.filter(|_| matches) else {
+            return Ok(None);
+        };
+        sqlx::query(
+            "UPDATE notebooks SET password_hash = crypt($2, gen_salt('bf'))
+            WHERE id = $1 AND password_hash LIKE 'sha256:%'",
+        )
+        .bind(id)
+        .bind(password)
+        .execute(pool)
+        .await?;
+        Ok(Some(id))
+    }
+
+    /// Finds a notebook's id from its name, ignoring case.
+    #[tracing::instrument(skip_all)]
+    pub async fn id_from_name(
+        executor: impl sqlx::PgExecutor<'_>,
+        name: &str,
+    ) -> Result<Option<i32>, Error> {
+        let id: Option<(i32,)> =
+            sqlx::query_as("SELECT id FROM notebooks WHERE UPPER(name) = UPPER($1)")
+                .bind(name)
+                .fetch_optional(executor)
+                .await?;
+        Ok(id.map(|(id,)| id))
+    }
+
+    /// Deletes a notebook along with everything in it.
+    #[tracing::instrument(skip_all, fields(notebook_id = id))]
+    pub async fn delete(executor: impl sqlx::PgExecutor<'_>, id: i32) -> Result<(), Error> {
+        sqlx::query("DELETE FROM notebooks WHERE id = $1")
+            .bind(id)
+            .execute(executor)
+            .await?;
         Ok(())
     }
+
+    /// Creates an empty notebook, returning its id.
+    /// Doesn't check whether the name is taken - see [`Notebook::name_taken`].
+    #[tracing::instrument(skip_all)]
+    pub async fn create(
+        executor: impl sqlx::PgExecutor<'_>,
+        name: &str,
+        password: &str,
+    ) -> Result<i32, Error> {
+        let (id,): (i32,) = sqlx::query_as("INSERT INTO notebooks (name, password_hash) VALUES ($1, crypt($2, gen_salt('bf'))) RETURNING id")
+            .bind(name)
+            .bind(password)
+            .fetch_one(executor)
+            .await?;
+        Ok(id)
+    }
+
+    /// Appends new texts to the end of a notebook, letting the database pick their ids.
+    #[tracing::instrument(skip_all, fields(notebook_id = notebook_id, texts = texts.len()))]
+    pub async fn insert_texts(
+        executor: impl sqlx::PgExecutor<'_>,
+        notebook_id: i32,
+        texts: &[String],
+    ) -> Result<Vec<TextFile>, Error> {
+        if texts.is_empty() {
+            return Ok(Vec::new());
+        }
+        let results: Vec<(i32, String)> = sqlx::query_as(
+"INSERT INTO texts (notebook_id, text, position)
+            SELECT $1, text, (SELECT COALESCE(MAX(position), 0) FROM texts WHERE notebook_id = $1) + ordinality::int
+            FROM UNNEST($2::text[]) WITH ORDINALITY AS new_texts (text, ordinality)
+            RETURNING id, text",
+        )
+        .bind(notebook_id)
+        .bind(texts)
+        .fetch_all(executor)
+        .await?;
+        Ok(results
+            .into_iter()
+            .map(|(id, text)| TextFile { id, text })
+            .collect())
+    }
 }
 impl Notebook {
     pub fn add_new_text(&mut self, text: TextFile) {
@@ -101,12 +266,98 @@ impl Notebook {
     }
 
     pub fn set_text(&mut self, id: i32, text: String) {
-        leptos::logging::log!("setting id {id} to '{text}' for notebook: {:#?}", &self);
         if let Some(text_file) = self.texts.iter_mut().find(|t| t.id == id) {
             text_file.text = text;
         }
     }
 
+    /// Moves a text up (negative) or down (positive) the notebook, stopping at either end.
+    pub fn move_text(&mut self, id: i32, by: isize) {
+        if let Some(i) = self.texts.iter().position(|t| t.id == id) {
+            let new_i = i.saturating_add_signed(by).min(self.texts.len() - 1);
+            let text = self.texts.remove(i);
+            self.texts.insert(new_i, text);
+        }
+    }
+
+    /// Applies a change made on another device.
+    /// Changes which have already been applied here are ignored.
+    pub fn apply(&mut self, event: SyncEvent) {
+        match event {
+            SyncEvent::TextInserted { text } => {
+                if !self.texts.iter().any(|t| t.id == text.id) {
+                    self.texts.push(text);
+                }
+            }
+            SyncEvent::TextUpdated { text } => self.set_text(text.id, text.text),
+            SyncEvent::TextDeleted { id } => self.delete_text(id),
+            SyncEvent::TextsReordered { ids } => self
+                .texts
+                .sort_by_key(|t| ids.iter().position(|id| *id == t.id).unwrap_or(usize::MAX)),
+            SyncEvent::NotebookRenamed { name } => self.name = name,
+            // these are for whoever is editing a cell, and for the connection itself
+            SyncEvent::Resync
+            | SyncEvent::NotebookDeleted
+            | SyncEvent::Welcome { .. }
+            | SyncEvent::CellUpdate { .. }
+            | SyncEvent::Cursor { .. } => (),
+        }
+    }
+
+    /// The events which would turn `previous` into this notebook.
+    pub fn changes_since(&self, previous: &Notebook) -> Vec<SyncEvent> {
+        let mut events = Vec::new();
+        for text in self.texts() {
+            match previous.texts().find(|t| t.id == text.id) {
+                None => events.push(SyncEvent::TextInserted { text: text.clone() }),
+                Some(old) if old.text != text.text => {
+                    events.push(SyncEvent::TextUpdated { text: text.clone() })
+                }
+                Some(_) => (),
+            }
+        }
+        for text in previous.texts() {
+            if !self.texts.iter().any(|t| t.id == text.id) {
+                events.push(SyncEvent::TextDeleted { id: text.id });
+            }
+        }
+        // only texts in both notebooks can have been reordered
+        let kept = |a: &Notebook, b: &Notebook| {
+            a.texts()
+                .filter(|t| b.texts.iter().any(|other| other.id == t.id))
+                .map(|t| t.id)
+                .collect::<Vec<_>>()
+        };
+        if kept(self, previous) != kept(previous, self) {
+            events.push(SyncEvent::TextsReordered {
+                ids: self.texts().map(|t| t.id).collect(),
+            });
+        }
+        events
+    }
+
+    /// What's been changed here since `previous`, to be saved.
+    pub fn edits_since(&self, previous: &Notebook) -> Vec<NotebookChange> {
+        self.changes_since(previous)
+            .into_iter()
+            .filter_map(|event| match event {
+                SyncEvent::TextUpdated { text } => Some(NotebookChange::TextEdited {
+                    id: text.id,
+                    base: previous
+                        .texts()
+                        .find(|t| t.id == text.id)
+                        .map(|t| t.text.clone())
+                        .unwrap_or_default(),
+                    text: text.text,
+                }),
+                SyncEvent::TextDeleted { id } => Some(NotebookChange::TextDeleted { id }),
+                SyncEvent::TextsReordered { ids } => Some(NotebookChange::TextsReordered { ids }),
+                // texts are only ever added on the server, which tells everyone about them
+                _ => None,
+            })
+            .collect()
+    }
+
     pub fn delete_text(&mut self, id: i32) {
         if let Some(i) = self
             .texts
@@ -131,18 +382,122 @@ impl Notebook {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// A change made to a notebook on one device, for `save_notebook`.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum NotebookChange {
+    /// `base` is the text the edit was made to, so it isn't saved over anyone else's edit since.
+    TextEdited {
+        id: i32,
+        base: String,
+        text: String,
+    },
+    TextDeleted {
+        id: i32,
+    },
+    /// The ids of every text in the notebook, in their new order.
+    TextsReordered {
+        ids: Vec<i32>,
+    },
+}
+/// Logs don't get to see what the texts say - see [`Redacted`].
+impl std::fmt::Debug for NotebookChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TextEdited { id, base, text } => f
+                .debug_struct("TextEdited")
+                .field("id", id)
+                .field("base", &Redacted(base))
+                .field("text", &Redacted(text))
+                .finish(),
+            Self::TextDeleted { id } => f.debug_struct("TextDeleted").field("id", id).finish(),
+            Self::TextsReordered { ids } => {
+                f.debug_struct("TextsReordered").field("ids", ids).finish()
+            }
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "ssr", derive(utoipa::ToSchema))]
 pub struct TextFile {
     text: String,
     id: i32,
 }
+/// Logs don't get to see what the text says - see [`Redacted`].
+impl std::fmt::Debug for TextFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TextFile")
+            .field("id", &self.id)
+            .field("text", &Redacted(&self.text))
+            .finish()
+    }
+}
 #[cfg(feature = "ssr")]
 impl TextFile {
-    pub fn new(id: i32, text: String) -> Self {
-        Self { id, text }
+    /// Gets one page of a notebook's texts, in order, along with how many texts there are in total.
+    #[tracing::instrument(skip(pool))]
+    pub async fn page(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        notebook_id: i32,
+        offset: i64,
+        limit: i64,
+    ) -> Result<(Vec<Self>, i64), Error> {
+        let (total,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM texts WHERE notebook_id = $1")
+            .bind(notebook_id)
+            .fetch_one(pool)
+            .await?;
+        let results: Vec<(i32, String)> = sqlx::query_as(
+            "SELECT id, text FROM texts WHERE notebook_id = $1 ORDER BY position, id OFFSET $2 LIMIT $3",
+        )
+        .bind(notebook_id)
+        .bind(offset)
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+        Ok((
+            results
+                .into_iter()
+                .map(|(id, text)| Self { id, text })
+                .collect(),
+            total,
+        ))
+    }
+
+    #[tracing::instrument(skip(pool))]
+    pub async fn get(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        notebook_id: i32,
+        id: i32,
+    ) -> Result<Option<Self>, Error> {
+        let result: Option<(i32, String)> =
+            sqlx::query_as("SELECT id, text FROM texts WHERE notebook_id = $1 AND id = $2")
+                .bind(notebook_id)
+                .bind(id)
+                .fetch_optional(pool)
+                .await?;
+        Ok(result.map(|(id, text)| Self { id, text }))
+    }
+
+    /// Returns whether there was a text with that id in the notebook.
+    #[tracing::instrument(skip(pool))]
+    pub async fn delete(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        notebook_id: i32,
+        id: i32,
+    ) -> Result<bool, Error> {
+        let result = sqlx::query("DELETE FROM texts WHERE notebook_id = $1 AND id = $2")
+            .bind(notebook_id)
+            .bind(id)
+            .execute(pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
     }
 }
 impl TextFile {
+    pub fn new(id: i32, text: String) -> Self {
+        Self { id, text }
+    }
+
     pub fn text(&self) -> &str {
         &self.text
     }
@@ -0,0 +1,416 @@
+//! The public JSON API under `/v1`.
+//! Unlike the server functions under `/api`, these routes and their bodies are
+//! meant to stay stable, and are described by the OpenAPI document at `/v1/openapi.json`.
+
+use actix_web::{delete, get, http::StatusCode, post, put, web, HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+
+use crate::{
+    auth::Caller,
+    cells::{self, CellError},
+    encryption::{is_encrypted_for_share, is_opaque},
+    notebook::{Notebook, TextFile},
+    sync::SyncEvent,
+    AppState,
+};
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+#[derive(Debug)]
+pub enum ApiError {
+    Unauthenticated,
+    InvalidToken,
+    Forbidden,
+    NotFound(String),
+    BadRequest(String),
+    Internal,
+}
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unauthenticated => write!(f, "You need to log in to a notebook first!"),
+            Self::InvalidToken => write!(f, "That API token is invalid or has been revoked!"),
+            Self::Forbidden => write!(f, "You don't have access to that notebook!"),
+            Self::NotFound(e) | Self::BadRequest(e) => write!(f, "{e}"),
+            Self::Internal => write!(f, "Something went wrong on our end."),
+        }
+    }
+}
+impl ApiError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::Unauthenticated => "unauthenticated",
+            Self::InvalidToken => "invalid_token",
+            Self::Forbidden => "forbidden",
+            Self::NotFound(_) => "not_found",
+            Self::BadRequest(_) => "bad_request",
+            Self::Internal => "internal",
+        }
+    }
+}
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::Unauthenticated | Self::InvalidToken => StatusCode::UNAUTHORIZED,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorBody {
+            error: ErrorDetail {
+                code: self.code().to_string(),
+                message: self.to_string(),
+            },
+        })
+    }
+}
+impl From<sqlx::Error> for ApiError {
+    fn from(e: sqlx::Error) -> Self {
+        tracing::error!("database error in the json api: {e}");
+        Self::Internal
+    }
+}
+
+impl From<CellError> for ApiError {
+    fn from(e: CellError) -> Self {
+        match e {
+            CellError::Database(e) => e.into(),
+            CellError::InvalidUpdate(e) => {
+                tracing::error!("couldn't edit a cell's document in the json api: {e}");
+                Self::Internal
+            }
+            CellError::Encrypted | CellError::Stale => Self::BadRequest(e.to_string()),
+        }
+    }
+}
+
+/// Every error response has this shape.
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody {
+    error: ErrorDetail,
+}
+#[derive(Serialize, ToSchema)]
+pub struct ErrorDetail {
+    /// One of `unauthenticated`, `invalid_token`, `forbidden`, `not_found`, `bad_request` or `internal`.
+    code: String,
+    message: String,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct Pagination {
+    /// How many texts to skip. Defaults to 0.
+    offset: Option<i64>,
+    /// How many texts to return, at most 200. Defaults to 50.
+    limit: Option<i64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TextPage {
+    items: Vec<TextFile>,
+    /// The number of texts in the notebook, across all pages.
+    total: i64,
+    offset: i64,
+    limit: i64,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct TextInput {
+    text: String,
+}
+
+/// The same rules the server functions use.
+fn authorize_read(caller: &Caller, notebook_id: i32) -> Result<(), ApiError> {
+    match caller {
+        _ if caller.can_read(notebook_id) => Ok(()),
+        Caller::Anonymous => Err(ApiError::Unauthenticated),
+        _ => Err(ApiError::Forbidden),
+    }
+}
+
+fn authorize_write(caller: &Caller, notebook_id: i32) -> Result<(), ApiError> {
+    match caller {
+        _ if caller.can_write(notebook_id) => Ok(()),
+        Caller::Anonymous => Err(ApiError::Unauthenticated),
+        _ => Err(ApiError::Forbidden),
+    }
+}
+
+/// The server can't encrypt texts itself, so scripts have to send encrypted notebooks ciphertext.
+/// Encryption can't be turned on until `executor`'s transaction ends, so the text can be written in it.
+async fn check_encrypted(
+    executor: impl sqlx::PgExecutor<'_>,
+    notebook_id: i32,
+    text: &str,
+) -> Result<(), ApiError> {
+    if is_encrypted_for_share(executor, notebook_id).await? && !is_opaque(text) {
+        Err(ApiError::BadRequest(
+            "This notebook is encrypted, so texts have to be encrypted before they're sent!"
+                .to_string(),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn text_not_found(notebook_id: i32, text_id: i32) -> ApiError {
+    ApiError::NotFound(format!(
+        "Couldn't find a text with id {text_id} in notebook {notebook_id}!"
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/notebooks/{id}",
+    params(("id" = i32, Path, description = "Notebook id")),
+    responses(
+        (status = 200, description = "The notebook, with all of its texts", body = Notebook),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+#[get("/v1/notebooks/{id}")]
+async fn notebook(
+    state: web::Data<AppState>,
+    caller: Caller,
+    id: web::Path<i32>,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    authorize_read(&caller, id)?;
+    let notebook = Notebook::get_from_id(&state.pool, id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("Couldn't find a notebook with id {id}!")))?;
+    Ok(HttpResponse::Ok().json(notebook))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/notebooks/{id}/texts",
+    params(("id" = i32, Path, description = "Notebook id"), Pagination),
+    responses(
+        (status = 200, description = "One page of texts, oldest first", body = TextPage),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+    )
+)]
+#[get("/v1/notebooks/{id}/texts")]
+async fn list_texts(
+    state: web::Data<AppState>,
+    caller: Caller,
+    id: web::Path<i32>,
+    pagination: web::Query<Pagination>,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    authorize_read(&caller, id)?;
+    let offset = pagination.offset.unwrap_or(0);
+    let limit = pagination.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if offset < 0 {
+        return Err(ApiError::BadRequest(
+            "offset can't be negative!".to_string(),
+        ));
+    }
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(ApiError::BadRequest(format!(
+            "limit has to be between 1 and {MAX_PAGE_SIZE}!"
+        )));
+    }
+    let (items, total) = TextFile::page(&state.pool, id, offset, limit).await?;
+    Ok(HttpResponse::Ok().json(TextPage {
+        items,
+        total,
+        offset,
+        limit,
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/notebooks/{id}/texts",
+    params(("id" = i32, Path, description = "Notebook id")),
+    request_body = TextInput,
+    responses(
+        (status = 201, description = "The new text", body = TextFile),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+    )
+)]
+#[post("/v1/notebooks/{id}/texts")]
+async fn create_text(
+    state: web::Data<AppState>,
+    caller: Caller,
+    id: web::Path<i32>,
+    input: web::Json<TextInput>,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    authorize_write(&caller, id)?;
+    let mut transaction = state.pool.begin().await?;
+    check_encrypted(&mut *transaction, id, &input.text).await?;
+    let text = Notebook::insert_texts(&mut *transaction, id, &[input.into_inner().text])
+        .await?
+        .pop()
+        .ok_or(ApiError::Internal)?;
+    transaction.commit().await?;
+    state
+        .sync
+        .publish(id, [SyncEvent::TextInserted { text: text.clone() }]);
+    Ok(HttpResponse::Created().json(text))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/notebooks/{id}/texts/{text_id}",
+    params(
+        ("id" = i32, Path, description = "Notebook id"),
+        ("text_id" = i32, Path, description = "Text id"),
+    ),
+    responses(
+        (status = 200, body = TextFile),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+#[get("/v1/notebooks/{id}/texts/{text_id}")]
+async fn get_text(
+    state: web::Data<AppState>,
+    caller: Caller,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, ApiError> {
+    let (id, text_id) = path.into_inner();
+    authorize_read(&caller, id)?;
+    let text = TextFile::get(&state.pool, id, text_id)
+        .await?
+        .ok_or_else(|| text_not_found(id, text_id))?;
+    Ok(HttpResponse::Ok().json(text))
+}
+
+#[utoipa::path(
+    put,
+    path = "/v1/notebooks/{id}/texts/{text_id}",
+    params(
+        ("id" = i32, Path, description = "Notebook id"),
+        ("text_id" = i32, Path, description = "Text id"),
+    ),
+    request_body = TextInput,
+    responses(
+        (status = 200, description = "The updated text", body = TextFile),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+#[put("/v1/notebooks/{id}/texts/{text_id}")]
+async fn update_text(
+    state: web::Data<AppState>,
+    caller: Caller,
+    path: web::Path<(i32, i32)>,
+    input: web::Json<TextInput>,
+) -> Result<HttpResponse, ApiError> {
+    let (id, text_id) = path.into_inner();
+    authorize_write(&caller, id)?;
+    // `replace_text` checks again as it writes, in case the notebook is encrypted in between
+    check_encrypted(&state.pool, id, &input.text).await?;
+    let (text, update) = cells::replace_text(&state.pool, id, text_id, &input.text)
+        .await?
+        .ok_or_else(|| text_not_found(id, text_id))?;
+    state.sync.publish(
+        id,
+        update
+            .map(|update| SyncEvent::CellUpdate { text_id, update })
+            .into_iter()
+            .chain([SyncEvent::TextUpdated { text: text.clone() }]),
+    );
+    Ok(HttpResponse::Ok().json(text))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/v1/notebooks/{id}/texts/{text_id}",
+    params(
+        ("id" = i32, Path, description = "Notebook id"),
+        ("text_id" = i32, Path, description = "Text id"),
+    ),
+    responses(
+        (status = 204, description = "The text was deleted"),
+        (status = 401, body = ErrorBody),
+        (status = 403, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+#[delete("/v1/notebooks/{id}/texts/{text_id}")]
+async fn delete_text(
+    state: web::Data<AppState>,
+    caller: Caller,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, ApiError> {
+    let (id, text_id) = path.into_inner();
+    authorize_write(&caller, id)?;
+    if TextFile::delete(&state.pool, id, text_id).await? {
+        state
+            .sync
+            .publish(id, [SyncEvent::TextDeleted { id: text_id }]);
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(text_not_found(id, text_id))
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "note-to-self",
+        description = "Read and write the texts in a notebook. Requests are authorised by the web app's session cookie, or by an API token in an `Authorization: Bearer` header."
+    ),
+    paths(notebook, list_texts, create_text, get_text, update_text, delete_text),
+    modifiers(&BearerToken),
+    security(("api_token" = []))
+)]
+pub struct ApiDoc;
+
+struct BearerToken;
+impl utoipa::Modify for BearerToken {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "api_token",
+                SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+            );
+        }
+    }
+}
+
+#[get("/v1/openapi.json")]
+async fn openapi() -> HttpResponse {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+/// Registers every `/v1` route on the app.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    // malformed requests should get json errors too, not actix's plain text ones
+    cfg.app_data(
+        web::JsonConfig::default().error_handler(|e, _| ApiError::BadRequest(e.to_string()).into()),
+    )
+    .app_data(
+        web::PathConfig::default().error_handler(|e, _| ApiError::BadRequest(e.to_string()).into()),
+    )
+    .app_data(
+        web::QueryConfig::default()
+            .error_handler(|e, _| ApiError::BadRequest(e.to_string()).into()),
+    )
+    .service(openapi)
+    .service(notebook)
+    .service(list_texts)
+    .service(create_text)
+    .service(get_text)
+    .service(update_text)
+    .service(delete_text);
+}
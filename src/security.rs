@@ -0,0 +1,227 @@
+//! Security headers, and refusing requests which change things when they come from another site.
+//!
+//! Session cookies are `SameSite=Lax`, which already keeps them off most cross-site requests, but a
+//! cross-site request is still refused if the browser says that's what it is: with an `Origin` that
+//! isn't this server or one of `security.allowed_origins`, or with `Sec-Fetch-Site: cross-site`.
+//! Requests without either, like the ones `nts` makes, aren't from a browser, so they can't be forged.
+
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::{header, Method},
+    middleware::DefaultHeaders,
+    HttpResponse,
+};
+
+use crate::config::Config;
+
+/// The headers every response gets. The content security policy is only for pages, and has a nonce
+/// in it, so it's added by [`content_security_policy`] while they're rendered.
+pub fn headers(config: &Config) -> DefaultHeaders {
+    let mut headers = DefaultHeaders::new()
+        // share links have their token in the url, so it mustn't be passed on to other sites
+        .add((header::REFERRER_POLICY, "no-referrer"))
+        .add((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        .add((header::X_FRAME_OPTIONS, "DENY"));
+    if config.security.hsts_max_age_secs > 0 {
+        headers = headers.add((
+            header::STRICT_TRANSPORT_SECURITY,
+            format!("max-age={}", config.security.hsts_max_age_secs),
+        ));
+    }
+    headers
+}
+
+/// Leptos puts the nonce on the hydration scripts. WebAssembly needs `wasm-unsafe-eval` to be
+/// compiled, and some elements are sized with inline styles. `cargo leptos watch` reloads pages
+/// through a websocket on another port, so that's allowed while developing.
+pub fn content_security_policy(nonce: &str, developing: bool) -> String {
+    format!(
+        "default-src 'self'; \
+        script-src 'self' 'nonce-{nonce}' 'wasm-unsafe-eval'; \
+        style-src 'self' 'unsafe-inline'; \
+        img-src 'self' data:; \
+        media-src 'self' blob:; \
+        connect-src 'self'{}; \
+        object-src 'none'; \
+        base-uri 'self'; \
+        form-action 'self'; \
+        frame-ancestors 'none'",
+        if developing { " ws:" } else { "" }
+    )
+}
+
+/// Refuses cross-site requests which could change something: anything but a `GET`, `HEAD` or `OPTIONS`, and
+/// websockets, which can send edits and are opened with a `GET`.
+pub fn check_origin<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl std::future::Future<Output = Result<ServiceResponse<BoxBody>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody + 'static,
+{
+    let changes_things = !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS)
+        || req
+            .headers()
+            .get(header::UPGRADE)
+            .and_then(|upgrade| upgrade.to_str().ok())
+            .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+    let next = if changes_things && is_cross_site(&req) {
+        tracing::warn!(
+            origin = ?req.headers().get(header::ORIGIN),
+            path = req.path(),
+            "refused a cross-site request"
+        );
+        Err(req
+            .into_response(HttpResponse::Forbidden().body("Cross-site requests aren't allowed.")))
+    } else {
+        Ok(srv.call(req))
+    };
+    async move {
+        match next {
+            Ok(response) => Ok(response.await?.map_into_boxed_body()),
+            Err(refusal) => Ok(refusal),
+        }
+    }
+}
+
+fn is_cross_site(req: &ServiceRequest) -> bool {
+    let headers = req.headers();
+    let allowed_origins = req
+        .app_data::<actix_web::web::Data<crate::AppState>>()
+        .map(|state| state.config.security.allowed_origins.as_slice())
+        .unwrap_or_default();
+    is_cross_site_from(
+        headers
+            .get(header::ORIGIN)
+            .and_then(|origin| origin.to_str().ok()),
+        headers
+            .get("sec-fetch-site")
+            .and_then(|site| site.to_str().ok()),
+        req.connection_info().scheme(),
+        req.connection_info().host(),
+        allowed_origins,
+    )
+}
+
+/// An `Origin` decides it when there is one, and `Sec-Fetch-Site` when there isn't.
+fn is_cross_site_from(
+    origin: Option<&str>,
+    sec_fetch_site: Option<&str>,
+    scheme: &str,
+    host: &str,
+    allowed_origins: &[String],
+) -> bool {
+    if let Some(origin) = origin {
+        // the scheme counts too, or a page on plain http could post to the https site
+        let same_origin = origin
+            .split_once("://")
+            .is_some_and(|(origin_scheme, authority)| {
+                origin_scheme.eq_ignore_ascii_case(scheme) && authority.eq_ignore_ascii_case(host)
+            });
+        let allowed = allowed_origins
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(origin));
+        return !(same_origin || allowed);
+    }
+    sec_fetch_site == Some("cross-site")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_cross_site_from;
+
+    const SCHEME: &str = "https";
+    const HOST: &str = "notes.example.com";
+
+    #[test]
+    fn same_host_is_allowed() {
+        assert!(!is_cross_site_from(
+            Some("https://notes.example.com"),
+            Some("same-origin"),
+            SCHEME,
+            HOST,
+            &[]
+        ));
+        assert!(!is_cross_site_from(
+            Some("https://Notes.Example.com"),
+            None,
+            SCHEME,
+            HOST,
+            &[]
+        ));
+    }
+
+    #[test]
+    fn allowed_origins_are_allowed() {
+        let allowed = ["https://app.example.org".to_string()];
+        assert!(!is_cross_site_from(
+            Some("https://app.example.org"),
+            Some("cross-site"),
+            SCHEME,
+            HOST,
+            &allowed
+        ));
+    }
+
+    #[test]
+    fn foreign_origins_are_refused() {
+        let allowed = ["https://app.example.org".to_string()];
+        assert!(is_cross_site_from(
+            Some("https://evil.example.net"),
+            None,
+            SCHEME,
+            HOST,
+            &allowed
+        ));
+        // the host has to match all of the authority, not just the start of it
+        assert!(is_cross_site_from(
+            Some("https://notes.example.com.evil.example.net"),
+            None,
+            SCHEME,
+            HOST,
+            &[]
+        ));
+        // the same host on plain http is a different origin
+        assert!(is_cross_site_from(
+            Some("http://notes.example.com"),
+            None,
+            SCHEME,
+            HOST,
+            &[]
+        ));
+        assert!(is_cross_site_from(Some("null"), None, SCHEME, HOST, &[]));
+    }
+
+    #[test]
+    fn without_an_origin_sec_fetch_site_decides() {
+        assert!(is_cross_site_from(
+            None,
+            Some("cross-site"),
+            SCHEME,
+            HOST,
+            &[]
+        ));
+        assert!(!is_cross_site_from(
+            None,
+            Some("same-origin"),
+            SCHEME,
+            HOST,
+            &[]
+        ));
+        assert!(!is_cross_site_from(
+            None,
+            Some("same-site"),
+            SCHEME,
+            HOST,
+            &[]
+        ));
+        assert!(!is_cross_site_from(None, Some("none"), SCHEME, HOST, &[]));
+    }
+
+    #[test]
+    fn requests_without_either_are_allowed() {
+        assert!(!is_cross_site_from(None, None, SCHEME, HOST, &[]));
+    }
+}
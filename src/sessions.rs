@@ -0,0 +1,440 @@
+use leptos::{either::Either, prelude::*, task::spawn_local};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{AppError, ErrorMessage};
+
+// browser sessions live in the database rather than in the cookie, so they can expire and be revoked.
+// by default, a session ends after a week without being used, and a month after logging in no matter what.
+
+/// A browser that's logged in as the caller, as shown on the sessions page.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActiveSession {
+    id: i32,
+    device: Option<String>,
+    created_at: String,
+    last_seen_at: String,
+    /// Whether it's the browser asking.
+    current: bool,
+}
+
+#[cfg(feature = "ssr")]
+pub use store::*;
+
+#[cfg(feature = "ssr")]
+mod store {
+    use std::collections::HashMap;
+
+    use actix_session::{
+        config::{CookieContentSecurity, PersistentSession, TtlExtensionPolicy},
+        storage::{LoadError, SaveError, SessionKey, SessionStore, UpdateError},
+        SessionExt, SessionMiddleware,
+    };
+    use actix_web::{cookie::time::Duration, dev::ServiceRequest, http::header};
+
+    use crate::config::Config;
+
+    /// `last_seen_at` is only written this often, rather than on every request.
+    const TOUCH_INTERVAL_SECS: i64 = 60;
+
+    /// Sessions time out, and their cookies are set, as `sessions` and `cookies` in the config say.
+    pub fn middleware(
+        pool: sqlx::Pool<sqlx::Postgres>,
+        config: &Config,
+    ) -> SessionMiddleware<PgSessionStore> {
+        let store = PgSessionStore {
+            pool,
+            absolute_timeout_secs: config.sessions.absolute_timeout_secs,
+        };
+        SessionMiddleware::builder(store, config.session_key())
+            .session_lifecycle(
+                PersistentSession::default()
+                    .session_ttl(Duration::seconds(config.sessions.idle_timeout_secs))
+                    .session_ttl_extension_policy(TtlExtensionPolicy::OnEveryRequest),
+            )
+            .cookie_path("/".to_string())
+            .cookie_secure(config.cookies.secure)
+            .cookie_same_site(config.cookies.same_site.same_site())
+            // scripts never need it, so one that got onto a page couldn't steal it
+            .cookie_http_only(true)
+            // encrypted as well as signed, though it only holds the session's key
+            .cookie_content_security(CookieContentSecurity::Private)
+            .build()
+    }
+
+    /// Logs every session out of a notebook, apart from `except`.
+    /// Sessions which are also logged into an account stay logged into it.
+    pub async fn forget_notebook(
+        executor: impl sqlx::PgExecutor<'_>,
+        notebook_id: i32,
+        except: Option<i32>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "WITH kept AS (
+                UPDATE sessions SET notebook_id = NULL, state = state - 'notebook_id'
+                WHERE notebook_id = $1 AND user_id IS NOT NULL AND id IS DISTINCT FROM $2
+            )
+            DELETE FROM sessions WHERE notebook_id = $1 AND user_id IS NULL AND id IS DISTINCT FROM $2",
+        )
+        .bind(notebook_id)
+        .bind(except)
+        .execute(executor)
+        .await?;
+        Ok(())
+    }
+
+    /// The notebook and account a session is logged into, or `None` if it's ended, for websockets
+    /// which outlive the request that opened them.
+    pub async fn logged_into(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        id: i32,
+        config: &Config,
+    ) -> Result<Option<(Option<i32>, Option<i32>)>, sqlx::Error> {
+        sqlx::query_as(
+            "SELECT notebook_id, user_id FROM sessions
+            WHERE id = $1 AND expires_at > now() AND created_at > now() - make_interval(secs => $2)",
+        )
+        .bind(id)
+        .bind(config.sessions.absolute_timeout_secs as f64)
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Notes which browser a logged in session belongs to, for the sessions page.
+    /// Has to run inside the session middleware.
+    pub fn remember_device(req: &ServiceRequest) {
+        let session = req.get_session();
+        let logged_in = {
+            let entries = session.entries();
+            (entries.contains_key("notebook_id") || entries.contains_key("user_id"))
+                && !entries.contains_key("device")
+        };
+        if logged_in {
+            let device = req
+                .headers()
+                .get(header::USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("Unknown browser")
+                .chars()
+                .take(200)
+                .collect::<String>();
+            let _ = session.insert("device", device);
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct PgSessionStore {
+        pool: sqlx::Pool<sqlx::Postgres>,
+        absolute_timeout_secs: i64,
+    }
+
+    /// The columns copied out of a session's state, which holds everything as json.
+    struct Columns {
+        state: String,
+        user_id: Option<i32>,
+        notebook_id: Option<i32>,
+        device: Option<String>,
+    }
+    impl Columns {
+        fn new(state: &HashMap<String, String>) -> Result<Self, serde_json::Error> {
+            let get = |key: &str| {
+                state
+                    .get(key)
+                    .and_then(|value| serde_json::from_str(value).ok())
+            };
+            Ok(Self {
+                state: serde_json::to_string(state)?,
+                user_id: get("user_id"),
+                notebook_id: get("notebook_id"),
+                device: get("device"),
+            })
+        }
+    }
+
+    impl PgSessionStore {
+        async fn insert(
+            &self,
+            columns: Columns,
+            ttl: &Duration,
+        ) -> Result<SessionKey, anyhow::Error> {
+            let (key,): (String,) = sqlx::query_as(
+                "WITH new_session AS (
+                    SELECT encode(gen_random_bytes(32), 'hex') AS key, nextval('sessions_id_seq') AS id
+                )
+                INSERT INTO sessions (id, key_hash, state, user_id, notebook_id, device, expires_at)
+                SELECT id, digest(key, 'sha256'), $1::JSONB || jsonb_build_object('session_id', id::TEXT),
+                    $2, $3, $4, now() + make_interval(secs => $5)
+                FROM new_session
+                RETURNING (SELECT key FROM new_session)",
+            )
+            .bind(columns.state)
+            .bind(columns.user_id)
+            .bind(columns.notebook_id)
+            .bind(columns.device)
+            .bind(ttl.whole_seconds() as f64)
+            .fetch_one(&self.pool)
+            .await?;
+            Ok(SessionKey::try_from(key)?)
+        }
+    }
+
+    impl SessionStore for PgSessionStore {
+        async fn load(
+            &self,
+            session_key: &SessionKey,
+        ) -> Result<Option<HashMap<String, String>>, LoadError> {
+            let state: Option<(String,)> = sqlx::query_as(
+                "SELECT state::TEXT FROM sessions
+                WHERE key_hash = digest($1, 'sha256') AND expires_at > now()
+                AND created_at > now() - make_interval(secs => $2)",
+            )
+            .bind(session_key.as_ref())
+            .bind(self.absolute_timeout_secs as f64)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| LoadError::Other(e.into()))?;
+            state
+                .map(|(state,)| serde_json::from_str(&state))
+                .transpose()
+                .map_err(|e| LoadError::Deserialization(e.into()))
+        }
+
+        async fn save(
+            &self,
+            session_state: HashMap<String, String>,
+            ttl: &Duration,
+        ) -> Result<SessionKey, SaveError> {
+            let columns =
+                Columns::new(&session_state).map_err(|e| SaveError::Serialization(e.into()))?;
+            self.insert(columns, ttl).await.map_err(SaveError::Other)
+        }
+
+        async fn update(
+            &self,
+            session_key: SessionKey,
+            session_state: HashMap<String, String>,
+            ttl: &Duration,
+        ) -> Result<SessionKey, UpdateError> {
+            let columns =
+                Columns::new(&session_state).map_err(|e| UpdateError::Serialization(e.into()))?;
+            // `session_id` came from the database in the first place, so it's kept
+            let result = sqlx::query(
+                "UPDATE sessions SET state = $2::JSONB, user_id = $3, notebook_id = $4, device = $5,
+                last_seen_at = now(), expires_at = now() + make_interval(secs => $6)
+                WHERE key_hash = digest($1, 'sha256')",
+            )
+            .bind(session_key.as_ref())
+            .bind(&columns.state)
+            .bind(columns.user_id)
+            .bind(columns.notebook_id)
+            .bind(&columns.device)
+            .bind(ttl.whole_seconds() as f64)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| UpdateError::Other(e.into()))?;
+            if result.rows_affected() > 0 {
+                Ok(session_key)
+            } else {
+                // revoked while the request was running: start again logged out, rather than bring it back
+                self.insert(
+                    Columns::new(&HashMap::new())
+                        .map_err(|e| UpdateError::Serialization(e.into()))?,
+                    ttl,
+                )
+                .await
+                .map_err(UpdateError::Other)
+            }
+        }
+
+        async fn update_ttl(
+            &self,
+            session_key: &SessionKey,
+            ttl: &Duration,
+        ) -> Result<(), anyhow::Error> {
+            sqlx::query(
+                "UPDATE sessions SET last_seen_at = now(), expires_at = now() + make_interval(secs => $2)
+                WHERE key_hash = digest($1, 'sha256') AND last_seen_at < now() - make_interval(secs => $3)",
+            )
+            .bind(session_key.as_ref())
+            .bind(ttl.whole_seconds() as f64)
+            .bind(TOUCH_INTERVAL_SECS as f64)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        }
+
+        async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+            sqlx::query("DELETE FROM sessions WHERE key_hash = digest($1, 'sha256')")
+                .bind(session_key.as_ref())
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        }
+    }
+}
+
+/// Sessions are listed by who they're logged in as: the caller's account, and the notebook they opened with its password.
+#[cfg(feature = "ssr")]
+async fn session_owner() -> Result<(Option<i32>, Option<i32>), AppError> {
+    let session: actix_session::Session = leptos_actix::extract().await?;
+    let user_id = session.get::<i32>("user_id").ok().flatten();
+    let notebook_id = session.get::<i32>("notebook_id").ok().flatten();
+    if user_id.is_none() && notebook_id.is_none() {
+        return Err(AppError::Unauthorized("You aren't logged in!".to_string()));
+    }
+    Ok((user_id, notebook_id))
+}
+
+/// Logs out of everything, ending the session for good.
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all)]
+pub async fn end_session() -> Result<(), ServerFnError<AppError>> {
+    let session: actix_session::Session = leptos_actix::extract().await.map_err(AppError::from)?;
+    let (user_id, notebook_id) = session_owner().await.unwrap_or_default();
+    session.purge();
+    crate::sync::close_access_from_context(notebook_id, user_id)
+        .await
+        .map_err(AppError::from)?;
+    leptos_actix::redirect("/");
+    Ok(())
+}
+
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all)]
+pub async fn list_sessions() -> Result<Vec<ActiveSession>, ServerFnError<AppError>> {
+    let session: actix_session::Session = leptos_actix::extract().await.map_err(AppError::from)?;
+    let (user_id, notebook_id) = session_owner().await?;
+    let current_id = session.get::<i32>("session_id").ok().flatten();
+    let results: Vec<(i32, Option<String>, String, String, bool)> = sqlx::query_as(
+        "SELECT id, device, to_char(created_at, 'YYYY-MM-DD HH24:MI'), to_char(last_seen_at, 'YYYY-MM-DD HH24:MI'), id = $3
+        FROM sessions
+        WHERE (user_id = $1 OR notebook_id = $2) AND expires_at > now()
+        AND created_at > now() - make_interval(secs => $4)
+        ORDER BY last_seen_at DESC",
+    )
+    .bind(user_id)
+    .bind(notebook_id)
+    .bind(current_id)
+    .bind(crate::app::get_config_from_context()?.sessions.absolute_timeout_secs as f64)
+    .fetch_all(&crate::app::get_pool_from_context().await?)
+    .await
+    .map_err(AppError::from)?;
+    Ok(results
+        .into_iter()
+        .map(
+            |(id, device, created_at, last_seen_at, current)| ActiveSession {
+                id,
+                device,
+                created_at,
+                last_seen_at,
+                current,
+            },
+        )
+        .collect())
+}
+
+/// Logs another browser out.
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all)]
+pub async fn revoke_session(id: i32) -> Result<(), ServerFnError<AppError>> {
+    let (user_id, notebook_id) = session_owner().await?;
+    let revoked: Option<(Option<i32>, Option<i32>)> = sqlx::query_as(
+        "DELETE FROM sessions WHERE id = $1 AND (user_id = $2 OR notebook_id = $3)
+        RETURNING notebook_id, user_id",
+    )
+    .bind(id)
+    .bind(user_id)
+    .bind(notebook_id)
+    .fetch_optional(&crate::app::get_pool_from_context().await?)
+    .await
+    .map_err(AppError::from)?;
+    if let Some((notebook_id, user_id)) = revoked {
+        crate::sync::close_access_from_context(notebook_id, user_id)
+            .await
+            .map_err(AppError::from)?;
+        Ok(())
+    } else {
+        Err(AppError::NotFound("That session has already ended!".to_string()).into())
+    }
+}
+
+/// Logs out of everything, and goes back to the home page.
+#[component]
+pub fn LogOutButton() -> impl IntoView {
+    let end_session = ServerAction::<EndSession>::new();
+    view! {
+        <ActionForm action=end_session attr:class="log-out">
+            <button type="submit"> "Log out" </button>
+        </ActionForm>
+    }
+}
+
+#[component]
+pub fn SessionsPage() -> impl IntoView {
+    let sessions = RwSignal::new(Vec::<ActiveSession>::new());
+    let error = RwSignal::new(None::<ServerFnError<AppError>>);
+
+    let refresh = move || {
+        spawn_local(async move {
+            match list_sessions().await {
+                Ok(received) => sessions.set(received),
+                Err(e) => error.set(Some(e)),
+            }
+        })
+    };
+    Effect::new(move |_| refresh());
+
+    let revoke = move |id: i32| {
+        spawn_local(async move {
+            match revoke_session(id).await {
+                Ok(()) => error.set(None),
+                Err(e) => error.set(Some(e)),
+            }
+            refresh();
+        })
+    };
+
+    view! {
+        <div class="notebook-header">
+            <div class="left">
+                <a href="/"> <h1> "home" </h1> </a>
+            </div>
+            <div class="center">
+                <h1> "Sessions" </h1>
+            </div>
+        </div>
+        <div id="settings-page">
+            <div class="settings-section">
+                <p> "Everywhere you're logged in. Logging out another browser takes effect on its next request." </p>
+                <ul>
+                    <For
+                        each={move || sessions.get()}
+                        key={|session| session.id}
+                        children={move |session| {
+                            let id = session.id;
+                            let description = format!(
+                                "{} (logged in {}, last seen {})",
+                                session.device.as_deref().unwrap_or("Unknown browser"),
+                                session.created_at,
+                                session.last_seen_at,
+                            );
+                            view! {
+                                <li>
+                                    {description}
+                                    {if session.current {
+                                        Either::Left(view! { <strong> " this browser" </strong> })
+                                    } else {
+                                        Either::Right(view! {
+                                            <button on:click=move |_| revoke(id)> "Log out" </button>
+                                        })
+                                    }}
+                                </li>
+                            }
+                        }}
+                    />
+                </ul>
+                <LogOutButton />
+                {move || error.get().map(|error| view! { <ErrorMessage error /> })}
+            </div>
+        </div>
+    }
+}
@@ -0,0 +1,296 @@
+use leptos::{either::Either, prelude::*, task::spawn_local};
+use leptos_router::{hooks::use_params, params::Params};
+
+use crate::{
+    encryption::EncryptionSettings,
+    error::{error_message, AppError},
+    share_links::ShareLinks,
+    sharing::Sharing,
+    tokens::ApiTokens,
+    two_factor::TwoFactorSettings,
+};
+
+#[derive(Params, PartialEq, Eq)]
+struct SettingsParams {
+    id: Option<i32>,
+}
+
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all, fields(notebook_id = notebook_id))]
+pub async fn rename_notebook(
+    notebook_id: i32,
+    name: String,
+) -> Result<(), ServerFnError<AppError>> {
+    crate::sharing::owned_notebook(notebook_id).await?;
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(AppError::Validation("The notebook needs a name!".to_string()).into());
+    }
+    let renamed = crate::notebook::Notebook::rename(
+        &crate::app::get_pool_from_context().await?,
+        notebook_id,
+        name,
+    )
+    .await
+    .map_err(AppError::from)?;
+    if renamed {
+        crate::sync::publish_from_context(
+            notebook_id,
+            [crate::sync::SyncEvent::NotebookRenamed {
+                name: name.to_string(),
+            }],
+        );
+        Ok(())
+    } else {
+        Err(AppError::Conflict("That notebook already exists!".to_string()).into())
+    }
+}
+
+/// Changes the password, and logs every other browser out of the notebook.
+/// Wrong guesses at the old password count towards the same lockout as logging in.
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all, fields(notebook_id = notebook_id))]
+pub async fn change_notebook_password(
+    notebook_id: i32,
+    old_password: String,
+    new_password: String,
+) -> Result<(), ServerFnError<AppError>> {
+    crate::sharing::owned_notebook(notebook_id).await?;
+    if new_password.is_empty() {
+        return Err(AppError::Validation("The new password can't be empty!".to_string()).into());
+    }
+    let session: actix_session::Session = leptos_actix::extract().await.map_err(AppError::from)?;
+    let req: actix_web::HttpRequest = leptos_actix::extract().await.map_err(AppError::from)?;
+    let pool = crate::app::get_pool_from_context().await?;
+    let (name,): (String,) = sqlx::query_as("SELECT name FROM notebooks WHERE id = $1")
+        .bind(notebook_id)
+        .fetch_one(&pool)
+        .await
+        .map_err(AppError::from)?;
+    let throttle = crate::throttle::LoginKeys::new(&req, "notebook", &name);
+    if let Some(seconds) = throttle.locked_for(&pool).await.map_err(AppError::from)? {
+        return Err(AppError::Validation(crate::throttle::locked_out_message(seconds)).into());
+    }
+    let mut transaction = pool.begin().await.map_err(AppError::from)?;
+    let changed = crate::notebook::Notebook::change_password(
+        &mut *transaction,
+        notebook_id,
+        &old_password,
+        &new_password,
+    )
+    .await
+    .map_err(AppError::from)?;
+    if !changed {
+        transaction.rollback().await.map_err(AppError::from)?;
+        throttle
+            .record_failure(&pool)
+            .await
+            .map_err(AppError::from)?;
+        return Err(AppError::Validation("The old password is incorrect!".to_string()).into());
+    }
+    let current_id = session.get::<i32>("session_id").ok().flatten();
+    crate::sessions::forget_notebook(&mut *transaction, notebook_id, current_id)
+        .await
+        .map_err(AppError::from)?;
+    transaction.commit().await.map_err(AppError::from)?;
+    crate::sync::close_from_context([notebook_id]);
+    throttle
+        .record_success(&pool)
+        .await
+        .map_err(AppError::from)?;
+    Ok(())
+}
+
+/// Deletes the notebook for good, as long as `confirm_name` is its name.
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all, fields(notebook_id = notebook_id))]
+pub async fn delete_notebook(
+    notebook_id: i32,
+    confirm_name: String,
+) -> Result<(), ServerFnError<AppError>> {
+    crate::sharing::owned_notebook(notebook_id).await?;
+    let session: actix_session::Session = leptos_actix::extract().await.map_err(AppError::from)?;
+    let mut transaction = crate::app::get_pool_from_context()
+        .await?
+        .begin()
+        .await
+        .map_err(AppError::from)?;
+    let (name,): (String,) = sqlx::query_as("SELECT name FROM notebooks WHERE id = $1 FOR UPDATE")
+        .bind(notebook_id)
+        .fetch_one(&mut *transaction)
+        .await
+        .map_err(AppError::from)?;
+    if !name.eq_ignore_ascii_case(confirm_name.trim()) {
+        return Err(AppError::Validation("That isn't the notebook's name!".to_string()).into());
+    }
+    // sessions which are also logged into an account would otherwise be deleted along with the notebook
+    crate::sessions::forget_notebook(&mut *transaction, notebook_id, None)
+        .await
+        .map_err(AppError::from)?;
+    crate::notebook::Notebook::delete(&mut *transaction, notebook_id)
+        .await
+        .map_err(AppError::from)?;
+    transaction.commit().await.map_err(AppError::from)?;
+    session.remove("notebook_id");
+    crate::sync::publish_from_context(notebook_id, [crate::sync::SyncEvent::NotebookDeleted]);
+    leptos_actix::redirect("/");
+    Ok(())
+}
+
+#[component]
+fn RenameNotebook(notebook_id: i32) -> impl IntoView {
+    let name = RwSignal::new(String::new());
+    let message = RwSignal::new(None::<Result<String, String>>);
+    let rename = move |_| {
+        let new_name = name.get_untracked();
+        spawn_local(async move {
+            message.set(Some(
+                match rename_notebook(notebook_id, new_name.clone()).await {
+                    Ok(()) => {
+                        name.set(String::new());
+                        Ok(format!("Renamed to \"{}\".", new_name.trim()))
+                    }
+                    Err(e) => Err(error_message(&e)),
+                },
+            ));
+        })
+    };
+    view! {
+        <div class="settings-section">
+            <h2> "Name" </h2>
+            <input
+                type="text"
+                placeholder="New name..."
+                prop:value=move || name.get()
+                on:input:target=move |ev| name.set(ev.target().value())
+            />
+            <button on:click=rename> "Rename" </button>
+            {move || match message.get() {
+                Some(Ok(done)) => Either::Left(view! { <p> {done} </p> }),
+                Some(Err(e)) => Either::Right(Either::Left(view! { <p class="error-message"> {e} </p> })),
+                None => Either::Right(Either::Right(())),
+            }}
+        </div>
+    }
+}
+
+#[component]
+fn ChangePassword(notebook_id: i32) -> impl IntoView {
+    let old_password = RwSignal::new(String::new());
+    let new_password = RwSignal::new(String::new());
+    let message = RwSignal::new(None::<Result<String, String>>);
+    let change = move |_| {
+        let (old, new) = (old_password.get_untracked(), new_password.get_untracked());
+        spawn_local(async move {
+            message.set(Some(
+                match change_notebook_password(notebook_id, old, new).await {
+                    Ok(()) => {
+                        old_password.set(String::new());
+                        new_password.set(String::new());
+                        Ok("Password changed. Every other browser has been logged out of this notebook.".to_string())
+                    }
+                    Err(e) => Err(error_message(&e)),
+                },
+            ));
+        })
+    };
+    view! {
+        <div class="settings-section">
+            <h2> "Password" </h2>
+            <input
+                type="password"
+                placeholder="Current password..."
+                prop:value=move || old_password.get()
+                on:input:target=move |ev| old_password.set(ev.target().value())
+            />
+            <input
+                type="password"
+                placeholder="New password..."
+                prop:value=move || new_password.get()
+                on:input:target=move |ev| new_password.set(ev.target().value())
+            />
+            <button on:click=change> "Change password" </button>
+            {move || match message.get() {
+                Some(Ok(done)) => Either::Left(view! { <p> {done} </p> }),
+                Some(Err(e)) => Either::Right(Either::Left(view! { <p class="error-message"> {e} </p> })),
+                None => Either::Right(Either::Right(())),
+            }}
+        </div>
+    }
+}
+
+#[component]
+fn DeleteNotebook(notebook_id: i32) -> impl IntoView {
+    let confirming = RwSignal::new(false);
+    let confirm_name = RwSignal::new(String::new());
+    let error = RwSignal::new(None::<String>);
+    let delete = move |_| {
+        let typed = confirm_name.get_untracked();
+        spawn_local(async move {
+            // the server redirects home once it's gone
+            if let Err(e) = delete_notebook(notebook_id, typed).await {
+                error.set(Some(error_message(&e)));
+            }
+        })
+    };
+    view! {
+        <div class="settings-section">
+            <h2> "Delete" </h2>
+            <Show
+                when=move || confirming.get()
+                fallback=move || view! {
+                    <button on:click=move |_| confirming.set(true)> "Delete this notebook..." </button>
+                }
+            >
+                <p> "This deletes every text in the notebook, and can't be undone. Type the notebook's name to confirm." </p>
+                <input
+                    type="text"
+                    placeholder="Notebook name..."
+                    prop:value=move || confirm_name.get()
+                    on:input:target=move |ev| confirm_name.set(ev.target().value())
+                />
+                <button on:click=delete> "Delete forever" </button>
+                <button on:click=move |_| {
+                    confirming.set(false);
+                    confirm_name.set(String::new());
+                    error.set(None);
+                }> "Cancel" </button>
+            </Show>
+            {move || match error.get() {
+                Some(e) => Either::Left(view! { <p class="error-message"> {e} </p> }),
+                None => Either::Right(()),
+            }}
+        </div>
+    }
+}
+
+#[component]
+pub fn NotebookSettingsPage() -> impl IntoView {
+    let params = use_params::<SettingsParams>();
+    let result = move || match params.read().as_ref().ok().and_then(|params| params.id) {
+        Some(id) => Either::Left(view! {
+            <div class="notebook-header">
+                <div class="left">
+                    <a href=format!("/notebook/{id}")> <h1> "back" </h1> </a>
+                </div>
+                <div class="center">
+                    <h1> "Settings" </h1>
+                </div>
+            </div>
+            <div id="settings-page">
+                <RenameNotebook notebook_id=id />
+                <ChangePassword notebook_id=id />
+                <Sharing notebook_id=id />
+                <ShareLinks notebook_id=id />
+                <ApiTokens notebook_id=Some(id) />
+                <TwoFactorSettings notebook_id=Some(id) />
+                <EncryptionSettings notebook_id=id />
+                <DeleteNotebook notebook_id=id />
+            </div>
+        }),
+        None => Either::Right(view! { <h1> "Notebook not found" </h1> }),
+    };
+    view! {
+        {result}
+    }
+}
@@ -0,0 +1,493 @@
+use leptos::{
+    either::{Either, EitherOf3},
+    logging::log,
+    prelude::*,
+    task::spawn_local,
+};
+use leptos_router::{hooks::use_params, params::Params};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{error_message, AppError},
+    notebook::TextFile,
+};
+
+// read-only links to a notebook, or to one text in it, for people without an account.
+// like api tokens, only a hash of the token in the link is stored.
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareLink {
+    id: i32,
+    /// `None` when the whole notebook is shared.
+    text_id: Option<i32>,
+    has_password: bool,
+    created_at: String,
+    expires_at: Option<String>,
+}
+impl ShareLink {
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+}
+
+/// What someone opening a share link gets to see.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SharedContent {
+    NeedsPassword { wrong_password: bool },
+    Texts { name: String, texts: Vec<TextFile> },
+}
+
+#[cfg(feature = "ssr")]
+impl ShareLink {
+    /// Returns the new link's id and its token, or `None` if the text isn't in the notebook.
+    pub async fn create(
+        executor: impl sqlx::PgExecutor<'_>,
+        notebook_id: i32,
+        text_id: Option<i32>,
+        expires_in_days: Option<i32>,
+        password: Option<&str>,
+    ) -> Result<Option<(i32, String)>, sqlx::Error> {
+        sqlx::query_as(
+            "WITH new_link AS (SELECT encode(gen_random_bytes(24), 'hex') AS token)
+            INSERT INTO share_links (notebook_id, text_id, token_hash, password_hash, expires_at)
+            SELECT $1, $2, digest(token, 'sha256'), crypt($4, gen_salt('bf')), now() + make_interval(days => $3)
+            FROM new_link
+            WHERE $2::INTEGER IS NULL OR EXISTS (SELECT 1 FROM texts WHERE id = $2 AND notebook_id = $1)
+            RETURNING id, (SELECT token FROM new_link)",
+        )
+        .bind(notebook_id)
+        .bind(text_id)
+        .bind(expires_in_days)
+        .bind(password)
+        .fetch_optional(executor)
+        .await
+    }
+
+    /// Lists the links for a notebook which haven't been revoked or expired.
+    pub async fn list(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        notebook_id: i32,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let results: Vec<(i32, Option<i32>, bool, String, Option<String>)> = sqlx::query_as(
+            "SELECT id, text_id, password_hash IS NOT NULL, to_char(created_at, 'YYYY-MM-DD HH24:MI'), to_char(expires_at, 'YYYY-MM-DD HH24:MI')
+            FROM share_links
+            WHERE notebook_id = $1 AND revoked_at IS NULL AND (expires_at IS NULL OR expires_at > now())
+            ORDER BY id",
+        )
+        .bind(notebook_id)
+        .fetch_all(pool)
+        .await?;
+        Ok(results
+            .into_iter()
+            .map(|(id, text_id, has_password, created_at, expires_at)| Self {
+                id,
+                text_id,
+                has_password,
+                created_at,
+                expires_at,
+            })
+            .collect())
+    }
+
+    /// Returns whether there was a live link with that id in the notebook.
+    pub async fn revoke(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        notebook_id: i32,
+        id: i32,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "UPDATE share_links SET revoked_at = now() WHERE id = $1 AND notebook_id = $2 AND revoked_at IS NULL",
+        )
+        .bind(id)
+        .bind(notebook_id)
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// The id of the live link with that token, which password guesses are counted against.
+    pub async fn id_from_token(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        token: &str,
+    ) -> Result<Option<i32>, sqlx::Error> {
+        let id: Option<(i32,)> = sqlx::query_as(
+            "SELECT id FROM share_links
+            WHERE token_hash = digest($1, 'sha256') AND revoked_at IS NULL AND (expires_at IS NULL OR expires_at > now())",
+        )
+        .bind(token)
+        .fetch_optional(pool)
+        .await?;
+        Ok(id.map(|(id,)| id))
+    }
+
+    /// Looks up the token from a link, returning `None` if it's revoked, expired or made up.
+    pub async fn open(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        token: &str,
+        password: Option<&str>,
+    ) -> Result<Option<SharedContent>, sqlx::Error> {
+        let link: Option<(i32, Option<i32>, bool)> = sqlx::query_as(
+            "SELECT notebook_id, text_id,
+                password_hash IS NULL OR password_hash = crypt(COALESCE($2, ''), password_hash)
+            FROM share_links
+            WHERE token_hash = digest($1, 'sha256') AND revoked_at IS NULL AND (expires_at IS NULL OR expires_at > now())",
+        )
+        .bind(token)
+        .bind(password)
+        .fetch_optional(pool)
+        .await?;
+        let Some((notebook_id, text_id, unlocked)) = link else {
+            return Ok(None);
+        };
+        if !unlocked {
+            return Ok(Some(SharedContent::NeedsPassword {
+                wrong_password: password.is_some(),
+            }));
+        }
+        let Some(notebook) = crate::notebook::Notebook::get_from_id(pool, notebook_id).await?
+        else {
+            return Ok(None);
+        };
+        Ok(Some(SharedContent::Texts {
+            name: notebook.name().to_string(),
+            texts: notebook
+                .texts()
+                .filter(|text| text_id.is_none_or(|text_id| text.id() == text_id))
+                .cloned()
+                .collect(),
+        }))
+    }
+}
+
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all, fields(notebook_id = notebook_id))]
+pub async fn list_share_links(notebook_id: i32) -> Result<Vec<ShareLink>, ServerFnError<AppError>> {
+    crate::sharing::owned_notebook(notebook_id).await?;
+    Ok(
+        ShareLink::list(&crate::app::get_pool_from_context().await?, notebook_id)
+            .await
+            .map_err(AppError::from)?,
+    )
+}
+
+/// Returns the path of the new link, which isn't stored anywhere.
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all, fields(notebook_id = notebook_id))]
+pub async fn create_share_link(
+    notebook_id: i32,
+    text_id: Option<i32>,
+    expires_in_days: Option<i32>,
+    password: Option<String>,
+) -> Result<String, ServerFnError<AppError>> {
+    crate::sharing::owned_notebook(notebook_id).await?;
+    if !crate::app::get_config_from_context()?.features.share_links {
+        return Err(
+            AppError::Validation("Share links are turned off on this server!".to_string()).into(),
+        );
+    }
+    if expires_in_days.is_some_and(|days| days <= 0) {
+        return Err(AppError::Validation("Links have to last at least a day!".to_string()).into());
+    }
+    let pool = crate::app::get_pool_from_context().await?;
+    let mut transaction = pool.begin().await.map_err(AppError::from)?;
+    // whoever opened the link would only see ciphertext, and encrypting it revokes its links, which
+    // this one would miss if it was made while that happened
+    if crate::encryption::is_encrypted_for_share(&mut *transaction, notebook_id)
+        .await
+        .map_err(AppError::from)?
+    {
+        return Err(AppError::Validation(
+            "Encrypted notebooks can't be shared with links!".to_string(),
+        )
+        .into());
+    }
+    let password = password.filter(|password| !password.is_empty());
+    let created = ShareLink::create(
+        &mut *transaction,
+        notebook_id,
+        text_id,
+        expires_in_days,
+        password.as_deref(),
+    )
+    .await
+    .map_err(AppError::from)?;
+    transaction.commit().await.map_err(AppError::from)?;
+    created
+        .map(|(_, token)| format!("/s/{token}"))
+        .ok_or_else(|| AppError::NotFound("That text isn't in this notebook!".to_string()).into())
+}
+
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all, fields(notebook_id = notebook_id))]
+pub async fn revoke_share_link(
+    notebook_id: i32,
+    link_id: i32,
+) -> Result<(), ServerFnError<AppError>> {
+    crate::sharing::owned_notebook(notebook_id).await?;
+    if ShareLink::revoke(
+        &crate::app::get_pool_from_context().await?,
+        notebook_id,
+        link_id,
+    )
+    .await
+    .map_err(AppError::from)?
+    {
+        Ok(())
+    } else {
+        Err(AppError::NotFound(format!("Couldn't find a share link with id {link_id}!")).into())
+    }
+}
+
+/// Anyone with the link can call this, so it doesn't look at the session at all.
+/// Wrong passwords count towards a lockout on the link, like logging in to a notebook.
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all)]
+pub async fn open_share_link(
+    token: String,
+    password: Option<String>,
+) -> Result<SharedContent, ServerFnError<AppError>> {
+    let not_found = || AppError::NotFound("This link has expired, or never existed.".to_string());
+    if !crate::app::get_config_from_context()?.features.share_links {
+        return Err(not_found().into());
+    }
+    let req: actix_web::HttpRequest = leptos_actix::extract().await.map_err(AppError::from)?;
+    let pool = crate::app::get_pool_from_context().await?;
+    let link_id = ShareLink::id_from_token(&pool, &token)
+        .await
+        .map_err(AppError::from)?
+        .ok_or_else(not_found)?;
+    let throttle = crate::throttle::LoginKeys::new(&req, "share-link", &link_id.to_string());
+    if password.is_some() {
+        if let Some(seconds) = throttle.locked_for(&pool).await.map_err(AppError::from)? {
+            return Err(AppError::Validation(crate::throttle::locked_out_message(seconds)).into());
+        }
+    }
+    let content = ShareLink::open(&pool, &token, password.as_deref())
+        .await
+        .map_err(AppError::from)?
+        .ok_or_else(not_found)?;
+    match content {
+        SharedContent::NeedsPassword {
+            wrong_password: true,
+        } => throttle.record_failure(&pool).await,
+        SharedContent::Texts { .. } if password.is_some() => throttle.record_success(&pool).await,
+        _ => Ok(()),
+    }
+    .map_err(AppError::from)?;
+    Ok(content)
+}
+
+#[derive(Params, PartialEq, Eq)]
+struct SharedParams {
+    token: Option<String>,
+}
+
+/// What a share link opens: the shared texts, read-only, rendered on the server.
+#[component]
+pub fn SharedPage() -> impl IntoView {
+    let params = use_params::<SharedParams>();
+    let token = move || {
+        params
+            .read()
+            .as_ref()
+            .ok()
+            .and_then(|params| params.token.clone())
+            .unwrap_or_default()
+    };
+    let password = RwSignal::new(None::<String>);
+    let password_input = RwSignal::new(String::new());
+    let content = Resource::new(
+        move || (token(), password.get()),
+        |(token, password)| open_share_link(token, password),
+    );
+
+    let page = move || {
+        content.get().map(|content| match content {
+            Ok(SharedContent::Texts { name, texts }) => EitherOf3::A(view! {
+                <div class="notebook-header">
+                    <div class="center">
+                        <h1> {name} </h1>
+                    </div>
+                </div>
+                {texts
+                    .into_iter()
+                    .map(|text| view! {
+                        <div class="text-input-cell">
+                            <div class="text-input-cell-text">
+                                <p class="shared-text"> {text.text().to_string()} </p>
+                            </div>
+                        </div>
+                    })
+                    .collect_view()}
+            }),
+            Ok(SharedContent::NeedsPassword { wrong_password }) => EitherOf3::B(view! {
+                <div id="notebook-page">
+                    <form on:submit=move |ev| {
+                        ev.prevent_default();
+                        password.set(Some(password_input.get_untracked()));
+                    }>
+                        <h1> "This link needs a password" </h1>
+                        <input
+                            type="password"
+                            placeholder="Password..."
+                            required
+                            prop:value=move || password_input.get()
+                            on:input:target=move |ev| password_input.set(ev.target().value())
+                        />
+                        <br />
+                        <button type="submit"> "Open" </button>
+                    </form>
+                    {wrong_password.then(|| view! { <p class="error-message"> "That password is incorrect!" </p> })}
+                </div>
+            }),
+            Err(e) => EitherOf3::C(view! { <h1> {error_message(&e)} </h1> }),
+        })
+    };
+    view! {
+        <Suspense fallback=|| view! { <p> "Loading..." </p> }>
+            {page}
+        </Suspense>
+    }
+}
+
+/// Lets the owner make and revoke share links, on the notebook's settings page.
+#[component]
+pub fn ShareLinks(notebook_id: i32) -> impl IntoView {
+    let links = RwSignal::new(Vec::<ShareLink>::new());
+    let texts = RwSignal::new(Vec::<TextFile>::new());
+    let new_link = RwSignal::new(None::<Result<String, String>>);
+    let text_id = RwSignal::new(None::<i32>);
+    let expires_in_days = RwSignal::new(None::<i32>);
+    let password = RwSignal::new(String::new());
+
+    let refresh = move || {
+        spawn_local(async move {
+            match list_share_links(notebook_id).await {
+                Ok(received) => links.set(received),
+                Err(e) => log!("couldn't list share links: {:#?}", e),
+            }
+        })
+    };
+    Effect::new(move |_| refresh());
+    // so a single text can be picked to share
+    Effect::new(move |_| {
+        spawn_local(async move {
+            match crate::app::get_notebook(notebook_id).await {
+                Ok(notebook) => texts.set(notebook.texts().cloned().collect()),
+                Err(e) => log!("couldn't get the notebook's texts: {:#?}", e),
+            }
+        })
+    });
+    let first_line = move |text_id: i32| {
+        texts.with(|texts| {
+            texts
+                .iter()
+                .find(|text| text.id() == text_id)
+                .map(|text| text.text().lines().next().unwrap_or_default().to_string())
+                .unwrap_or_else(|| format!("text {text_id}"))
+        })
+    };
+
+    let create = move |_| {
+        let (link_text_id, link_expires_in_days, link_password) = (
+            text_id.get_untracked(),
+            expires_in_days.get_untracked(),
+            password.get_untracked(),
+        );
+        spawn_local(async move {
+            let result = create_share_link(
+                notebook_id,
+                link_text_id,
+                link_expires_in_days,
+                Some(link_password),
+            )
+            .await;
+            let origin = leptos::tachys::dom::window()
+                .location()
+                .origin()
+                .unwrap_or_default();
+            new_link.set(Some(
+                result
+                    .map(|path| format!("{origin}{path}"))
+                    .map_err(|e| error_message(&e)),
+            ));
+            password.set(String::new());
+            refresh();
+        })
+    };
+    let revoke = move |link_id: i32| {
+        spawn_local(async move {
+            if let Err(e) = revoke_share_link(notebook_id, link_id).await {
+                log!("couldn't revoke share link: {:#?}", e);
+            }
+            refresh();
+        })
+    };
+
+    let new_link_view = move || {
+        new_link.get().map(|link| match link {
+            Ok(link) => Either::Left(view! {
+                <p> "Copy this link now, it won't be shown again:" </p>
+                <code class="api-token"> {link} </code>
+            }),
+            Err(e) => Either::Right(view! { <p class="error-message"> {e} </p> }),
+        })
+    };
+
+    view! {
+        <div class="settings-section">
+            <h2> "Share links" </h2>
+            <p> "Anyone with a share link can read the notebook, or the one text it's for, without an account." </p>
+            <ul>
+                <For
+                    each={move || links.get()}
+                    key={|link| link.id()}
+                    children={move |link| {
+                        let link_id = link.id();
+                        view! {
+                            <li>
+                                {move || format!(
+                                    "{}{} - created {}, {}",
+                                    link.text_id.map(|text_id| format!("\"{}\"", first_line(text_id))).unwrap_or_else(|| "Whole notebook".to_string()),
+                                    if link.has_password { " (with a password)" } else { "" },
+                                    link.created_at,
+                                    link.expires_at.as_ref().map(|t| format!("expires {t}")).unwrap_or_else(|| "never expires".to_string()),
+                                )}
+                                <button on:click=move |_| revoke(link_id)> "Revoke" </button>
+                            </li>
+                        }
+                    }}
+                />
+            </ul>
+            <select on:change:target=move |ev| text_id.set(ev.target().value().parse().ok())>
+                <option value="" selected=move || text_id.get().is_none()> "Whole notebook" </option>
+                <For
+                    each={move || texts.get()}
+                    key={|text| text.id()}
+                    children={move |text| {
+                        let id = text.id();
+                        view! {
+                            <option value=id selected=move || text_id.get() == Some(id)>
+                                {move || first_line(id)}
+                            </option>
+                        }
+                    }}
+                />
+            </select>
+            <select on:change:target=move |ev| expires_in_days.set(ev.target().value().parse().ok())>
+                <option value=""> "Never expires" </option>
+                <option value="1"> "Expires in a day" </option>
+                <option value="7"> "Expires in a week" </option>
+                <option value="30"> "Expires in 30 days" </option>
+            </select>
+            <input
+                type="password"
+                placeholder="Password (optional)..."
+                prop:value=move || password.get()
+                on:input:target=move |ev| password.set(ev.target().value())
+            />
+            <button on:click=create> "Create link" </button>
+            {new_link_view}
+        </div>
+    }
+}
@@ -0,0 +1,315 @@
+use std::str::FromStr;
+
+use leptos::{either::Either, logging::log, prelude::*, task::spawn_local};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{error_message, AppError};
+
+// sharing a notebook with other accounts, without handing out its password.
+// whoever logs in with the password is the notebook's owner, and can invite accounts as members.
+
+/// What someone can do with a notebook, from least to most.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Role {
+    Viewer,
+    /// Can read the notebook like a viewer, and will be able to comment on it once comments exist.
+    Commenter,
+    Editor,
+    Owner,
+}
+impl Role {
+    pub fn can_edit(self) -> bool {
+        self >= Self::Editor
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Viewer => "viewer",
+            Self::Commenter => "commenter",
+            Self::Editor => "editor",
+            Self::Owner => "owner",
+        }
+    }
+}
+impl FromStr for Role {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "viewer" => Ok(Self::Viewer),
+            "commenter" => Ok(Self::Commenter),
+            "editor" => Ok(Self::Editor),
+            "owner" => Ok(Self::Owner),
+            _ => Err(()),
+        }
+    }
+}
+
+/// An account a notebook has been shared with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Member {
+    user_id: i32,
+    username: String,
+    role: Role,
+}
+impl Member {
+    pub fn user_id(&self) -> i32 {
+        self.user_id
+    }
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+    pub fn role(&self) -> Role {
+        self.role
+    }
+}
+
+#[cfg(feature = "ssr")]
+impl Member {
+    pub async fn list(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        notebook_id: i32,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let results: Vec<(i32, String, String)> = sqlx::query_as(
+            "SELECT users.id, users.username, memberships.role FROM memberships
+            JOIN users ON users.id = memberships.user_id
+            WHERE memberships.notebook_id = $1 ORDER BY UPPER(users.username)",
+        )
+        .bind(notebook_id)
+        .fetch_all(pool)
+        .await?;
+        Ok(results
+            .into_iter()
+            .filter_map(|(user_id, username, role)| {
+                Some(Self {
+                    user_id,
+                    username,
+                    role: role.parse().ok()?,
+                })
+            })
+            .collect())
+    }
+
+    /// Gives an account a role on a notebook, or changes the role it already has.
+    /// Returns `false` if there's no account with that username.
+    pub async fn invite(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        notebook_id: i32,
+        username: &str,
+        role: Role,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "INSERT INTO memberships (notebook_id, user_id, role)
+            SELECT $1, id, $3 FROM users WHERE UPPER(username) = UPPER($2)
+            ON CONFLICT (notebook_id, user_id) DO UPDATE SET role = EXCLUDED.role",
+        )
+        .bind(notebook_id)
+        .bind(username)
+        .bind(role.as_str())
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Returns whether the account was a member of the notebook.
+    pub async fn remove(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        notebook_id: i32,
+        user_id: i32,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM memberships WHERE notebook_id = $1 AND user_id = $2")
+            .bind(notebook_id)
+            .bind(user_id)
+            .execute(pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Every notebook shared with an account, and its role on each.
+    pub async fn roles_for_user(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        user_id: i32,
+    ) -> Result<std::collections::HashMap<i32, Role>, sqlx::Error> {
+        let results: Vec<(i32, String)> =
+            sqlx::query_as("SELECT notebook_id, role FROM memberships WHERE user_id = $1")
+                .bind(user_id)
+                .fetch_all(pool)
+                .await?;
+        Ok(results
+            .into_iter()
+            .filter_map(|(notebook_id, role)| Some((notebook_id, role.parse().ok()?)))
+            .collect())
+    }
+}
+
+/// Only the owner can see and change who a notebook is shared with, or how else it's shared.
+#[cfg(feature = "ssr")]
+pub(crate) async fn owned_notebook(notebook_id: i32) -> Result<(), AppError> {
+    let caller: crate::auth::Caller = leptos_actix::extract().await?;
+    if caller.role(notebook_id) == Some(Role::Owner) {
+        Ok(())
+    } else {
+        Err(AppError::Unauthorized(
+            "Only the notebook's owner can do that!".to_string(),
+        ))
+    }
+}
+
+/// The caller's role on a notebook, or `None` if they can't see it at all.
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all, fields(notebook_id = notebook_id))]
+pub async fn notebook_role(notebook_id: i32) -> Result<Option<Role>, ServerFnError<AppError>> {
+    let caller: crate::auth::Caller = leptos_actix::extract().await.map_err(AppError::from)?;
+    Ok(caller.role(notebook_id))
+}
+
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all, fields(notebook_id = notebook_id))]
+pub async fn list_members(notebook_id: i32) -> Result<Vec<Member>, ServerFnError<AppError>> {
+    owned_notebook(notebook_id).await?;
+    Ok(
+        Member::list(&crate::app::get_pool_from_context().await?, notebook_id)
+            .await
+            .map_err(AppError::from)?,
+    )
+}
+
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all, fields(notebook_id = notebook_id))]
+pub async fn invite_member(
+    notebook_id: i32,
+    username: String,
+    role: Role,
+) -> Result<(), ServerFnError<AppError>> {
+    owned_notebook(notebook_id).await?;
+    if role == Role::Owner {
+        return Err(AppError::Validation("A notebook can only have one owner!".to_string()).into());
+    }
+    if Member::invite(
+        &crate::app::get_pool_from_context().await?,
+        notebook_id,
+        username.trim(),
+        role,
+    )
+    .await
+    .map_err(AppError::from)?
+    {
+        // someone whose role was lowered shouldn't carry on editing
+        crate::sync::close_from_context([notebook_id]);
+        Ok(())
+    } else {
+        Err(AppError::NotFound(format!(
+            "There's no account called \"{}\"!",
+            username.trim()
+        ))
+        .into())
+    }
+}
+
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all, fields(notebook_id = notebook_id))]
+pub async fn remove_member(notebook_id: i32, user_id: i32) -> Result<(), ServerFnError<AppError>> {
+    owned_notebook(notebook_id).await?;
+    if Member::remove(
+        &crate::app::get_pool_from_context().await?,
+        notebook_id,
+        user_id,
+    )
+    .await
+    .map_err(AppError::from)?
+    {
+        crate::sync::close_from_context([notebook_id]);
+        Ok(())
+    } else {
+        Err(AppError::NotFound("That account isn't a member of this notebook!".to_string()).into())
+    }
+}
+
+#[component]
+pub fn Sharing(notebook_id: i32) -> impl IntoView {
+    let members = RwSignal::new(Vec::<Member>::new());
+    let error = RwSignal::new(None::<String>);
+    let username = RwSignal::new(String::new());
+    let role = RwSignal::new(Role::Viewer);
+
+    let refresh = move || {
+        spawn_local(async move {
+            match list_members(notebook_id).await {
+                Ok(received) => members.set(received),
+                Err(e) => log!("couldn't list members: {:#?}", e),
+            }
+        })
+    };
+    Effect::new(move |_| refresh());
+
+    let show_error = move |e: ServerFnError<AppError>| error.set(Some(error_message(&e)));
+    let invite = move |_| {
+        let (member_username, member_role) = (username.get_untracked(), role.get_untracked());
+        spawn_local(async move {
+            match invite_member(notebook_id, member_username, member_role).await {
+                Ok(()) => {
+                    error.set(None);
+                    username.set(String::new());
+                }
+                Err(e) => show_error(e),
+            }
+            refresh();
+        })
+    };
+    let remove = move |user_id: i32| {
+        spawn_local(async move {
+            if let Err(e) = remove_member(notebook_id, user_id).await {
+                show_error(e);
+            }
+            refresh();
+        })
+    };
+
+    let roles = [Role::Viewer, Role::Commenter, Role::Editor];
+    view! {
+        <div class="settings-section">
+            <h2> "Sharing" </h2>
+            <p> "Share this notebook with other accounts. Viewers and commenters can read it, editors can change it too." </p>
+            <ul>
+                <For
+                    each={move || members.get()}
+                    key={|member| (member.user_id(), member.role())}
+                    children={move |member| {
+                        let user_id = member.user_id();
+                        view! {
+                            <li>
+                                {format!("{} ({})", member.username(), member.role().as_str())}
+                                <button on:click=move |_| remove(user_id)> "Remove" </button>
+                            </li>
+                        }
+                    }}
+                />
+            </ul>
+            <input
+                type="text"
+                placeholder="Username..."
+                prop:value=move || username.get()
+                on:input:target=move |ev| username.set(ev.target().value())
+            />
+            <select on:change:target=move |ev| {
+                if let Ok(new_role) = ev.target().value().parse() {
+                    role.set(new_role);
+                }
+            }>
+                {roles
+                    .into_iter()
+                    .map(|option| view! {
+                        <option value=option.as_str() selected=move || role.get() == option>
+                            {option.as_str()}
+                        </option>
+                    })
+                    .collect_view()}
+            </select>
+            <button on:click=invite> "Invite" </button>
+            {move || match error.get() {
+                Some(e) => Either::Left(view! { <p class="error-message"> {e} </p> }),
+                None => Either::Right(()),
+            }}
+        </div>
+    }
+}
@@ -0,0 +1,39 @@
+//! Stopping the server without losing anything, when Fly stops the machine.
+//!
+//! On `SIGTERM` (or `SIGINT`) the server stops accepting connections, closes every notebook's websocket
+//! so browsers reconnect to whichever server is next, and gives requests which have already started,
+//! like saves and imports, and background jobs which are running, until `shutdown.timeout_secs` to
+//! finish. `main` closes the database pool once they have.
+
+use std::time::Duration;
+
+use actix_web::dev::ServerHandle;
+
+use crate::{jobs::Workers, sync::SyncHub};
+
+/// Waits for a signal to stop, then stops the server and the job workers gracefully.
+/// The server is started with `disable_signals`, so this is the only thing that stops it.
+pub async fn on_signal(server: ServerHandle, sync: SyncHub, workers: Workers, deadline: Duration) {
+    let signal = wait_for_signal().await;
+    tracing::info!("received {signal}, finishing the requests in progress");
+    // an open websocket is a request that never finishes, so they'd hold the server up until the deadline
+    sync.close_all();
+    tokio::join!(server.stop(true), workers.stop(deadline));
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate =
+        signal(SignalKind::terminate()).expect("should be able to listen for SIGTERM");
+    tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+    }
+}
+#[cfg(not(unix))]
+async fn wait_for_signal() -> &'static str {
+    let _ = tokio::signal::ctrl_c().await;
+    "ctrl-c"
+}
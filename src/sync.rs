@@ -0,0 +1,598 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use gloo_timers::future::sleep;
+use leptos::{logging::log, prelude::*, tachys::dom::window, task::spawn_local};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::{prelude::Closure, JsCast};
+use web_sys::{MessageEvent, WebSocket};
+
+use crate::{
+    encryption::{is_ciphertext, Ciphertexts, NotebookKey},
+    notebook::{Notebook, TextFile},
+};
+
+// every device with a notebook open keeps a websocket to `/ws/notebooks/:id`,
+// and the server pushes each change to the notebook down all of them.
+
+/// A change to a notebook, as broadcast to everyone who has it open.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum SyncEvent {
+    TextInserted {
+        text: TextFile,
+    },
+    TextUpdated {
+        text: TextFile,
+    },
+    TextDeleted {
+        id: i32,
+    },
+    /// The ids of every text in the notebook, in their new order.
+    TextsReordered {
+        ids: Vec<i32>,
+    },
+    NotebookRenamed {
+        name: String,
+    },
+    /// The notebook is gone, so there's nothing left to show.
+    NotebookDeleted,
+    /// Some events were missed, so the whole notebook should be fetched again.
+    Resync,
+    /// Sent once to each connection, so it can recognise its own cursor.
+    Welcome {
+        client: u64,
+    },
+    /// An edit to a cell's shared document, see [`crate::cells`].
+    CellUpdate {
+        text_id: i32,
+        update: Vec<u8>,
+    },
+    /// Where someone's cursor is in a cell they're editing, or `None` once they stop.
+    Cursor {
+        text_id: i32,
+        client: u64,
+        position: Option<Vec<u8>>,
+    },
+}
+
+/// What a client can send up its websocket.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ClientMessage {
+    CellUpdate {
+        text_id: i32,
+        update: Vec<u8>,
+    },
+    Cursor {
+        text_id: i32,
+        position: Option<Vec<u8>>,
+    },
+}
+
+#[cfg(feature = "ssr")]
+pub use server::*;
+
+#[cfg(feature = "ssr")]
+mod server {
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use actix_web::{get, web, HttpRequest, HttpResponse};
+    use actix_ws::Message;
+    use tokio::sync::broadcast::{self, error::RecvError};
+    use tracing::Instrument;
+
+    use super::{ClientMessage, SyncEvent};
+    use crate::{auth::Caller, cells, rest::ApiError, sharing::Member, AppState};
+
+    /// How many events a slow connection can fall behind by before it has to resync.
+    const CHANNEL_CAPACITY: usize = 64;
+
+    /// How often a connection's access is looked up again, in case it was changed on another server.
+    const RECHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+    static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+    /// One broadcast channel per notebook that somebody has open.
+    #[derive(Clone, Default)]
+    pub struct SyncHub {
+        channels: Arc<Mutex<HashMap<i32, broadcast::Sender<SyncEvent>>>>,
+    }
+    impl SyncHub {
+        pub fn subscribe(&self, notebook_id: i32) -> broadcast::Receiver<SyncEvent> {
+            self.channels
+                .lock()
+                .expect("sync hub lock shouldn't be poisoned")
+                .entry(notebook_id)
+                .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+                .subscribe()
+        }
+
+        pub fn publish(&self, notebook_id: i32, events: impl IntoIterator<Item = SyncEvent>) {
+            let mut channels = self
+                .channels
+                .lock()
+                .expect("sync hub lock shouldn't be poisoned");
+            if let Some(sender) = channels.get(&notebook_id) {
+                if sender.receiver_count() == 0 {
+                    channels.remove(&notebook_id);
+                } else {
+                    for event in events {
+                        let _ = sender.send(event);
+                    }
+                }
+            }
+        }
+
+        /// Ends every websocket a notebook has open, when who can see it has changed.
+        /// Browsers reconnect straight away, and are let back in if they still have access.
+        pub fn close(&self, notebook_ids: impl IntoIterator<Item = i32>) {
+            let mut channels = self
+                .channels
+                .lock()
+                .expect("sync hub lock shouldn't be poisoned");
+            for notebook_id in notebook_ids {
+                channels.remove(&notebook_id);
+            }
+        }
+
+        /// Drops every channel, which ends every websocket, when the server is shutting down.
+        pub fn close_all(&self) {
+            self.channels
+                .lock()
+                .expect("sync hub lock shouldn't be poisoned")
+                .clear();
+        }
+    }
+
+    /// Publishes events through the `AppState` provided to server functions.
+    pub fn publish_from_context(notebook_id: i32, events: impl IntoIterator<Item = SyncEvent>) {
+        if let Some(state) = leptos::prelude::use_context::<AppState>() {
+            state.sync.publish(notebook_id, events);
+        }
+    }
+
+    /// Closes notebooks' websockets through the `AppState` provided to server functions.
+    pub fn close_from_context(notebook_ids: impl IntoIterator<Item = i32>) {
+        if let Some(state) = leptos::prelude::use_context::<AppState>() {
+            state.sync.close(notebook_ids);
+        }
+    }
+
+    /// Closes the websockets of every notebook that a session or token for `notebook_id` and `user_id`
+    /// could have open, when it's been logged out or revoked.
+    pub async fn close_access_from_context(
+        notebook_id: Option<i32>,
+        user_id: Option<i32>,
+    ) -> Result<(), sqlx::Error> {
+        let Some(state) = leptos::prelude::use_context::<AppState>() else {
+            return Ok(());
+        };
+        let mut notebook_ids = Vec::from_iter(notebook_id);
+        if let Some(user_id) = user_id {
+            notebook_ids.extend(
+                Member::roles_for_user(&state.pool, user_id)
+                    .await?
+                    .into_keys(),
+            );
+        }
+        state.sync.close(notebook_ids);
+        Ok(())
+    }
+
+    #[get("/ws/notebooks/{id}")]
+    pub async fn notebook_socket(
+        req: HttpRequest,
+        body: web::Payload,
+        mut caller: Caller,
+        state: web::Data<AppState>,
+        id: web::Path<i32>,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let id = id.into_inner();
+        if !caller.can_read(id) {
+            return Err(ApiError::Forbidden.into());
+        }
+        let (response, mut session, mut messages) = actix_ws::handle(&req, body)?;
+        let mut events = state.sync.subscribe(id);
+        let client = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+        actix_web::rt::spawn(async move {
+            let welcome = serde_json::to_string(&SyncEvent::Welcome { client })
+                .expect("sync events should serialise");
+            if session.text(welcome).await.is_err() {
+                return;
+            }
+            // the cell this client last had a cursor in, so it can be cleared when they leave
+            let mut cursor_in = None;
+            let mut recheck = tokio::time::interval_at(
+                tokio::time::Instant::now() + RECHECK_INTERVAL,
+                RECHECK_INTERVAL,
+            );
+            loop {
+                tokio::select! {
+                    // the connection can outlive the caller's access, which was checked when it was opened
+                    _ = recheck.tick() => match caller.refresh(&state).await {
+                        Ok(refreshed) if refreshed.can_read(id) => caller = refreshed,
+                        Ok(_) => break,
+                        Err(e) => tracing::error!("couldn't check the connection's access: {e}"),
+                    },
+                    event = events.recv() => {
+                        let event = match event {
+                            Ok(event) => event,
+                            Err(RecvError::Lagged(_)) => SyncEvent::Resync,
+                            Err(RecvError::Closed) => break,
+                        };
+                        let json = serde_json::to_string(&event).expect("sync events should serialise");
+                        if session.text(json).await.is_err() {
+                            break;
+                        }
+                    }
+                    message = messages.recv() => match message {
+                        Some(Ok(Message::Text(text))) => {
+                            match serde_json::from_str::<ClientMessage>(&text) {
+                                // readers can watch, but not edit
+                                Ok(_) if !caller.can_write(id) => (),
+                                Ok(ClientMessage::CellUpdate { text_id, update }) => {
+                                    match cells::merge_update(&state.pool, id, text_id, &update).await {
+                                        Ok(Some(text)) => state.sync.publish(
+                                            id,
+                                            [
+                                                SyncEvent::CellUpdate { text_id, update },
+                                                SyncEvent::TextUpdated { text },
+                                            ],
+                                        ),
+                                        Ok(None) => (),
+                                        Err(e) => tracing::error!("couldn't merge cell update: {e}"),
+                                    }
+                                }
+                                Ok(ClientMessage::Cursor { text_id, position }) => {
+                                    cursor_in = position.is_some().then_some(text_id);
+                                    state.sync.publish(
+                                        id,
+                                        [SyncEvent::Cursor { text_id, client, position }],
+                                    );
+                                }
+                                Err(e) => tracing::warn!("couldn't understand sync message: {e}"),
+                            }
+                        }
+                        Some(Ok(Message::Ping(bytes))) => {
+                            if session.pong(&bytes).await.is_err() {
+                                break;
+                            }
+                        }
+                        Some(Ok(Message::Close(reason))) => {
+                            let _ = session.clone().close(reason).await;
+                            break;
+                        }
+                        Some(Ok(_)) => (),
+                        Some(Err(_)) | None => break,
+                    },
+                }
+            }
+            if let Some(text_id) = cursor_in {
+                state.sync.publish(
+                    id,
+                    [SyncEvent::Cursor {
+                        text_id,
+                        client,
+                        position: None,
+                    }],
+                );
+            }
+            let _ = session.close(None).await;
+        }
+        // the request's span ends when the response is sent, but the connection carries on
+        .instrument(tracing::info_span!("notebook_socket", notebook_id = id, client)));
+        Ok(response)
+    }
+}
+
+/// The client side of a notebook's websocket.
+/// Provided as context by `NotebookComponent`, so cells can exchange edits through it.
+#[derive(Clone, Copy)]
+pub struct SyncConnection {
+    socket: StoredValue<Option<WebSocket>, LocalStorage>,
+    client: StoredValue<Option<u64>>,
+    cells: StoredValue<HashMap<i32, CellListener>>,
+    /// The notebook as the server last saw it, so changes which came from the server aren't saved back.
+    synced: StoredValue<Option<Notebook>>,
+    /// Set once an encrypted notebook has been unlocked. Changes from other devices arrive encrypted.
+    key: StoredValue<Option<NotebookKey>, LocalStorage>,
+    ciphertexts: StoredValue<Ciphertexts>,
+    /// The open socket's event handlers, which are dropped along with it.
+    handlers: StoredValue<Option<SocketHandlers>, LocalStorage>,
+    /// How many times in a row reconnecting has failed, for backing off.
+    failures: StoredValue<u32>,
+}
+
+type CellListener = Arc<dyn Fn(SyncEvent) + Send + Sync>;
+
+struct SocketHandlers {
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_open: Closure<dyn FnMut()>,
+    _on_close: Closure<dyn FnMut()>,
+}
+
+impl SyncConnection {
+    pub fn new() -> Self {
+        Self {
+            socket: StoredValue::new_local(None),
+            client: StoredValue::new(None),
+            cells: StoredValue::new(HashMap::new()),
+            synced: StoredValue::new(None),
+            key: StoredValue::new_local(None),
+            ciphertexts: StoredValue::new(Ciphertexts::default()),
+            handlers: StoredValue::new_local(None),
+            failures: StoredValue::new(0),
+        }
+    }
+
+    /// Remembers the notebook as it was loaded, so it isn't saved straight back.
+    pub fn mark_synced(&self, notebook: &Notebook) {
+        let _ = self.synced.try_set_value(Some(notebook.clone()));
+    }
+
+    pub fn unlock(&self, key: NotebookKey) {
+        let _ = self.key.try_set_value(Some(key));
+    }
+
+    /// The key to the notebook, if it's encrypted and has been unlocked.
+    pub fn key(&self) -> Option<NotebookKey> {
+        self.key.try_get_value().flatten()
+    }
+
+    /// What the server has for the texts of an encrypted notebook, as of the last save or sync.
+    pub fn ciphertexts(&self) -> Ciphertexts {
+        self.ciphertexts.try_get_value().unwrap_or_default()
+    }
+
+    pub fn remember_ciphertexts(&self, encrypted: &Notebook, decrypted: &Notebook) {
+        let _ = self
+            .ciphertexts
+            .try_update_value(|ciphertexts| ciphertexts.remember(encrypted, decrypted));
+    }
+
+    /// The notebook as it was last saved or synced, if `notebook` has been changed here since.
+    /// Each call assumes the changes are about to be saved.
+    pub fn take_unsaved(&self, notebook: &Notebook) -> Option<Notebook> {
+        self.synced
+            .try_update_value(|synced| {
+                let base = synced.replace(notebook.clone())?;
+                (!notebook.changes_since(&base).is_empty()).then_some(base)
+            })
+            .flatten()
+    }
+
+    /// Keeps `notebook` up to date with changes made on other devices, until the calling effect is cleaned up.
+    pub fn connect(self, notebook_id: i32, notebook: RwSignal<Option<Notebook>>) {
+        let stopped = StoredValue::new(false);
+        self.open(notebook_id, notebook, stopped, false);
+        on_cleanup(move || {
+            stopped.set_value(true);
+            self.close();
+        });
+    }
+
+    /// Closes the socket, if there is one, without reconnecting.
+    fn close(&self) {
+        let _ = self.socket.try_update_value(|socket| {
+            if let Some(socket) = socket.take() {
+                // its handlers are about to be dropped, so they mustn't be called
+                socket.set_onmessage(None);
+                socket.set_onopen(None);
+                socket.set_onclose(None);
+                let _ = socket.close();
+            }
+        });
+        let _ = self.handlers.try_set_value(None);
+    }
+
+    /// Messages sent while disconnected are dropped, and this returns false; listening cells are sent
+    /// a `Resync` when the connection comes back, so they can send their whole state again.
+    pub fn send(&self, message: &ClientMessage) -> bool {
+        let json = serde_json::to_string(message).expect("sync messages should serialise");
+        self.socket
+            .try_with_value(|socket| {
+                socket.as_ref().is_some_and(|socket| {
+                    socket.ready_state() == WebSocket::OPEN && socket.send_with_str(&json).is_ok()
+                })
+            })
+            .unwrap_or(false)
+    }
+
+    /// Passes `CellUpdate`, `Cursor` and `Resync` events for a text to `listener`.
+    pub fn listen(&self, text_id: i32, listener: impl Fn(SyncEvent) + Send + Sync + 'static) {
+        let _ = self.cells.try_update_value(|cells| {
+            cells.insert(text_id, Arc::new(listener));
+        });
+    }
+
+    pub fn stop_listening(&self, text_id: i32) {
+        let _ = self.cells.try_update_value(|cells| {
+            cells.remove(&text_id);
+        });
+    }
+
+    fn notify(&self, text_id: Option<i32>, event: SyncEvent) {
+        let listeners = self
+            .cells
+            .try_with_value(|cells| {
+                cells
+                    .iter()
+                    .filter(|(id, _)| text_id.is_none_or(|text_id| **id == text_id))
+                    .map(|(_, listener)| Arc::clone(listener))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        for listener in listeners {
+            listener(event.clone());
+        }
+    }
+
+    fn open(
+        self,
+        notebook_id: i32,
+        notebook: RwSignal<Option<Notebook>>,
+        stopped: StoredValue<bool>,
+        reconnecting: bool,
+    ) {
+        let location = window().location();
+        let protocol = if location.protocol().ok().as_deref() == Some("https:") {
+            "wss"
+        } else {
+            "ws"
+        };
+        let host = location.host().unwrap_or_default();
+        let Ok(new_socket) =
+            WebSocket::new(&format!("{protocol}://{host}/ws/notebooks/{notebook_id}"))
+        else {
+            log!("couldn't open a sync connection");
+            return;
+        };
+
+        let on_message = Closure::<dyn FnMut(MessageEvent)>::new(move |e: MessageEvent| {
+            let Some(data) = e.data().as_string() else {
+                return;
+            };
+            match serde_json::from_str::<SyncEvent>(&data) {
+                Ok(SyncEvent::Resync) => {
+                    self.resync(notebook_id, notebook);
+                    self.notify(None, SyncEvent::Resync);
+                }
+                Ok(SyncEvent::NotebookDeleted) => {
+                    let _ = window().location().set_href("/");
+                }
+                Ok(SyncEvent::Welcome { client }) => {
+                    let _ = self.client.try_set_value(Some(client));
+                }
+                Ok(event @ SyncEvent::CellUpdate { text_id, .. }) => {
+                    self.notify(Some(text_id), event)
+                }
+                Ok(
+                    event @ SyncEvent::Cursor {
+                        text_id, client, ..
+                    },
+                ) => {
+                    if self.client.try_get_value().flatten() != Some(client) {
+                        self.notify(Some(text_id), event);
+                    }
+                }
+                Ok(event) => match self.key() {
+                    Some(key) => spawn_local(async move {
+                        match key.decrypt_event(event.clone()).await {
+                            Ok(decrypted) => {
+                                if let (
+                                    SyncEvent::TextInserted { text: encrypted }
+                                    | SyncEvent::TextUpdated { text: encrypted },
+                                    SyncEvent::TextInserted { text: decrypted }
+                                    | SyncEvent::TextUpdated { text: decrypted },
+                                ) = (&event, &decrypted)
+                                {
+                                    let _ = self.ciphertexts.try_update_value(|ciphertexts| {
+                                        ciphertexts.remember_text(encrypted, decrypted)
+                                    });
+                                }
+                                self.apply(notebook, decrypted)
+                            }
+                            Err(e) => log!("couldn't decrypt a change: {e}"),
+                        }
+                    }),
+                    None => self.apply(notebook, event),
+                },
+                Err(e) => log!("couldn't understand sync message: {e}"),
+            }
+        });
+        // anything could have changed while we were disconnected
+        let on_open = Closure::<dyn FnMut()>::new(move || {
+            let _ = self.failures.try_set_value(0);
+            if reconnecting {
+                self.resync(notebook_id, notebook);
+                self.notify(None, SyncEvent::Resync);
+            }
+        });
+        let on_close = Closure::<dyn FnMut()>::new(move || {
+            if stopped.try_get_value().unwrap_or(true) {
+                return;
+            }
+            let failures = self
+                .failures
+                .try_update_value(|failures| {
+                    *failures += 1;
+                    *failures - 1
+                })
+                .unwrap_or_default();
+            spawn_local(async move {
+                // back off up to about 30 seconds between attempts, starting over once one works
+                sleep(Duration::from_millis(500 * 2u64.pow(failures.min(6)))).await;
+                if !stopped.try_get_value().unwrap_or(true) {
+                    self.open(notebook_id, notebook, stopped, true);
+                }
+            });
+        });
+        new_socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        new_socket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+        new_socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+        // the last socket has closed by now, and this drops its handlers
+        self.close();
+        let _ = self.socket.try_set_value(Some(new_socket));
+        let _ = self.handlers.try_set_value(Some(SocketHandlers {
+            _on_message: on_message,
+            _on_open: on_open,
+            _on_close: on_close,
+        }));
+    }
+
+    fn apply(self, notebook: RwSignal<Option<Notebook>>, event: SyncEvent) {
+        let _ = self.synced.try_update_value(|synced| {
+            if let Some(synced) = synced.as_mut() {
+                synced.apply(event.clone());
+            }
+        });
+        let _ = notebook.try_update(|notebook| {
+            if let Some(notebook) = notebook.as_mut() {
+                notebook.apply(event);
+            }
+        });
+    }
+
+    /// Fetches the whole notebook again, in place of whatever's here.
+    pub fn resync(self, notebook_id: i32, notebook: RwSignal<Option<Notebook>>) {
+        spawn_local(async move {
+            let received_notebook = match crate::app::get_notebook(notebook_id).await {
+                Ok(received_notebook) => received_notebook,
+                Err(e) => return log!("couldn't resync notebook: {:#?}", e),
+            };
+            let received_notebook = match self.key() {
+                Some(key) => match key.decrypt_notebook(&received_notebook).await {
+                    Ok(decrypted) => {
+                        self.remember_ciphertexts(&received_notebook, &decrypted);
+                        decrypted
+                    }
+                    Err(e) => return log!("couldn't decrypt notebook: {e}"),
+                },
+                // it's been encrypted since it was opened, so it needs unlocking
+                None if received_notebook
+                    .texts()
+                    .any(|text| is_ciphertext(text.text())) =>
+                {
+                    let _ = window().location().reload();
+                    return;
+                }
+                None => received_notebook,
+            };
+            let _ = self.synced.try_set_value(Some(received_notebook.clone()));
+            let _ = notebook.try_set(Some(received_notebook));
+        });
+    }
+}
+
+impl Default for SyncConnection {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,125 @@
+//! Logging, through `tracing`.
+//!
+//! Every request gets a span with its own request id, and server functions and database calls get
+//! spans of their own inside it, tagged with the notebook they're about where there is one.
+
+use std::fmt;
+
+/// Shows how long a note is instead of what it says, so logs don't fill up with people's notes.
+/// Set `logging.note_contents` on the server to see them anyway, when debugging locally.
+pub struct Redacted<'a>(pub &'a str);
+impl fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if show_note_contents() {
+            fmt::Debug::fmt(self.0, f)
+        } else {
+            write!(f, "<{} bytes>", self.0.len())
+        }
+    }
+}
+
+#[cfg(feature = "ssr")]
+static SHOW_NOTE_CONTENTS: std::sync::atomic::AtomicBool =
+    std::sync::atomic::AtomicBool::new(false);
+
+#[cfg(feature = "ssr")]
+fn show_note_contents() -> bool {
+    SHOW_NOTE_CONTENTS.load(std::sync::atomic::Ordering::Relaxed)
+}
+#[cfg(not(feature = "ssr"))]
+fn show_note_contents() -> bool {
+    false
+}
+
+/// Sets up the server's logging. `RUST_LOG` picks what gets logged (`info` by default).
+/// Logs go to stderr, so they don't get mixed up with the output of admin commands.
+#[cfg(feature = "ssr")]
+pub fn init(config: &crate::config::LoggingConfig) {
+    use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+    SHOW_NOTE_CONTENTS.store(config.note_contents, std::sync::atomic::Ordering::Relaxed);
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let registry = tracing_subscriber::registry().with(filter);
+    match config.format {
+        crate::config::LogFormat::Json => registry
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .with_writer(std::io::stderr)
+                    .json()
+                    .with_current_span(true)
+                    .with_span_list(true),
+            )
+            .init(),
+        crate::config::LogFormat::Pretty => registry
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .with_writer(std::io::stderr)
+                    .pretty(),
+            )
+            .init(),
+    }
+}
+
+/// Spans requests like `tracing_actix_web`'s default does, but with share links' tokens left out of
+/// `http.target`, since anyone who reads the logs could otherwise open them.
+#[cfg(feature = "ssr")]
+pub struct RequestSpan;
+#[cfg(feature = "ssr")]
+impl tracing_actix_web::RootSpanBuilder for RequestSpan {
+    fn on_request_start(request: &actix_web::dev::ServiceRequest) -> tracing::Span {
+        use actix_web::HttpMessage as _;
+        use tracing::field::Empty;
+
+        let connection = request.connection_info();
+        let request_id = request
+            .extensions()
+            .get::<tracing_actix_web::RequestId>()
+            .map(ToString::to_string);
+        let route = request.match_pattern();
+        tracing::info_span!(
+            "HTTP request",
+            http.method = %request.method(),
+            http.route = route.as_deref().unwrap_or("default"),
+            http.flavor = ?request.version(),
+            http.scheme = %connection.scheme(),
+            http.host = %connection.host(),
+            http.client_ip = connection.realip_remote_addr().unwrap_or(""),
+            http.user_agent = request
+                .headers()
+                .get(actix_web::http::header::USER_AGENT)
+                .and_then(|agent| agent.to_str().ok())
+                .unwrap_or(""),
+            http.target = %redact_target(
+                request
+                    .uri()
+                    .path_and_query()
+                    .map(|target| target.as_str())
+                    .unwrap_or("")
+            ),
+            http.status_code = Empty,
+            otel.status_code = Empty,
+            request_id = request_id.as_deref().unwrap_or(""),
+            exception.message = Empty,
+            exception.details = Empty,
+        )
+    }
+
+    fn on_request_end<B: actix_web::body::MessageBody>(
+        span: tracing::Span,
+        outcome: &Result<actix_web::dev::ServiceResponse<B>, actix_web::Error>,
+    ) {
+        tracing_actix_web::DefaultRootSpanBuilder::on_request_end(span, outcome);
+    }
+}
+
+/// `/s/<token>` becomes `/s/<redacted>`, keeping anything after the token.
+#[cfg(feature = "ssr")]
+fn redact_target(target: &str) -> std::borrow::Cow<'_, str> {
+    match target.strip_prefix("/s/") {
+        Some(rest) => {
+            let end = rest.find(['/', '?']).unwrap_or(rest.len());
+            format!("/s/<redacted>{}", &rest[end..]).into()
+        }
+        None => target.into(),
+    }
+}
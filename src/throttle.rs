@@ -0,0 +1,231 @@
+//! Slowing down password guessing, by locking out a client address or a name after too many failed logins.
+//! The state lives in the `login_throttles` table, so restarting the server doesn't reset it.
+
+use actix_web::HttpRequest;
+
+/// Failed logins in a row before a name is locked out.
+const NAME_THRESHOLD: i32 = 5;
+/// Several people can share an address, so addresses are given more leeway.
+const ADDRESS_THRESHOLD: i32 = 20;
+/// The first lockout, which doubles with every failure after that.
+const BASE_LOCKOUT_SECS: f64 = 30.0;
+const MAX_LOCKOUT_SECS: f64 = 60.0 * 60.0;
+
+/// A bcrypt hash, with the same cost as `gen_salt('bf')`, of a password nobody has. It's checked instead
+/// when there's no real hash, see [`password_matches`].
+const DUMMY_PASSWORD_HASH: &str = "$2a$06$N0tT0S3cr3tNoteToSelfON4nKZFlHRRQz0Nzqj2W5KHLCjh2wrNm";
+
+/// What a failed login counts against.
+pub struct LoginKeys {
+    address: String,
+    name: String,
+}
+impl LoginKeys {
+    /// `kind` keeps names of different things apart, e.g. `notebook` or `account`.
+    /// Names are compared case insensitively, like they are when logging in.
+    pub fn new(req: &HttpRequest, kind: &str, name: &str) -> Self {
+        let trusted_proxies = req
+            .app_data::<actix_web::web::Data<crate::AppState>>()
+            .map_or(0, |state| state.config.security.trusted_proxies);
+        let address = client_address(req, trusted_proxies).unwrap_or_else(|| "unknown".to_string());
+        Self {
+            address: format!("address:{address}"),
+            name: name_key(kind, name),
+        }
+    }
+
+    /// How many seconds are left on a lockout, if either key is locked out.
+    pub async fn locked_for(
+        &self,
+        pool: &sqlx::Pool<sqlx::Postgres>,
+    ) -> Result<Option<i64>, sqlx::Error> {
+        let result: (Option<i64>,) = sqlx::query_as(
+            "SELECT CEIL(EXTRACT(EPOCH FROM MAX(locked_until) - now()))::BIGINT FROM login_throttles
+            WHERE key IN ($1, $2) AND locked_until > now()",
+        )
+        .bind(&self.address)
+        .bind(&self.name)
+        .fetch_one(pool)
+        .await?;
+        Ok(result.0)
+    }
+
+    pub async fn record_failure(
+        &self,
+        pool: &sqlx::Pool<sqlx::Postgres>,
+    ) -> Result<(), sqlx::Error> {
+        record_failure(pool, &self.address, ADDRESS_THRESHOLD).await?;
+        record_failure(pool, &self.name, NAME_THRESHOLD).await
+    }
+
+    /// Only the name is forgiven, otherwise guessing at someone else's notebook
+    /// could be hidden by logging in to your own every so often.
+    pub async fn record_success(
+        &self,
+        pool: &sqlx::Pool<sqlx::Postgres>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM login_throttles WHERE key = $1")
+            .bind(&self.name)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Each trusted proxy appends the address it got the request from to `X-Forwarded-For`, so the client's
+/// is that many from the end. Anything before it came from the client, which could have made it up.
+fn client_address(req: &HttpRequest, trusted_proxies: usize) -> Option<String> {
+    if trusted_proxies == 0 {
+        return req.peer_addr().map(|addr| addr.ip().to_string());
+    }
+    let forwarded: Vec<&str> = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|address| !address.is_empty())
+        .collect();
+    let client = forwarded.len().checked_sub(trusted_proxies)?;
+    Some(forwarded[client].to_string())
+}
+
+/// Checks a password against a bcrypt hash. Without one, because there's nothing by that name, the
+/// password is hashed all the same, so that takes as long to refuse as a wrong password and doesn't
+/// give away which names exist.
+pub async fn password_matches(
+    executor: impl sqlx::PgExecutor<'_>,
+    password: &str,
+    hash: Option<&str>,
+) -> Result<bool, sqlx::Error> {
+    let (matches,): (bool,) = sqlx::query_as("SELECT crypt($1, $2) = $2")
+        .bind(password)
+        .bind(hash.unwrap_or(DUMMY_PASSWORD_HASH))
+        .fetch_one(executor)
+        .await?;
+    Ok(matches && hash.is_some())
+}
+
+fn name_key(kind: &str, name: &str) -> String {
+    format!("{kind}:{}", name.trim().to_uppercase())
+}
+
+/// Lifts any lockout on a name, e.g. once its password has been reset.
+pub async fn forgive(
+    executor: impl sqlx::PgExecutor<'_>,
+    kind: &str,
+    name: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM login_throttles WHERE key = $1")
+        .bind(name_key(kind, name))
+        .execute(executor)
+        .await?;
+    Ok(())
+}
+
+/// Failures are forgotten after a day without any.
+async fn record_failure(
+    pool: &sqlx::Pool<sqlx::Postgres>,
+    key: &str,
+    threshold: i32,
+) -> Result<(), sqlx::Error> {
+    let (failures,): (i32,) = sqlx::query_as(
+        "INSERT INTO login_throttles (key, failures, last_failure_at) VALUES ($1, 1, now())
+        ON CONFLICT (key) DO UPDATE SET
+            failures = CASE
+                WHEN login_throttles.last_failure_at < now() - interval '1 day' THEN 1
+                ELSE login_throttles.failures + 1
+            END,
+            last_failure_at = now()
+        RETURNING failures",
+    )
+    .bind(key)
+    .fetch_one(pool)
+    .await?;
+    if let Some(lockout) = lockout_secs(failures, threshold) {
+        sqlx::query(
+            "UPDATE login_throttles SET locked_until = now() + make_interval(secs => $2) WHERE key = $1",
+        )
+        .bind(key)
+        .bind(lockout)
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
+
+/// How long a key is locked out for after `failures` in a row, if it's reached the threshold.
+fn lockout_secs(failures: i32, threshold: i32) -> Option<f64> {
+    (failures >= threshold)
+        .then(|| (BASE_LOCKOUT_SECS * 2f64.powi(failures - threshold)).min(MAX_LOCKOUT_SECS))
+}
+
+pub fn locked_out_message(seconds: i64) -> String {
+    let minutes = (seconds + 59) / 60;
+    format!(
+        "Too many failed attempts! Try again in {minutes} minute{}.",
+        if minutes == 1 { "" } else { "s" }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::{client_address, lockout_secs, MAX_LOCKOUT_SECS};
+
+    #[test]
+    fn without_proxies_the_peer_is_the_client() {
+        let req = TestRequest::default()
+            .peer_addr("192.0.2.1:4321".parse().unwrap())
+            .insert_header(("x-forwarded-for", "203.0.113.9"))
+            .to_http_request();
+        assert_eq!(client_address(&req, 0).as_deref(), Some("192.0.2.1"));
+    }
+
+    #[test]
+    fn behind_proxies_the_client_is_counted_from_the_end() {
+        // the client made up the first address, and each proxy added the one after it
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.2:4321".parse().unwrap())
+            .insert_header(("x-forwarded-for", "6.6.6.6, 203.0.113.9 ,10.0.0.1"))
+            .to_http_request();
+        assert_eq!(client_address(&req, 1).as_deref(), Some("10.0.0.1"));
+        assert_eq!(client_address(&req, 2).as_deref(), Some("203.0.113.9"));
+        assert_eq!(client_address(&req, 3).as_deref(), Some("6.6.6.6"));
+        assert_eq!(client_address(&req, 4), None);
+    }
+
+    #[test]
+    fn repeated_forwarded_headers_are_read_in_order() {
+        let req = TestRequest::default()
+            .append_header(("x-forwarded-for", "6.6.6.6"))
+            .append_header(("x-forwarded-for", "203.0.113.9,"))
+            .to_http_request();
+        assert_eq!(client_address(&req, 1).as_deref(), Some("203.0.113.9"));
+        assert_eq!(client_address(&req, 2).as_deref(), Some("6.6.6.6"));
+    }
+
+    #[test]
+    fn without_a_forwarded_header_behind_a_proxy_there_is_no_client() {
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.2:4321".parse().unwrap())
+            .to_http_request();
+        assert_eq!(client_address(&req, 1), None);
+    }
+
+    #[test]
+    fn lockouts_start_at_the_threshold() {
+        assert_eq!(lockout_secs(4, 5), None);
+        assert_eq!(lockout_secs(5, 5), Some(30.0));
+    }
+
+    #[test]
+    fn lockouts_double_up_to_an_hour() {
+        assert_eq!(lockout_secs(6, 5), Some(60.0));
+        assert_eq!(lockout_secs(7, 5), Some(120.0));
+        assert_eq!(lockout_secs(11, 5), Some(1920.0));
+        assert_eq!(lockout_secs(12, 5), Some(MAX_LOCKOUT_SECS));
+        assert_eq!(lockout_secs(1000, 5), Some(MAX_LOCKOUT_SECS));
+    }
+}
@@ -0,0 +1,369 @@
+use leptos::{either::Either, logging::log, prelude::*, task::spawn_local};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{error_message, AppError};
+
+// personal api tokens, for scripts that can't hold on to a session cookie.
+// a token is tied to the notebook it was created from, or to an account, in which case it works on every
+// notebook shared with the account. it's sent as `Authorization: Bearer <token>`.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TokenScope {
+    ReadOnly,
+    ReadWrite,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    id: i32,
+    name: String,
+    scope: TokenScope,
+    created_at: String,
+    last_used_at: Option<String>,
+}
+impl ApiToken {
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn scope(&self) -> TokenScope {
+        self.scope
+    }
+}
+
+/// What a token belongs to.
+#[cfg(feature = "ssr")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenOwner {
+    Notebook(i32),
+    Account(i32),
+}
+#[cfg(feature = "ssr")]
+impl TokenOwner {
+    fn columns(self) -> (Option<i32>, Option<i32>) {
+        match self {
+            Self::Notebook(notebook_id) => (Some(notebook_id), None),
+            Self::Account(user_id) => (None, Some(user_id)),
+        }
+    }
+}
+
+/// What a token is allowed to do, once it's been checked against the database.
+#[cfg(feature = "ssr")]
+#[derive(Debug, Clone, Copy)]
+pub struct TokenGrant {
+    pub token_id: i32,
+    pub owner: TokenOwner,
+    pub scope: TokenScope,
+}
+
+#[cfg(feature = "ssr")]
+impl ApiToken {
+    /// Returns the new token's id and the token itself, which isn't stored anywhere.
+    pub async fn create(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        owner: TokenOwner,
+        name: &str,
+        scope: TokenScope,
+    ) -> Result<(i32, String), sqlx::Error> {
+        let (notebook_id, user_id) = owner.columns();
+        sqlx::query_as(
+            "WITH new_token AS (SELECT 'nts_' || encode(gen_random_bytes(32), 'hex') AS token)
+            INSERT INTO api_tokens (notebook_id, user_id, name, read_only, token_hash)
+            SELECT $1, $4, $2, $3, digest(token, 'sha256') FROM new_token
+            RETURNING id, (SELECT token FROM new_token)",
+        )
+        .bind(notebook_id)
+        .bind(name)
+        .bind(scope == TokenScope::ReadOnly)
+        .bind(user_id)
+        .fetch_one(pool)
+        .await
+    }
+
+    /// Lists the tokens for a notebook or an account which haven't been revoked.
+    pub async fn list(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        owner: TokenOwner,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let (notebook_id, user_id) = owner.columns();
+        let results: Vec<(i32, String, bool, String, Option<String>)> = sqlx::query_as(
+            "SELECT id, name, read_only, to_char(created_at, 'YYYY-MM-DD HH24:MI'), to_char(last_used_at, 'YYYY-MM-DD HH24:MI')
+            FROM api_tokens
+            WHERE notebook_id IS NOT DISTINCT FROM $1 AND user_id IS NOT DISTINCT FROM $2 AND revoked_at IS NULL
+            ORDER BY id",
+        )
+        .bind(notebook_id)
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+        Ok(results
+            .into_iter()
+            .map(|(id, name, read_only, created_at, last_used_at)| Self {
+                id,
+                name,
+                scope: if read_only {
+                    TokenScope::ReadOnly
+                } else {
+                    TokenScope::ReadWrite
+                },
+                created_at,
+                last_used_at,
+            })
+            .collect())
+    }
+
+    /// Returns whether there was a live token with that id belonging to `owner`.
+    pub async fn revoke(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        owner: TokenOwner,
+        id: i32,
+    ) -> Result<bool, sqlx::Error> {
+        let (notebook_id, user_id) = owner.columns();
+        let result = sqlx::query(
+            "UPDATE api_tokens SET revoked_at = now()
+            WHERE id = $1 AND notebook_id IS NOT DISTINCT FROM $2 AND user_id IS NOT DISTINCT FROM $3
+            AND revoked_at IS NULL",
+        )
+        .bind(id)
+        .bind(notebook_id)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Looks up a token presented by a client, recording that it was used.
+    pub async fn authenticate(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        token: &str,
+    ) -> Result<Option<TokenGrant>, sqlx::Error> {
+        let result: Option<(i32, Option<i32>, Option<i32>, bool)> = sqlx::query_as(
+            "UPDATE api_tokens SET last_used_at = now()
+            WHERE token_hash = digest($1, 'sha256') AND revoked_at IS NULL
+            RETURNING id, notebook_id, user_id, read_only",
+        )
+        .bind(token)
+        .fetch_optional(pool)
+        .await?;
+        Ok(result.and_then(TokenGrant::from_row))
+    }
+
+    /// Looks up a token again by its id, for connections which outlive the request it was presented with.
+    /// `None` once it's been revoked.
+    pub async fn grant(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        token_id: i32,
+    ) -> Result<Option<TokenGrant>, sqlx::Error> {
+        let result: Option<(i32, Option<i32>, Option<i32>, bool)> = sqlx::query_as(
+            "SELECT id, notebook_id, user_id, read_only FROM api_tokens WHERE id = $1 AND revoked_at IS NULL",
+        )
+        .bind(token_id)
+        .fetch_optional(pool)
+        .await?;
+        Ok(result.and_then(TokenGrant::from_row))
+    }
+}
+
+#[cfg(feature = "ssr")]
+impl TokenGrant {
+    fn from_row(
+        (token_id, notebook_id, user_id, read_only): (i32, Option<i32>, Option<i32>, bool),
+    ) -> Option<Self> {
+        let owner = match (notebook_id, user_id) {
+            (Some(notebook_id), _) => TokenOwner::Notebook(notebook_id),
+            (None, Some(user_id)) => TokenOwner::Account(user_id),
+            (None, None) => return None,
+        };
+        Some(Self {
+            token_id,
+            owner,
+            scope: if read_only {
+                TokenScope::ReadOnly
+            } else {
+                TokenScope::ReadWrite
+            },
+        })
+    }
+}
+
+/// Tokens can only be managed from a logged in browser, by the notebook's owner or for the account
+/// that's logged in, so a leaked token can't mint more tokens.
+/// `notebook_id` is the notebook whose tokens they are, or `None` for the account's.
+#[cfg(feature = "ssr")]
+async fn token_owner(notebook_id: Option<i32>) -> Result<TokenOwner, AppError> {
+    let caller: crate::auth::Caller = leptos_actix::extract().await?;
+    match notebook_id {
+        Some(notebook_id) if caller.role(notebook_id) == Some(crate::sharing::Role::Owner) => {
+            Ok(TokenOwner::Notebook(notebook_id))
+        }
+        Some(_) => Err(AppError::Unauthorized(
+            "You don't have access to that notebook!".to_string(),
+        )),
+        None => caller
+            .user_id()
+            .map(TokenOwner::Account)
+            .ok_or_else(|| AppError::Unauthorized("Log in to your account first!".to_string())),
+    }
+}
+
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all, fields(notebook_id = notebook_id))]
+pub async fn list_api_tokens(
+    notebook_id: Option<i32>,
+) -> Result<Vec<ApiToken>, ServerFnError<AppError>> {
+    let owner = token_owner(notebook_id).await?;
+    Ok(
+        ApiToken::list(&crate::app::get_pool_from_context().await?, owner)
+            .await
+            .map_err(AppError::from)?,
+    )
+}
+
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all, fields(notebook_id = notebook_id))]
+pub async fn create_api_token(
+    notebook_id: Option<i32>,
+    name: String,
+    read_only: bool,
+) -> Result<String, ServerFnError<AppError>> {
+    let owner = token_owner(notebook_id).await?;
+    if name.trim().is_empty() {
+        return Err(AppError::Validation("Give the token a name!".to_string()).into());
+    }
+    let scope = if read_only {
+        TokenScope::ReadOnly
+    } else {
+        TokenScope::ReadWrite
+    };
+    let (_, token) = ApiToken::create(
+        &crate::app::get_pool_from_context().await?,
+        owner,
+        name.trim(),
+        scope,
+    )
+    .await
+    .map_err(AppError::from)?;
+    Ok(token)
+}
+
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all, fields(notebook_id = notebook_id))]
+pub async fn revoke_api_token(
+    notebook_id: Option<i32>,
+    token_id: i32,
+) -> Result<(), ServerFnError<AppError>> {
+    let owner = token_owner(notebook_id).await?;
+    if ApiToken::revoke(&crate::app::get_pool_from_context().await?, owner, token_id)
+        .await
+        .map_err(AppError::from)?
+    {
+        let (notebook_id, user_id) = owner.columns();
+        crate::sync::close_access_from_context(notebook_id, user_id)
+            .await
+            .map_err(AppError::from)?;
+        Ok(())
+    } else {
+        Err(AppError::NotFound(format!("Couldn't find a token with id {token_id}!")).into())
+    }
+}
+
+/// A notebook's tokens on its settings page, or the account's, with no `notebook_id`, on the account page.
+#[component]
+pub fn ApiTokens(notebook_id: Option<i32>) -> impl IntoView {
+    let tokens = RwSignal::new(Vec::<ApiToken>::new());
+    let new_token = RwSignal::new(None::<Result<String, String>>);
+    let name = RwSignal::new(String::new());
+    let read_only = RwSignal::new(false);
+
+    let refresh = move || {
+        spawn_local(async move {
+            match list_api_tokens(notebook_id).await {
+                Ok(received) => tokens.set(received),
+                Err(e) => log!("couldn't list api tokens: {:#?}", e),
+            }
+        })
+    };
+    Effect::new(move |_| refresh());
+
+    let create = move |_| {
+        let (token_name, token_read_only) = (name.get_untracked(), read_only.get_untracked());
+        spawn_local(async move {
+            let result = create_api_token(notebook_id, token_name, token_read_only).await;
+            new_token.set(Some(result.map_err(|e| error_message(&e))));
+            name.set(String::new());
+            refresh();
+        })
+    };
+    let revoke = move |token_id: i32| {
+        spawn_local(async move {
+            if let Err(e) = revoke_api_token(notebook_id, token_id).await {
+                log!("couldn't revoke api token: {:#?}", e);
+            }
+            refresh();
+        })
+    };
+
+    let new_token_view = move || {
+        new_token.get().map(|token| match token {
+            Ok(token) => Either::Left(view! {
+                <p> "Copy this token now, it won't be shown again:" </p>
+                <code class="api-token"> {token} </code>
+            }),
+            Err(e) => Either::Right(view! { <p class="error-message"> {e} </p> }),
+        })
+    };
+
+    view! {
+        <div class="settings-section">
+            <h2> "API tokens" </h2>
+            <p>
+                {if notebook_id.is_some() {
+                    "Tokens let scripts use the JSON API for this notebook, with an "
+                } else {
+                    "Tokens let scripts use the JSON API for every notebook shared with your account, with an "
+                }}
+                <code> "Authorization: Bearer" </code> " header."
+            </p>
+            <ul>
+                <For
+                    each={move || tokens.get()}
+                    key={|token| token.id()}
+                    children={move |token| {
+                        let token_id = token.id();
+                        view! {
+                            <li>
+                                {format!(
+                                    "{} ({}) - created {}, {}",
+                                    token.name(),
+                                    match token.scope() { TokenScope::ReadOnly => "read only", TokenScope::ReadWrite => "read and write" },
+                                    token.created_at,
+                                    token.last_used_at.as_ref().map(|t| format!("last used {t}")).unwrap_or_else(|| "never used".to_string()),
+                                )}
+                                <button on:click=move |_| revoke(token_id)> "Revoke" </button>
+                            </li>
+                        }
+                    }}
+                />
+            </ul>
+            <input
+                type="text"
+                placeholder="Token name..."
+                prop:value=move || name.get()
+                on:input:target=move |ev| name.set(ev.target().value())
+            />
+            <label>
+                <input
+                    type="checkbox"
+                    prop:checked=move || read_only.get()
+                    on:change:target=move |ev| read_only.set(ev.target().checked())
+                />
+                "Read only"
+            </label>
+            <button on:click=create> "Create token" </button>
+            {new_token_view}
+        </div>
+    }
+}
@@ -0,0 +1,611 @@
+use leptos::{
+    either::{Either, EitherOf3},
+    logging::log,
+    prelude::*,
+    task::spawn_local,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{error_message, AppError, ErrorMessage};
+
+// optional TOTP two-factor authentication, for notebooks and for accounts.
+// once a password checks out, the session only records that it did, as a `PendingLogin`,
+// and `notebook_id` or `user_id` isn't set until a code from an authenticator app (or a recovery code) is given too.
+
+/// What a second factor protects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FactorOwner {
+    Notebook(i32),
+    Account(i32),
+}
+
+/// What happens after a correct password.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LoginStep {
+    Done,
+    NeedsCode,
+}
+
+/// What an authenticator app needs, shown while setting up two-factor authentication.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Enrolment {
+    /// A base64 encoded png.
+    qr_code: String,
+    /// For typing into the app by hand, in base32.
+    secret: String,
+}
+
+#[cfg(feature = "ssr")]
+pub use server::*;
+
+#[cfg(feature = "ssr")]
+mod server {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use serde::{Deserialize, Serialize};
+    use totp_rs::{Algorithm, TOTP};
+
+    use super::{Enrolment, FactorOwner, LoginStep};
+
+    const STEP_SECS: u64 = 30;
+    /// How long someone has to give a code after their password.
+    const PENDING_LOGIN_SECS: u64 = 5 * 60;
+    const RECOVERY_CODES: i32 = 10;
+
+    #[derive(Debug)]
+    pub enum TwoFactorError {
+        Database(sqlx::Error),
+        Totp(String),
+        Session(String),
+    }
+    impl std::fmt::Display for TwoFactorError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Self::Database(e) => write!(f, "{e}"),
+                Self::Totp(e) => write!(f, "couldn't set up the authenticator: {e}"),
+                Self::Session(e) => write!(f, "couldn't update the session: {e}"),
+            }
+        }
+    }
+    impl From<sqlx::Error> for TwoFactorError {
+        fn from(e: sqlx::Error) -> Self {
+            Self::Database(e)
+        }
+    }
+
+    /// Kept in the session between the password and the code.
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct PendingLogin {
+        pub owner: FactorOwner,
+        password_checked_at: u64,
+    }
+    impl PendingLogin {
+        pub fn expired(&self) -> bool {
+            now().saturating_sub(self.password_checked_at) > PENDING_LOGIN_SECS
+        }
+    }
+
+    impl FactorOwner {
+        /// The `(notebook_id, user_id)` columns of `second_factors`.
+        fn columns(self) -> (Option<i32>, Option<i32>) {
+            match self {
+                Self::Notebook(id) => (Some(id), None),
+                Self::Account(id) => (None, Some(id)),
+            }
+        }
+
+        /// What code guesses are throttled by.
+        pub fn key(self) -> String {
+            match self {
+                Self::Notebook(id) => format!("notebook-{id}"),
+                Self::Account(id) => format!("account-{id}"),
+            }
+        }
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or_default()
+    }
+
+    fn totp(secret: Vec<u8>, label: &str) -> Result<TOTP, TwoFactorError> {
+        TOTP::new(
+            Algorithm::SHA1,
+            6,
+            1,
+            STEP_SECS,
+            secret,
+            Some("note-to-self".to_string()),
+            label.replace(':', " "),
+        )
+        .map_err(|e| TwoFactorError::Totp(e.to_string()))
+    }
+
+    /// The step a code is from, allowing for a step of clock drift either way.
+    fn matching_step(totp: &TOTP, code: &str) -> Option<i64> {
+        let current = now() / STEP_SECS;
+        (current.saturating_sub(1)..=current + 1)
+            .find(|step| {
+                constant_time_eq(totp.generate(step * STEP_SECS).as_bytes(), code.as_bytes())
+            })
+            .map(|step| step as i64)
+    }
+
+    /// Looks at every byte, so how long a wrong code takes to refuse doesn't say how much of it was right.
+    fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+        a.len() == b.len()
+            && a.iter()
+                .zip(b)
+                .fold(0, |difference, (a, b)| difference | (a ^ b))
+                == 0
+    }
+
+    /// Returns the id, secret and last used step of the owner's second factor.
+    async fn find(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        owner: FactorOwner,
+        confirmed: bool,
+    ) -> Result<Option<(i32, Vec<u8>, i64)>, sqlx::Error> {
+        let (notebook_id, user_id) = owner.columns();
+        sqlx::query_as(
+            "SELECT id, secret, last_used_step FROM second_factors
+            WHERE (notebook_id = $1 OR user_id = $2) AND (confirmed_at IS NOT NULL) = $3",
+        )
+        .bind(notebook_id)
+        .bind(user_id)
+        .bind(confirmed)
+        .fetch_optional(pool)
+        .await
+    }
+
+    pub async fn is_enabled(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        owner: FactorOwner,
+    ) -> Result<bool, sqlx::Error> {
+        Ok(find(pool, owner, true).await?.is_some())
+    }
+
+    /// Makes a new secret, replacing any which was never confirmed.
+    /// Returns `None` if two-factor authentication is already on.
+    pub async fn enrol(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        owner: FactorOwner,
+        label: &str,
+    ) -> Result<Option<Enrolment>, TwoFactorError> {
+        let (notebook_id, user_id) = owner.columns();
+        let mut transaction = pool.begin().await?;
+        sqlx::query(
+            "DELETE FROM second_factors WHERE (notebook_id = $1 OR user_id = $2) AND confirmed_at IS NULL",
+        )
+        .bind(notebook_id)
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await?;
+        let secret: Option<(Vec<u8>,)> = sqlx::query_as(
+            "INSERT INTO second_factors (notebook_id, user_id, secret) VALUES ($1, $2, gen_random_bytes(20))
+            ON CONFLICT DO NOTHING RETURNING secret",
+        )
+        .bind(notebook_id)
+        .bind(user_id)
+        .fetch_optional(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+        let Some((secret,)) = secret else {
+            return Ok(None);
+        };
+        let totp = totp(secret, label)?;
+        Ok(Some(Enrolment {
+            qr_code: totp.get_qr_base64().map_err(TwoFactorError::Totp)?,
+            secret: totp.get_secret_base32(),
+        }))
+    }
+
+    /// Turns two-factor authentication on once the app gives a right code,
+    /// returning the recovery codes, which aren't stored anywhere.
+    pub async fn confirm(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        owner: FactorOwner,
+        code: &str,
+    ) -> Result<Option<Vec<String>>, TwoFactorError> {
+        let Some((id, secret, _)) = find(pool, owner, false).await? else {
+            return Ok(None);
+        };
+        let Some(step) = matching_step(&totp(secret, "")?, code.trim()) else {
+            return Ok(None);
+        };
+        let mut transaction = pool.begin().await?;
+        sqlx::query(
+            "UPDATE second_factors SET confirmed_at = now(), last_used_step = $2 WHERE id = $1",
+        )
+        .bind(id)
+        .bind(step)
+        .execute(&mut *transaction)
+        .await?;
+        let codes: Vec<(String,)> =
+            sqlx::query_as("SELECT encode(gen_random_bytes(5), 'hex') FROM generate_series(1, $1)")
+                .bind(RECOVERY_CODES)
+                .fetch_all(&mut *transaction)
+                .await?;
+        let codes: Vec<String> = codes.into_iter().map(|(code,)| code).collect();
+        sqlx::query(
+            "INSERT INTO recovery_codes (second_factor_id, code_hash)
+            SELECT $1, digest(code, 'sha256') FROM UNNEST($2::TEXT[]) AS code",
+        )
+        .bind(id)
+        .bind(&codes)
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+        Ok(Some(
+            codes
+                .into_iter()
+                .map(|code| format!("{}-{}", &code[..5], &code[5..]))
+                .collect(),
+        ))
+    }
+
+    /// Checks a code from the app, or an unused recovery code, using it up.
+    pub async fn check_code(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        owner: FactorOwner,
+        code: &str,
+    ) -> Result<bool, TwoFactorError> {
+        let Some((id, secret, last_used_step)) = find(pool, owner, true).await? else {
+            return Ok(false);
+        };
+        let code = code.trim();
+        if code.len() == 6 && code.chars().all(|c| c.is_ascii_digit()) {
+            let Some(step) =
+                matching_step(&totp(secret, "")?, code).filter(|step| *step > last_used_step)
+            else {
+                return Ok(false);
+            };
+            // only one request gets to use the code, even if two arrive at once
+            let result = sqlx::query(
+                "UPDATE second_factors SET last_used_step = $2 WHERE id = $1 AND last_used_step < $2",
+            )
+            .bind(id)
+            .bind(step)
+            .execute(pool)
+            .await?;
+            Ok(result.rows_affected() > 0)
+        } else {
+            let recovery_code: String = code
+                .chars()
+                .filter(|c| c.is_ascii_alphanumeric())
+                .collect::<String>()
+                .to_lowercase();
+            let result = sqlx::query(
+                "UPDATE recovery_codes SET used_at = now()
+                WHERE second_factor_id = $1 AND code_hash = digest($2, 'sha256') AND used_at IS NULL",
+            )
+            .bind(id)
+            .bind(recovery_code)
+            .execute(pool)
+            .await?;
+            Ok(result.rows_affected() > 0)
+        }
+    }
+
+    pub async fn disable(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        owner: FactorOwner,
+    ) -> Result<(), sqlx::Error> {
+        let (notebook_id, user_id) = owner.columns();
+        sqlx::query("DELETE FROM second_factors WHERE notebook_id = $1 OR user_id = $2")
+            .bind(notebook_id)
+            .bind(user_id)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Logs in once the password has checked out, or waits for a code if there's a second factor.
+    pub async fn password_checked(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        session: &actix_session::Session,
+        owner: FactorOwner,
+    ) -> Result<LoginStep, TwoFactorError> {
+        if is_enabled(pool, owner).await? {
+            session
+                .insert(
+                    "pending_login",
+                    PendingLogin {
+                        owner,
+                        password_checked_at: now(),
+                    },
+                )
+                .map_err(|e| TwoFactorError::Session(e.to_string()))?;
+            Ok(LoginStep::NeedsCode)
+        } else {
+            log_in(session, owner)?;
+            Ok(LoginStep::Done)
+        }
+    }
+
+    pub fn log_in(
+        session: &actix_session::Session,
+        owner: FactorOwner,
+    ) -> Result<(), TwoFactorError> {
+        session.remove("pending_login");
+        match owner {
+            FactorOwner::Notebook(notebook_id) => {
+                // a new session, and so a new absolute timeout, like logging in to an account
+                session.renew();
+                session
+                    .insert("notebook_id", notebook_id)
+                    .map_err(|e| TwoFactorError::Session(e.to_string()))?;
+                leptos_actix::redirect(&format!("/notebook/{notebook_id}"));
+            }
+            FactorOwner::Account(user_id) => {
+                crate::accounts::log_in_session(session, user_id)
+                    .map_err(|e| TwoFactorError::Session(e.message().to_string()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Works out whose second factor a settings page is changing: the notebook's, for its owner,
+/// or the logged in account's.
+#[cfg(feature = "ssr")]
+async fn settings_owner(notebook_id: Option<i32>) -> Result<(FactorOwner, String), AppError> {
+    let pool = crate::app::get_pool_from_context().await?;
+    match notebook_id {
+        Some(notebook_id) => {
+            crate::sharing::owned_notebook(notebook_id).await?;
+            let (name,): (String,) = sqlx::query_as("SELECT name FROM notebooks WHERE id = $1")
+                .bind(notebook_id)
+                .fetch_one(&pool)
+                .await?;
+            Ok((FactorOwner::Notebook(notebook_id), name))
+        }
+        None => {
+            let caller: crate::auth::Caller = leptos_actix::extract().await?;
+            let account = match caller.user_id() {
+                Some(user_id) => crate::accounts::Account::get(&pool, user_id).await?,
+                None => None,
+            };
+            account
+                .map(|account| {
+                    (
+                        FactorOwner::Account(account.id()),
+                        account.username().to_string(),
+                    )
+                })
+                .ok_or_else(|| AppError::Unauthorized("You aren't logged in!".to_string()))
+        }
+    }
+}
+
+/// The second step of logging in, after `select_notebook` or `log_in` said a code is needed.
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all)]
+pub async fn verify_second_factor(code: String) -> Result<(), ServerFnError<AppError>> {
+    let session: actix_session::Session = leptos_actix::extract().await.map_err(AppError::from)?;
+    let req: actix_web::HttpRequest = leptos_actix::extract().await.map_err(AppError::from)?;
+    let pool = crate::app::get_pool_from_context().await?;
+    let pending = session
+        .get::<PendingLogin>("pending_login")
+        .ok()
+        .flatten()
+        .filter(|pending| !pending.expired())
+        .ok_or_else(|| AppError::Unauthorized("That took too long, log in again!".to_string()))?;
+    let throttle = crate::throttle::LoginKeys::new(&req, "second-factor", &pending.owner.key());
+    if let Some(seconds) = throttle.locked_for(&pool).await.map_err(AppError::from)? {
+        return Err(AppError::Validation(crate::throttle::locked_out_message(seconds)).into());
+    }
+    let correct = check_code(&pool, pending.owner, &code)
+        .await
+        .map_err(AppError::from)?;
+    crate::metrics::record_login("second-factor", correct);
+    if correct {
+        throttle
+            .record_success(&pool)
+            .await
+            .map_err(AppError::from)?;
+        Ok(log_in(&session, pending.owner).map_err(AppError::from)?)
+    } else {
+        throttle
+            .record_failure(&pool)
+            .await
+            .map_err(AppError::from)?;
+        Err(AppError::Validation("That code is incorrect!".to_string()).into())
+    }
+}
+
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all, fields(notebook_id = notebook_id))]
+pub async fn two_factor_enabled(notebook_id: Option<i32>) -> Result<bool, ServerFnError<AppError>> {
+    let (owner, _) = settings_owner(notebook_id).await?;
+    Ok(
+        is_enabled(&crate::app::get_pool_from_context().await?, owner)
+            .await
+            .map_err(AppError::from)?,
+    )
+}
+
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all, fields(notebook_id = notebook_id))]
+pub async fn start_two_factor(
+    notebook_id: Option<i32>,
+) -> Result<Enrolment, ServerFnError<AppError>> {
+    let (owner, label) = settings_owner(notebook_id).await?;
+    enrol(&crate::app::get_pool_from_context().await?, owner, &label)
+        .await
+        .map_err(AppError::from)?
+        .ok_or_else(|| {
+            AppError::Conflict("Two-factor authentication is already on!".to_string()).into()
+        })
+}
+
+/// Returns the recovery codes.
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all, fields(notebook_id = notebook_id))]
+pub async fn confirm_two_factor(
+    notebook_id: Option<i32>,
+    code: String,
+) -> Result<Vec<String>, ServerFnError<AppError>> {
+    let (owner, _) = settings_owner(notebook_id).await?;
+    confirm(&crate::app::get_pool_from_context().await?, owner, &code)
+        .await
+        .map_err(AppError::from)?
+        .ok_or_else(|| AppError::Validation("That code is incorrect!".to_string()).into())
+}
+
+/// Needs a current code, so someone who only has the password can't turn it off. Wrong codes count
+/// towards the same lockout as logging in with one, so they can't be guessed here instead.
+#[server(prefix = "/api")]
+#[tracing::instrument(skip_all, fields(notebook_id = notebook_id))]
+pub async fn disable_two_factor(
+    notebook_id: Option<i32>,
+    code: String,
+) -> Result<(), ServerFnError<AppError>> {
+    let (owner, _) = settings_owner(notebook_id).await?;
+    let req: actix_web::HttpRequest = leptos_actix::extract().await.map_err(AppError::from)?;
+    let pool = crate::app::get_pool_from_context().await?;
+    let throttle = crate::throttle::LoginKeys::new(&req, "second-factor", &owner.key());
+    if let Some(seconds) = throttle.locked_for(&pool).await.map_err(AppError::from)? {
+        return Err(AppError::Validation(crate::throttle::locked_out_message(seconds)).into());
+    }
+    if !check_code(&pool, owner, &code)
+        .await
+        .map_err(AppError::from)?
+    {
+        throttle
+            .record_failure(&pool)
+            .await
+            .map_err(AppError::from)?;
+        return Err(AppError::Validation("That code is incorrect!".to_string()).into());
+    }
+    throttle
+        .record_success(&pool)
+        .await
+        .map_err(AppError::from)?;
+    Ok(disable(&pool, owner).await.map_err(AppError::from)?)
+}
+
+/// Asks for the code after a password, for either kind of login.
+#[component]
+pub fn SecondFactorForm(action: ServerAction<VerifySecondFactor>) -> impl IntoView {
+    view! {
+        <ActionForm action>
+            <h1> "Enter your code" </h1>
+            <p> "Enter the code from your authenticator app, or one of your recovery codes." </p>
+            <input type="text" name="code" placeholder="123456" autocomplete="one-time-code" required />
+            <br />
+            <button type="submit"> "Continue" </button>
+        </ActionForm>
+        {move || match action.value().get() {
+            Some(Err(error)) => Either::Left(view! { <ErrorMessage error /> }),
+            Some(Ok(())) | None => Either::Right(()),
+        }}
+    }
+}
+
+/// Turning two-factor authentication on and off, for a notebook or for the logged in account.
+#[component]
+pub fn TwoFactorSettings(notebook_id: Option<i32>) -> impl IntoView {
+    let enabled = RwSignal::new(None::<bool>);
+    let enrolment = RwSignal::new(None::<Enrolment>);
+    let recovery_codes = RwSignal::new(Vec::<String>::new());
+    let code = RwSignal::new(String::new());
+    let error = RwSignal::new(None::<String>);
+
+    let refresh = move || {
+        spawn_local(async move {
+            match two_factor_enabled(notebook_id).await {
+                Ok(received) => enabled.set(Some(received)),
+                Err(e) => log!("couldn't check two-factor authentication: {:#?}", e),
+            }
+        })
+    };
+    Effect::new(move |_| refresh());
+
+    let start = move |_| {
+        spawn_local(async move {
+            match start_two_factor(notebook_id).await {
+                Ok(received) => {
+                    error.set(None);
+                    enrolment.set(Some(received));
+                }
+                Err(e) => error.set(Some(error_message(&e))),
+            }
+        })
+    };
+    let confirm = move |_| {
+        let entered = code.get_untracked();
+        spawn_local(async move {
+            match confirm_two_factor(notebook_id, entered).await {
+                Ok(codes) => {
+                    error.set(None);
+                    enrolment.set(None);
+                    recovery_codes.set(codes);
+                }
+                Err(e) => error.set(Some(error_message(&e))),
+            }
+            code.set(String::new());
+            refresh();
+        })
+    };
+    let disable = move |_| {
+        let entered = code.get_untracked();
+        spawn_local(async move {
+            match disable_two_factor(notebook_id, entered).await {
+                Ok(()) => {
+                    error.set(None);
+                    recovery_codes.set(Vec::new());
+                }
+                Err(e) => error.set(Some(error_message(&e))),
+            }
+            code.set(String::new());
+            refresh();
+        })
+    };
+
+    let code_input = move || {
+        view! {
+            <input
+                type="text"
+                placeholder="Code..."
+                autocomplete="one-time-code"
+                prop:value=move || code.get()
+                on:input:target=move |ev| code.set(ev.target().value())
+            />
+        }
+    };
+    let body = move || match (enabled.get(), enrolment.get()) {
+        (Some(true), _) => EitherOf3::A(view! {
+            <p> "Two-factor authentication is on. Enter a code to turn it off." </p>
+            {code_input()}
+            <button on:click=disable> "Turn off" </button>
+        }),
+        (_, Some(enrolment)) => EitherOf3::B(view! {
+            <p> "Scan this with your authenticator app, or enter the key by hand, then enter the code it shows." </p>
+            <img class="qr-code" src=format!("data:image/png;base64,{}", enrolment.qr_code) alt="QR code for an authenticator app" />
+            <code class="api-token"> {enrolment.secret} </code>
+            {code_input()}
+            <button on:click=confirm> "Turn on" </button>
+        }),
+        _ => EitherOf3::C(view! {
+            <p> "Ask for a code from an authenticator app as well as the password." </p>
+            <button on:click=start> "Set up" </button>
+        }),
+    };
+
+    view! {
+        <div class="settings-section">
+            <h2> "Two-factor authentication" </h2>
+            {body}
+            <Show when=move || !recovery_codes.with(Vec::is_empty)>
+                <p> "Keep these recovery codes somewhere safe. Each one can be used once instead of a code, and they won't be shown again:" </p>
+                <ul class="recovery-codes">
+                    {move || recovery_codes.get().into_iter().map(|code| view! { <li> <code> {code} </code> </li> }).collect_view()}
+                </ul>
+            </Show>
+            {move || error.get().map(|e| view! { <p class="error-message"> {e} </p> })}
+        </div>
+    }
+}